@@ -1,12 +1,17 @@
+use tokio::net::{TcpListener, TcpStream};
 use tokio::task::JoinHandle;
 use tokio::sync::{mpsc, oneshot};
-use anyhow::{Result, Context};
+use anyhow::{anyhow, Result, Context};
+
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
 
 use crate::peer::peer_message::Handshake;
-use crate::peer::{ConnectionType, PeerMessage, PeerSession};
-use crate::torrent::{TorrentState, TorrentHandle};
+use crate::peer::{ConnectionType, PeerAddress, PeerMessage, PeerSession, PickingMode};
+use crate::torrent::{FilePriority, MagnetLink, TorrentState, TorrentHandle};
 use crate::messager::ClientMessage;
-use crate::utils::{CommunicationPipe, UrlEncodable};
+use crate::disk_manager::UnsafeTorrentPath;
+use crate::dht::DhtHandle;
+use crate::utils::{CommunicationPipe, ExitCode, UrlEncodable};
 use crate::utils::sha1hash::Sha1Hash;
 
 pub struct ClientHandle {
@@ -43,12 +48,41 @@ impl ClientHandle {
         Ok(())
     }
 
-    pub async fn client_add_torrent(&mut self, src: String, dst: String) -> Result<()> {
+    pub async fn client_add_torrent(&mut self, src: String, dst: String, picking_mode: PickingMode, file_priorities: Vec<(usize, FilePriority)>) -> Result<ExitCode> {
+        let (tx, rx) = oneshot::channel();
         self.tx
-            .send(ClientMessage::AddTorrent{src, dst})
+            .send(ClientMessage::AddTorrent{src, dst, picking_mode, file_priorities, tx})
             .await
             .context("couldn't send an add message to the client")?;
 
+        rx.await.context("couldn't receive the add status from the client")
+    }
+
+    pub async fn client_recheck(&mut self, info_hash: Sha1Hash) -> Result<ExitCode> {
+        let (tx, rx) = oneshot::channel();
+        self.tx
+            .send(ClientMessage::RecheckTorrent{info_hash, tx})
+            .await
+            .context("couldn't send a recheck message to the client")?;
+
+        rx.await.context("couldn't receive the recheck status from the client")
+    }
+
+    pub async fn client_set_picking_mode(&mut self, info_hash: Sha1Hash, picking_mode: PickingMode) -> Result<()> {
+        self.tx
+            .send(ClientMessage::SetPickingMode{info_hash, picking_mode})
+            .await
+            .context("couldn't send a picking mode message to the client")?;
+
+        Ok(())
+    }
+
+    pub async fn client_set_file_priorities(&mut self, info_hash: Sha1Hash, file_priorities: Vec<(usize, FilePriority)>) -> Result<()> {
+        self.tx
+            .send(ClientMessage::SetFilePriorities{info_hash, file_priorities})
+            .await
+            .context("couldn't send a file priorities message to the client")?;
+
         Ok(())
     }
 
@@ -83,6 +117,10 @@ impl ClientHandle {
 struct Client {
     pipe: CommunicationPipe,
     torrent_handles: Vec<TorrentHandle>,
+    dht_handle: Option<DhtHandle>,
+    /// Read from the options once, the torrents and the DHT get them from the client.
+    state_file_path: String,
+    state_torrent_files_path: String,
 
     client_id: [u8; 20],
 }
@@ -92,14 +130,34 @@ impl Client {
         Self {
             pipe,
             torrent_handles: Vec::new(),
+            dht_handle: None,
+            state_file_path: unsafe { crate::CLIENT_OPTIONS.state_file_path.clone() },
+            state_torrent_files_path: unsafe { crate::CLIENT_OPTIONS.state_torrent_files_path.clone() },
 
             client_id,
         }
     }
 
+    async fn start_dht(&mut self) {
+        if !unsafe { crate::CLIENT_OPTIONS.dht_enabled } {
+            return;
+        }
+
+        let address = std::net::SocketAddr::from(([0, 0, 0, 0], unsafe { crate::CLIENT_OPTIONS.dht_port }));
+        let bootstrap_nodes = crate::dht::BOOTSTRAP_NODES.iter().map(|node| node.to_string()).collect();
+
+        match DhtHandle::new(address, bootstrap_nodes, Some(crate::dht::dht_state_path(&self.state_file_path))).await {
+            Ok(dht_handle) => self.dht_handle = Some(dht_handle),
+            Err(e) => tracing::error!("Failed to start the DHT: {:?}", e),
+        }
+    }
+
+    fn dht_tx(&self) -> Option<mpsc::Sender<ClientMessage>> {
+        self.dht_handle.as_ref().map(|dht_handle| dht_handle.tx.clone())
+    }
+
     async fn load_state(&mut self) -> Result<()> {
-        let path = unsafe { crate::CLIENT_OPTIONS.state_file_path.clone() };
-        let path = std::path::Path::new(&path);
+        let path = std::path::Path::new(&self.state_file_path);
 
         let client_state = match tokio::fs::read_to_string(path).await {
             Ok(state) => state,
@@ -121,7 +179,23 @@ impl Client {
                 }
             };
 
-            let torrent_handle = TorrentHandle::from_state(self.client_id, torrent_state, info_hash, ConnectionType::Outgoing).await?;
+            // the metadata of a magnet link didn't arrive before the shutdown, fetch it again
+            if let Some(magnet_link) = &torrent_state.magnet_link {
+                let mut torrent_handle = match TorrentHandle::from_magnet(self.client_id, magnet_link, &torrent_state.dest_path, &self.state_torrent_files_path, self.dht_tx()).await {
+                    Ok(torrent_handle) => torrent_handle,
+                    Err(e) => {
+                        tracing::error!("Failed to resume fetching the metadata of magnet link {}: {:?}", magnet_link, e);
+                        continue;
+                    }
+                };
+                if torrent_state.needed.picking_mode != PickingMode::default() {
+                    torrent_handle.set_picking_mode(torrent_state.needed.picking_mode).await?;
+                }
+                self.torrent_handles.push(torrent_handle);
+                continue;
+            }
+
+            let torrent_handle = TorrentHandle::from_state(self.client_id, torrent_state, info_hash, ConnectionType::Outgoing, self.dht_tx()).await?;
             self.torrent_handles.push(torrent_handle);
         }
 
@@ -139,12 +213,13 @@ impl Client {
     pub async fn run(mut self) -> Result<()> {
         tracing::event!(tracing::Level::INFO, "Client starting");
 
+        self.start_dht().await;
         self.load_state().await?;
         
         let mut sending_interval = tokio::time::interval(std::time::Duration::from_secs(unsafe { crate::CLIENT_OPTIONS.sending_to_ui_interval_secs }));
         let mut sending_to_terminal_client = false;
 
-        let seeding_socket = tokio::net::TcpListener::bind("0.0.0.0:".to_owned() + &unsafe { crate::CLIENT_OPTIONS.listening_port }.to_string()).await?;
+        let seeding_socket = SeedingSocket::bind(unsafe { crate::CLIENT_OPTIONS.listening_port }).await?;
         
         loop {
             tokio::select! {
@@ -160,14 +235,31 @@ impl Client {
                             }
                             break;
                         },
-                        ClientMessage::AddTorrent{src, dst} => {
-                            let mut torrent_handle = match TorrentHandle::new(self.client_id, &src, &dst).await {
+                        ClientMessage::AddTorrent{src, dst, picking_mode, file_priorities, tx} => {
+                            if let Err(e) = tokio::fs::create_dir_all(&dst).await {
+                                tracing::error!("Failed to create destination directory '{}': {:?}", dst, e);
+                                let _ = tx.send(ExitCode::InvalidSrcOrDst);
+                                continue;
+                            }
+
+                            let torrent_handle = match MagnetLink::is_magnet_link(&src) {
+                                true => TorrentHandle::from_magnet(self.client_id, &src, &dst, &self.state_torrent_files_path, self.dht_tx()).await,
+                                false => TorrentHandle::new(self.client_id, &src, &dst, &self.state_torrent_files_path, self.dht_tx()).await,
+                            };
+                            let mut torrent_handle = match torrent_handle {
                                 Ok(handle) => handle,
                                 Err(e) => {
                                     tracing::error!("Failed to create torrent handle: {:?}", e);
+
+                                    let exit_code = match e.downcast_ref::<UnsafeTorrentPath>() {
+                                        Some(_) => ExitCode::UnsafeTorrentPath,
+                                        None => ExitCode::InvalidSrcOrDst,
+                                    };
+                                    let _ = tx.send(exit_code);
                                     continue;
                                 }
                             };
+                            let _ = tx.send(ExitCode::SUCCESS);
 
                             if self.torrent_handles.iter().any(|handle| handle.torrent_info_hash == torrent_handle.torrent_info_hash) {
                                 torrent_handle.shutdown().await?;
@@ -175,9 +267,54 @@ impl Client {
                                 continue;
                             }
                             else {
+                                if picking_mode != PickingMode::default() {
+                                    if let Err(e) = torrent_handle.set_picking_mode(picking_mode).await {
+                                        tracing::error!("Failed to send picking mode to torrent handle: {:?}", e);
+                                    }
+                                }
+                                if !file_priorities.is_empty() {
+                                    if let Err(e) = torrent_handle.set_file_priorities(file_priorities).await {
+                                        tracing::error!("Failed to send file priorities to torrent handle: {:?}", e);
+                                    }
+                                }
                                 self.torrent_handles.push(torrent_handle);
                             }
                         },
+                        ClientMessage::RecheckTorrent{info_hash, tx} => {
+                            let exit_code = match self.torrent_handles.iter_mut().find(|handle| handle.torrent_info_hash == info_hash) {
+                                Some(torrent_handle) => {
+                                    if let Err(e) = torrent_handle.recheck().await {
+                                        tracing::error!("Failed to send recheck message to torrent handle: {:?}", e);
+                                    }
+                                    ExitCode::SUCCESS
+                                },
+                                None => {
+                                    tracing::error!("No torrent with info hash {} to recheck", info_hash.to_hex());
+                                    ExitCode::TorrentNotFound
+                                },
+                            };
+                            let _ = tx.send(exit_code);
+                        },
+                        ClientMessage::SetPickingMode{info_hash, picking_mode} => {
+                            match self.torrent_handles.iter_mut().find(|handle| handle.torrent_info_hash == info_hash) {
+                                Some(torrent_handle) => {
+                                    if let Err(e) = torrent_handle.set_picking_mode(picking_mode).await {
+                                        tracing::error!("Failed to send picking mode to torrent handle: {:?}", e);
+                                    }
+                                },
+                                None => tracing::error!("No torrent with info hash {} to change the picking mode of", info_hash.to_hex()),
+                            }
+                        },
+                        ClientMessage::SetFilePriorities{info_hash, file_priorities} => {
+                            match self.torrent_handles.iter_mut().find(|handle| handle.torrent_info_hash == info_hash) {
+                                Some(torrent_handle) => {
+                                    if let Err(e) = torrent_handle.set_file_priorities(file_priorities).await {
+                                        tracing::error!("Failed to send file priorities to torrent handle: {:?}", e);
+                                    }
+                                },
+                                None => tracing::error!("No torrent with info hash {} to change the file priorities of", info_hash.to_hex()),
+                            }
+                        },
                         ClientMessage::SendTorrentsInfo => {
                             sending_to_terminal_client = true;
                             tracing::debug!("Sending torrents info to terminal clients");
@@ -210,8 +347,21 @@ impl Client {
                         }
                     };
 
+                    let peer_address = match peer_session.stream.peer_addr() {
+                        Ok(peer_address) => PeerAddress::from(peer_address),
+                        Err(e) => {
+                            tracing::error!("Failed to get peer address of incoming connection: {:?}", e);
+                            continue;
+                        }
+                    };
+
                     for torrent_handle in self.torrent_handles.iter_mut() {
                         if torrent_handle.torrent_info_hash.as_bytes() == &peer_session.peer_handshake.info_hash {
+                            if torrent_handle.is_banned(&peer_address).await {
+                                tracing::debug!("Dropping incoming connection from banned peer '{}'", peer_address);
+                                break;
+                            }
+
                             if let Err(e) = torrent_handle.add_peer_session(peer_session).await {
                                 tracing::error!("Failed to add peer session to torrent handle: {:?}", e);
                             }
@@ -264,8 +414,49 @@ impl Client {
             }
         }
 
+        if let Some(mut dht_handle) = self.dht_handle {
+            if let Err(e) = dht_handle.shutdown().await {
+                tracing::error!("Failed to shutdown the DHT: {:?}", e);
+            }
+            if let Err(e) = dht_handle.join().await {
+                tracing::error!("Failed to join the DHT handle: {:?}", e);
+            }
+        }
+
         tracing::event!(tracing::Level::INFO, "Client gracefull shutdown");
 
         Ok(())
     }
+}
+
+/// Listens for incoming peers over ipv4 and ipv6 (BEP 7).
+struct SeedingSocket {
+    ipv4: Option<TcpListener>,
+    ipv6: Option<TcpListener>,
+}
+
+impl SeedingSocket {
+    async fn bind(port: u16) -> Result<SeedingSocket> {
+        let ipv6 = TcpListener::bind((Ipv6Addr::UNSPECIFIED, port)).await.ok();
+        // dual stack ipv6 sockets already take ipv4 connections so binding ipv4 on the same port fails
+        let ipv4 = TcpListener::bind((Ipv4Addr::UNSPECIFIED, port)).await.ok();
+
+        if ipv4.is_none() && ipv6.is_none() {
+            return Err(anyhow!("Failed to listen for peers on port {}", port));
+        }
+        tracing::debug!("Listening for peers on port {}, ipv4 socket: {}, ipv6 socket: {}", port, ipv4.is_some(), ipv6.is_some());
+
+        Ok(SeedingSocket { ipv4, ipv6 })
+    }
+
+    async fn accept(&self) -> std::io::Result<(TcpStream, SocketAddr)> {
+        match (&self.ipv4, &self.ipv6) {
+            (Some(ipv4), Some(ipv6)) => tokio::select! {
+                accepted = ipv4.accept() => accepted,
+                accepted = ipv6.accept() => accepted,
+            },
+            (Some(listener), None) | (None, Some(listener)) => listener.accept().await,
+            (None, None) => std::future::pending().await,
+        }
+    }
 }
\ No newline at end of file
@@ -8,6 +8,11 @@ const SAVE_STATE_INTERVAL_SECS: u64 = 120;
 const TRACKER_REGULAR_REQUEST_INTERVAL_SECS: u64 = 120;
 const CLIENT_KEEP_ALIVE_MESSAGE_INTERVAL_SECS: u64 = 120;
 const LISTENING_PORT: u16 = 6881;
+const HASH_FAIL_BAN_THRESHOLD: u32 = 2;
+const DHT_ENABLED: bool = true;
+const DHT_PORT: u16 = 6881;
+const MAX_PEERS_PER_TORRENT: usize = 50;
+const READAHEAD_PIECES: usize = 20;
 
 pub struct ClientOptions {
     pub debug_mode: bool,
@@ -23,6 +28,14 @@ pub struct ClientOptions {
     pub state_file_path: String,
     pub state_torrent_files_path: String,
     pub listening_port: u16,
+    pub hash_fail_ban_threshold: u32,
+    pub dht_enabled: bool,
+    pub dht_port: u16,
+    pub max_peers_per_torrent: usize,
+    /// the address trackers are told to hand out instead of the one they see us on
+    pub announce_ip: Option<std::net::IpAddr>,
+    /// pieces after the playback position that sequential torrents download in order
+    pub readahead_pieces: usize,
 }
  impl Default for ClientOptions {
     fn default() -> Self {
@@ -40,6 +53,12 @@ pub struct ClientOptions {
             state_file_path: "client_state/TtTClient.state".to_string(),
             state_torrent_files_path: "client_state/torrent_files".to_string(),
             listening_port: LISTENING_PORT,
+            hash_fail_ban_threshold: HASH_FAIL_BAN_THRESHOLD,
+            dht_enabled: DHT_ENABLED,
+            dht_port: DHT_PORT,
+            max_peers_per_torrent: MAX_PEERS_PER_TORRENT,
+            announce_ip: None,
+            readahead_pieces: READAHEAD_PIECES,
         }
     }
 }
@@ -251,6 +270,84 @@ pub fn setup_options(args: std::env::Args) {
                 std::process::exit(1);
             }
         }
+        else if arg == "--hash-fail-ban-threshold" {
+            if let Some(arg) = argv_iter.next() {
+                if let Ok(count) = arg.parse::<u32>() {
+                    unsafe { crate::CLIENT_OPTIONS.hash_fail_ban_threshold = count; }
+                }
+                else {
+                    print_error_menu();
+                    std::process::exit(1);
+                }
+            }
+            else {
+                print_error_menu();
+                std::process::exit(1);
+            }
+        }
+        else if arg == "--no-dht" {
+            unsafe { crate::CLIENT_OPTIONS.dht_enabled = false; }
+        }
+        else if arg == "--dht-port" {
+            if let Some(arg) = argv_iter.next() {
+                if let Ok(port) = arg.parse::<u16>() {
+                    unsafe { crate::CLIENT_OPTIONS.dht_port = port; }
+                }
+                else {
+                    print_error_menu();
+                    std::process::exit(1);
+                }
+            }
+            else {
+                print_error_menu();
+                std::process::exit(1);
+            }
+        }
+        else if arg == "--max-peers" {
+            if let Some(arg) = argv_iter.next() {
+                if let Ok(count) = arg.parse::<usize>() {
+                    unsafe { crate::CLIENT_OPTIONS.max_peers_per_torrent = count; }
+                }
+                else {
+                    print_error_menu();
+                    std::process::exit(1);
+                }
+            }
+            else {
+                print_error_menu();
+                std::process::exit(1);
+            }
+        }
+        else if arg == "--announce-ip" {
+            if let Some(arg) = argv_iter.next() {
+                if let Ok(ip) = arg.parse::<std::net::IpAddr>() {
+                    unsafe { crate::CLIENT_OPTIONS.announce_ip = Some(ip); }
+                }
+                else {
+                    print_error_menu();
+                    std::process::exit(1);
+                }
+            }
+            else {
+                print_error_menu();
+                std::process::exit(1);
+            }
+        }
+        else if arg == "--readahead-pieces" {
+            if let Some(arg) = argv_iter.next() {
+                if let Ok(count) = arg.parse::<usize>() {
+                    unsafe { crate::CLIENT_OPTIONS.readahead_pieces = count; }
+                }
+                else {
+                    print_error_menu();
+                    std::process::exit(1);
+                }
+            }
+            else {
+                print_error_menu();
+                std::process::exit(1);
+            }
+        }
         else {
             print_error_menu();
             std::process::exit(1);
@@ -278,4 +375,10 @@ fn print_help_menu() {
     println!("  --state-file-path <path>");
     println!("  --state-torrent-files-path <path>");
     println!("  --listening-port <port>");
+    println!("  --hash-fail-ban-threshold <count>");
+    println!("  --no-dht");
+    println!("  --dht-port <port>");
+    println!("  --max-peers <count>");
+    println!("  --announce-ip <ip>");
+    println!("  --readahead-pieces <count>");
 }
\ No newline at end of file
@@ -0,0 +1,623 @@
+use tokio::net::UdpSocket;
+use tokio::task::{JoinHandle, JoinSet};
+use tokio::sync::{mpsc, oneshot, Mutex};
+use anyhow::{anyhow, Context, Result};
+use serde::{Serialize, Deserialize};
+
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU16, Ordering};
+
+use crate::messager::ClientMessage;
+use crate::peer::PeerAddress;
+use crate::utils::sha1hash::Sha1Hash;
+
+pub mod krpc;
+pub use krpc::{KrpcMessage, NodeId, NodeInfo, Query, Response};
+
+pub mod routing_table;
+pub use routing_table::RoutingTable;
+
+pub mod peer_store;
+pub use peer_store::PeerStore;
+
+/// Well known nodes used to join the DHT when we don't know any nodes yet.
+pub const BOOTSTRAP_NODES: [&str; 3] = [
+    "router.bittorrent.com:6881",
+    "dht.transmissionbt.com:6881",
+    "router.utorrent.com:6881",
+];
+/// How often torrents look up and announce themselves in the DHT.
+pub const DHT_ANNOUNCE_INTERVAL_SECS: u64 = 15 * 60;
+/// How often the routing table is refreshed with a lookup of our own id.
+const DHT_REFRESH_INTERVAL_SECS: u64 = 15 * 60;
+const QUERY_TIMEOUT_MILLIS: u64 = 2000;
+/// Number of queries a lookup keeps in flight.
+const ALPHA: usize = 3;
+const MAX_LOOKUP_ROUNDS: usize = 16;
+const MAX_PACKET_SIZE: usize = 1500;
+
+/// Persisted between sessions so we keep our place in the DHT.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct DhtState {
+    pub id: NodeId,
+    pub nodes: Vec<NodeInfo>,
+}
+
+/// The DHT state file lives next to the client state file.
+pub fn dht_state_path(state_file_path: &str) -> std::path::PathBuf {
+    std::path::Path::new(state_file_path).with_file_name("TtTClient.dht")
+}
+
+fn random_transaction_id() -> u16 {
+    let mut transaction_id = [0u8; 2];
+    getrandom::getrandom(&mut transaction_id).expect("getrandom failed");
+    u16::from_be_bytes(transaction_id)
+}
+
+pub struct DhtHandle {
+    pub tx: mpsc::Sender<ClientMessage>,
+    join_handle: JoinHandle<()>,
+
+    pub local_address: SocketAddr,
+}
+
+impl DhtHandle {
+    /// Binds the DHT node to `address` and joins the DHT through `bootstrap_nodes`.
+    /// The node state is loaded from and saved to `state_path` when given.
+    pub async fn new(address: SocketAddr, bootstrap_nodes: Vec<String>, state_path: Option<std::path::PathBuf>) -> Result<Self> {
+        let (sender, receiver) = mpsc::channel(unsafe { crate::CLIENT_OPTIONS.max_channel_size });
+
+        let state = match &state_path {
+            Some(state_path) => Dht::load_state(state_path).await,
+            None => None
+        };
+
+        let dht = Dht::new(address, receiver, bootstrap_nodes, state, state_path).await?;
+        let local_address = dht.node.socket.local_addr()?;
+
+        let join_handle = tokio::spawn(async move {
+            if let Err(e) = dht.run().await {
+                tracing::error!("DHT error: {:?}", e);
+            }
+        });
+
+        Ok(Self {
+            tx: sender,
+            join_handle,
+
+            local_address,
+        })
+    }
+
+    pub async fn join(self) -> Result<()> {
+        self.join_handle.await?;
+        Ok(())
+    }
+
+    pub async fn shutdown(&mut self) -> Result<()> {
+        self.tx.send(ClientMessage::Shutdown).await?;
+        Ok(())
+    }
+
+    /// Looks up the peers of `info_hash` and announces us on `port` if given.
+    /// The peers are sent back to `tx` as `ClientMessage::DhtPeers`.
+    pub async fn find_peers(&mut self, info_hash: Sha1Hash, port: Option<u16>, tx: mpsc::Sender<ClientMessage>) -> Result<()> {
+        self.tx.send(ClientMessage::FindDhtPeers{info_hash, port, tx}).await?;
+        Ok(())
+    }
+}
+
+type PendingQueries = HashMap<(SocketAddr, Vec<u8>), oneshot::Sender<KrpcMessage>>;
+
+/// Everything the lookups running next to the main loop need to send queries and route answers.
+#[derive(Clone)]
+struct DhtNode {
+    id: NodeId,
+    socket: Arc<UdpSocket>,
+    routing_table: Arc<Mutex<RoutingTable>>,
+    peer_store: Arc<Mutex<PeerStore>>,
+    /// Queries waiting for an answer by the node they went to and their transaction id, so another node can't answer them.
+    pending_queries: Arc<Mutex<PendingQueries>>,
+    /// Starts at a random value so the ids can't be guessed to spoof answers.
+    next_transaction_id: Arc<AtomicU16>,
+}
+
+/// Nodes closest to a target found by a lookup, with the tokens they gave us.
+struct Lookup {
+    closest: Vec<(NodeInfo, Option<Vec<u8>>)>,
+    peers: Vec<SocketAddr>,
+}
+
+impl DhtNode {
+    async fn send(&self, message: &KrpcMessage, address: SocketAddr) -> Result<()> {
+        self.socket.send_to(&message.as_bytes()?, address).await?;
+        Ok(())
+    }
+
+    async fn query(&self, address: SocketAddr, query: Query) -> Result<Response> {
+        let transaction_id = self.next_transaction_id.fetch_add(1, Ordering::Relaxed).to_be_bytes().to_vec();
+        let pending_key = (address, transaction_id.clone());
+
+        let (tx, rx) = oneshot::channel();
+        self.pending_queries.lock().await.insert(pending_key.clone(), tx);
+
+        let message = KrpcMessage::Query {
+            transaction_id: transaction_id.clone(),
+            id: self.id,
+            query,
+        };
+        if let Err(e) = self.send(&message, address).await {
+            self.pending_queries.lock().await.remove(&pending_key);
+            return Err(e);
+        }
+
+        let answer = tokio::time::timeout(std::time::Duration::from_millis(QUERY_TIMEOUT_MILLIS), rx).await;
+        self.pending_queries.lock().await.remove(&pending_key);
+
+        match answer {
+            Ok(Ok(KrpcMessage::Response{response, ..})) => {
+                self.routing_table.lock().await.insert(NodeInfo {
+                    id: response.id,
+                    address,
+                });
+                Ok(response)
+            },
+            Ok(Ok(KrpcMessage::Error{code, message, ..})) => Err(anyhow!("Node '{}' answered with error {}: {}", address, code, message)),
+            Ok(Ok(KrpcMessage::Query{..})) | Ok(Err(_)) => Err(anyhow!("Invalid answer from node '{}'", address)),
+            Err(_) => {
+                self.routing_table.lock().await.failed(&address);
+                Err(anyhow!("Node '{}' didn't answer in time", address))
+            }
+        }
+    }
+
+    async fn handle_packet(&self, bytes: &[u8], address: SocketAddr) -> Result<()> {
+        let message = match KrpcMessage::from_bytes(bytes) {
+            Ok(message) => message,
+            Err(e) => {
+                // we can only answer if the transaction id is readable
+                if let Ok(transaction_id) = crate::utils::bencode::BencodedValue::from_bytes(bytes).and_then(|message| Ok(message.get_from_dict(b"t")?.try_into_byte_string()?.clone())) {
+                    let error = KrpcMessage::Error{transaction_id, code: krpc::ERROR_PROTOCOL, message: "Invalid message".to_string()};
+                    self.send(&error, address).await?;
+                }
+                return Err(e);
+            }
+        };
+
+        match message {
+            KrpcMessage::Query{transaction_id, query: Query::Unknown{..}, ..} => {
+                let error = KrpcMessage::Error{transaction_id, code: krpc::ERROR_METHOD_UNKNOWN, message: "Method Unknown".to_string()};
+                self.send(&error, address).await?;
+            },
+            KrpcMessage::Query{transaction_id, id, query} => {
+                let answer = self.answer_query(query, &address).await;
+                let answer = match answer {
+                    Ok(response) => KrpcMessage::Response{transaction_id, response},
+                    Err(e) => KrpcMessage::Error{transaction_id, code: krpc::ERROR_GENERIC, message: e.to_string()},
+                };
+                self.send(&answer, address).await?;
+
+                if address.port() != 0 {
+                    self.routing_table.lock().await.insert(NodeInfo{id, address});
+                }
+            },
+            message => {
+                match self.pending_queries.lock().await.remove(&(address, message.transaction_id().to_vec())) {
+                    Some(tx) => {
+                        let _ = tx.send(message);
+                    },
+                    None => tracing::debug!("Received an answer from '{}' to an unknown query", address)
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn answer_query(&self, query: Query, address: &SocketAddr) -> Result<Response> {
+        let mut response = Response {
+            id: self.id,
+            ..Default::default()
+        };
+
+        match query {
+            Query::Ping => {},
+            Query::FindNode{target} => {
+                response.nodes = self.routing_table.lock().await.closest(&target, routing_table::K);
+            },
+            Query::GetPeers{info_hash} => {
+                let mut peer_store = self.peer_store.lock().await;
+                response.token = Some(peer_store.token(&address.ip()));
+                response.values = peer_store.peers(&info_hash);
+                response.nodes = self.routing_table.lock().await.closest(&info_hash.0, routing_table::K);
+            },
+            Query::AnnouncePeer{info_hash, port, implied_port, token} => {
+                let mut peer_store = self.peer_store.lock().await;
+                if !peer_store.is_valid_token(&address.ip(), &token) {
+                    return Err(anyhow!("Invalid token"));
+                }
+
+                let port = if implied_port { address.port() } else { port };
+                peer_store.announce(&info_hash, SocketAddr::new(address.ip(), port));
+            },
+            Query::Unknown{method} => return Err(anyhow!("Unknown KRPC method: {}", String::from_utf8_lossy(&method))),
+        }
+
+        Ok(response)
+    }
+
+    /// Iteratively queries the nodes closest to `target` until no closer nodes are found.
+    /// Asks for the peers of `target` on the way if `get_peers` is set.
+    async fn lookup(&self, target: NodeId, get_peers: bool) -> Lookup {
+        let mut candidates = self.routing_table.lock().await.closest(&target, routing_table::K);
+        let mut queried: Vec<SocketAddr> = Vec::new();
+        let mut closest: Vec<(NodeInfo, Option<Vec<u8>>)> = Vec::new();
+        let mut peers: Vec<SocketAddr> = Vec::new();
+
+        for _ in 0..MAX_LOOKUP_ROUNDS {
+            candidates.sort_by_key(|node| routing_table::distance(&node.id, &target));
+            candidates.truncate(routing_table::K);
+
+            let to_query = candidates
+                .iter()
+                .filter(|node| !queried.contains(&node.address))
+                .take(ALPHA)
+                .cloned()
+                .collect::<Vec<NodeInfo>>();
+            if to_query.is_empty() {
+                break;
+            }
+
+            let mut queries = JoinSet::new();
+            for node in to_query {
+                queried.push(node.address);
+
+                let dht_node = self.clone();
+                let query = match get_peers {
+                    true => Query::GetPeers{info_hash: Sha1Hash(target)},
+                    false => Query::FindNode{target},
+                };
+                queries.spawn(async move {
+                    let response = dht_node.query(node.address, query).await;
+                    (node, response)
+                });
+            }
+
+            while let Some(answer) = queries.join_next().await {
+                let (node, response) = match answer {
+                    Ok(answer) => answer,
+                    Err(e) => {
+                        tracing::debug!("DHT query task failed: {}", e);
+                        continue;
+                    }
+                };
+
+                let response = match response {
+                    Ok(response) => response,
+                    Err(e) => {
+                        tracing::trace!("DHT query failed: {}", e);
+                        candidates.retain(|candidate| candidate.address != node.address);
+                        continue;
+                    }
+                };
+
+                for peer in response.values {
+                    if !peers.contains(&peer) {
+                        peers.push(peer);
+                    }
+                }
+
+                for new_node in response.nodes {
+                    if new_node.id != self.id && !candidates.iter().any(|candidate| candidate.address == new_node.address) {
+                        candidates.push(new_node);
+                    }
+                }
+
+                closest.push((NodeInfo{id: response.id, address: node.address}, response.token));
+            }
+        }
+
+        closest.sort_by_key(|(node, _)| routing_table::distance(&node.id, &target));
+        closest.truncate(routing_table::K);
+
+        Lookup {
+            closest,
+            peers,
+        }
+    }
+
+    /// Joins the DHT through `bootstrap_nodes` by looking up our own id.
+    async fn bootstrap(&self, bootstrap_nodes: &[String]) {
+        let mut addresses = Vec::new();
+        for bootstrap_node in bootstrap_nodes {
+            match tokio::net::lookup_host(bootstrap_node).await {
+                Ok(resolved) => addresses.extend(resolved.filter(|address| address.is_ipv4())),
+                Err(e) => tracing::debug!("Failed to resolve DHT bootstrap node '{}': {}", bootstrap_node, e),
+            }
+        }
+
+        let mut queries = JoinSet::new();
+        for address in addresses {
+            let dht_node = self.clone();
+            queries.spawn(async move {
+                dht_node.query(address, Query::FindNode{target: dht_node.id}).await
+            });
+        }
+        while let Some(answer) = queries.join_next().await {
+            if let Ok(Ok(response)) = answer {
+                let mut routing_table = self.routing_table.lock().await;
+                for node in response.nodes {
+                    routing_table.insert(node);
+                }
+            }
+        }
+
+        self.lookup(self.id, false).await;
+        tracing::debug!("DHT bootstrapped with {} nodes", self.routing_table.lock().await.len());
+    }
+
+    async fn find_peers(&self, info_hash: &Sha1Hash, port: Option<u16>, bootstrap_nodes: &[String]) -> Vec<PeerAddress> {
+        if self.routing_table.lock().await.is_empty() {
+            self.bootstrap(bootstrap_nodes).await;
+        }
+
+        let lookup = self.lookup(info_hash.0, true).await;
+
+        if let Some(port) = port {
+            let mut announces = JoinSet::new();
+            for (node, token) in lookup.closest {
+                let token = match token {
+                    Some(token) => token,
+                    None => continue
+                };
+
+                let dht_node = self.clone();
+                let query = Query::AnnouncePeer{info_hash: info_hash.clone(), port, implied_port: false, token};
+                announces.spawn(async move {
+                    dht_node.query(node.address, query).await
+                });
+            }
+            while announces.join_next().await.is_some() {}
+        }
+
+        lookup.peers
+            .into_iter()
+            .map(PeerAddress::from)
+            .collect()
+    }
+}
+
+struct Dht {
+    rx: mpsc::Receiver<ClientMessage>,
+    node: DhtNode,
+    bootstrap_nodes: Vec<String>,
+    state_path: Option<std::path::PathBuf>,
+}
+
+impl Dht {
+    async fn new(address: SocketAddr, rx: mpsc::Receiver<ClientMessage>, bootstrap_nodes: Vec<String>, state: Option<DhtState>, state_path: Option<std::path::PathBuf>) -> Result<Self> {
+        let socket = UdpSocket::bind(address).await.context("couldn't bind the DHT socket")?;
+
+        let (id, nodes) = match state {
+            Some(state) => (state.id, state.nodes),
+            None => {
+                let mut id = [0; 20];
+                getrandom::getrandom(&mut id).expect("Failed to generate random DHT node id");
+                (id, Vec::new())
+            }
+        };
+
+        let mut routing_table = RoutingTable::new(id);
+        for node in nodes {
+            routing_table.insert(node);
+        }
+
+        let node = DhtNode {
+            id,
+            socket: Arc::new(socket),
+            routing_table: Arc::new(Mutex::new(routing_table)),
+            peer_store: Arc::new(Mutex::new(PeerStore::new())),
+            pending_queries: Arc::new(Mutex::new(HashMap::new())),
+            next_transaction_id: Arc::new(AtomicU16::new(random_transaction_id())),
+        };
+
+        Ok(Self {
+            rx,
+            node,
+            bootstrap_nodes,
+            state_path,
+        })
+    }
+
+    async fn load_state(state_path: &std::path::Path) -> Option<DhtState> {
+        let state = tokio::fs::read_to_string(state_path).await.ok()?;
+
+        match serde_json::from_str::<DhtState>(&state) {
+            Ok(state) => Some(state),
+            Err(e) => {
+                tracing::warn!("Ignoring invalid DHT state file: {}", e);
+                None
+            }
+        }
+    }
+
+    async fn save_state(&self) -> Result<()> {
+        let state_path = match &self.state_path {
+            Some(state_path) => state_path,
+            None => return Ok(())
+        };
+
+        let state = DhtState {
+            id: self.node.id,
+            nodes: self.node.routing_table.lock().await.nodes(),
+        };
+        let state = serde_json::to_string_pretty(&state).unwrap(); // dht state is always valid json
+
+        tokio::fs::write(state_path, state).await.context("couldn't write to DHT state file")?;
+
+        Ok(())
+    }
+
+    #[tracing::instrument(
+        name = "Dht::run",
+        skip(self),
+        fields(
+            local_address = ?self.node.socket.local_addr().ok(),
+        )
+    )]
+    async fn run(mut self) -> Result<()> {
+        let mut tasks = JoinSet::new();
+
+        let dht_node = self.node.clone();
+        let bootstrap_nodes = self.bootstrap_nodes.clone();
+        tasks.spawn(async move {
+            dht_node.bootstrap(&bootstrap_nodes).await;
+        });
+
+        let mut buffer = vec![0; MAX_PACKET_SIZE];
+        let mut refresh_interval = tokio::time::interval(std::time::Duration::from_secs(DHT_REFRESH_INTERVAL_SECS));
+        refresh_interval.tick().await; // the bootstrap above already fills the table
+        let mut save_state_interval = tokio::time::interval(std::time::Duration::from_secs(unsafe { crate::CLIENT_OPTIONS.save_state_interval_secs }));
+
+        loop {
+            tokio::select! {
+                Some(msg) = self.rx.recv() => {
+                    match msg {
+                        ClientMessage::Shutdown => break,
+                        ClientMessage::FindDhtPeers{info_hash, port, tx} => {
+                            let dht_node = self.node.clone();
+                            let bootstrap_nodes = self.bootstrap_nodes.clone();
+                            tasks.spawn(async move {
+                                let peers = dht_node.find_peers(&info_hash, port, &bootstrap_nodes).await;
+                                tracing::debug!("DHT found {} peers for info hash {}", peers.len(), info_hash.to_hex());
+
+                                if let Err(e) = tx.send(ClientMessage::DhtPeers{peers}).await {
+                                    tracing::debug!("Failed to send DHT peers: {}", e);
+                                }
+                            });
+                        },
+                        ClientMessage::DhtNode{address} => {
+                            // nodes learned from peers are only added once they answer
+                            let dht_node = self.node.clone();
+                            tasks.spawn(async move {
+                                let _ = dht_node.query(address, Query::Ping).await;
+                            });
+                        },
+                        _ => {}
+                    }
+                },
+                received = self.node.socket.recv_from(&mut buffer) => {
+                    let (size, address) = match received {
+                        Ok(received) => received,
+                        Err(e) => {
+                            tracing::debug!("Failed to receive DHT packet: {}", e);
+                            continue;
+                        }
+                    };
+
+                    if let Err(e) = self.node.handle_packet(&buffer[..size], address).await {
+                        tracing::debug!("Invalid DHT packet from '{}': {}", address, e);
+                    }
+                },
+                Some(_) = tasks.join_next(), if !tasks.is_empty() => {},
+                _ = refresh_interval.tick() => {
+                    let dht_node = self.node.clone();
+                    let bootstrap_nodes = self.bootstrap_nodes.clone();
+                    tasks.spawn(async move {
+                        match dht_node.routing_table.lock().await.is_empty() {
+                            true => dht_node.bootstrap(&bootstrap_nodes).await,
+                            false => { dht_node.lookup(dht_node.id, false).await; }
+                        }
+                    });
+                },
+                _ = save_state_interval.tick() => {
+                    if let Err(e) = self.save_state().await {
+                        tracing::error!("Failed to save DHT state: {}", e);
+                    }
+                }
+            }
+        }
+
+        tasks.abort_all();
+        self.save_state().await?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod dht_tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_nodes_on_localhost_find_announced_peers() {
+        let localhost: SocketAddr = "127.0.0.1:0".parse().unwrap();
+
+        let first = DhtHandle::new(localhost, Vec::new(), None).await.unwrap();
+        let bootstrap_nodes = vec![first.local_address.to_string()];
+
+        let mut handles = vec![first];
+        for _ in 0..5 {
+            handles.push(DhtHandle::new(localhost, bootstrap_nodes.clone(), None).await.unwrap());
+        }
+
+        let info_hash = Sha1Hash([0x42; 20]);
+        let (tx, mut rx) = mpsc::channel(10);
+
+        // the announcing node doesn't know any peers yet
+        handles[1].find_peers(info_hash.clone(), Some(51413), tx.clone()).await.unwrap();
+        match tokio::time::timeout(std::time::Duration::from_secs(10), rx.recv()).await.unwrap() {
+            Some(ClientMessage::DhtPeers{peers}) => assert!(peers.is_empty()),
+            _ => panic!("expected dht peers"),
+        }
+
+        handles[5].find_peers(info_hash, None, tx).await.unwrap();
+        match tokio::time::timeout(std::time::Duration::from_secs(10), rx.recv()).await.unwrap() {
+            Some(ClientMessage::DhtPeers{peers}) => {
+                assert_eq!(peers, vec![PeerAddress("127.0.0.1:51413".parse().unwrap())]);
+            },
+            _ => panic!("expected dht peers"),
+        }
+
+        for mut handle in handles {
+            handle.shutdown().await.unwrap();
+            handle.join().await.unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn test_node_survives_malformed_packets() {
+        let mut handle = DhtHandle::new("127.0.0.1:0".parse().unwrap(), Vec::new(), None).await.unwrap();
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+
+        async fn recv(socket: &UdpSocket) -> KrpcMessage {
+            let mut buffer = vec![0; MAX_PACKET_SIZE];
+            let size = tokio::time::timeout(std::time::Duration::from_secs(5), socket.recv(&mut buffer)).await.unwrap().unwrap();
+            KrpcMessage::from_bytes(&buffer[..size]).unwrap()
+        }
+
+        // empty and truncated packets are dropped without an answer
+        socket.send_to(b"", handle.local_address).await.unwrap();
+        socket.send_to(b"d1:t2:aa1:y1:q1:q4:pi", handle.local_address).await.unwrap();
+        socket.send_to(b"5:ab", handle.local_address).await.unwrap();
+
+        let unknown = b"d1:ad2:id20:abcdefghij0123456789e1:q4:vote1:t2:bb1:y1:qe";
+        socket.send_to(unknown, handle.local_address).await.unwrap();
+        match recv(&socket).await {
+            KrpcMessage::Error{transaction_id, code, ..} => {
+                assert_eq!(transaction_id, b"bb".to_vec());
+                assert_eq!(code, krpc::ERROR_METHOD_UNKNOWN);
+            },
+            message => panic!("expected an error, got {:?}", message),
+        }
+
+        let ping = KrpcMessage::Query{transaction_id: b"cc".to_vec(), id: [1; 20], query: Query::Ping};
+        socket.send_to(&ping.as_bytes().unwrap(), handle.local_address).await.unwrap();
+        assert!(matches!(recv(&socket).await, KrpcMessage::Response{transaction_id, ..} if transaction_id == b"cc".to_vec()));
+
+        handle.shutdown().await.unwrap();
+        handle.join().await.unwrap();
+    }
+}
@@ -0,0 +1,306 @@
+use anyhow::{anyhow, Result};
+use serde::{Serialize, Deserialize};
+
+use std::collections::BTreeMap;
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
+
+use crate::utils::bencode::BencodedValue;
+use crate::utils::sha1hash::Sha1Hash;
+
+pub type NodeId = [u8; 20];
+
+pub const ERROR_GENERIC: i64 = 201;
+pub const ERROR_PROTOCOL: i64 = 203;
+pub const ERROR_METHOD_UNKNOWN: i64 = 204;
+
+/// Id and address of a DHT node, sent as 26 bytes in the compact node info format.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct NodeInfo {
+    pub id: NodeId,
+    pub address: SocketAddr,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Query {
+    Ping,
+    FindNode{target: NodeId},
+    GetPeers{info_hash: Sha1Hash},
+    AnnouncePeer{info_hash: Sha1Hash, port: u16, implied_port: bool, token: Vec<u8>},
+    /// A method we don't know, answered with a method unknown error.
+    Unknown{method: Vec<u8>},
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Response {
+    pub id: NodeId,
+    pub nodes: Vec<NodeInfo>,
+    pub values: Vec<SocketAddr>,
+    pub token: Option<Vec<u8>>,
+}
+
+/// A KRPC message (BEP 5), every message is a single bencoded dictionary in a UDP packet.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum KrpcMessage {
+    Query{transaction_id: Vec<u8>, id: NodeId, query: Query},
+    Response{transaction_id: Vec<u8>, response: Response},
+    Error{transaction_id: Vec<u8>, code: i64, message: String},
+}
+
+impl KrpcMessage {
+    pub fn transaction_id(&self) -> &[u8] {
+        match self {
+            KrpcMessage::Query{transaction_id, ..} => transaction_id,
+            KrpcMessage::Response{transaction_id, ..} => transaction_id,
+            KrpcMessage::Error{transaction_id, ..} => transaction_id,
+        }
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        let message = BencodedValue::from_bytes(bytes)?;
+
+        let transaction_id = message.get_from_dict(b"t")?.try_into_byte_string()?.clone();
+        let message_type = message.get_from_dict(b"y")?.try_into_byte_string()?.clone();
+
+        match message_type.as_slice() {
+            b"q" => {
+                let method = message.get_from_dict(b"q")?.try_into_byte_string()?.clone();
+                let arguments = message.get_from_dict(b"a")?;
+                let id = get_node_id(&arguments, b"id")?;
+
+                let query = match method.as_slice() {
+                    b"ping" => Query::Ping,
+                    b"find_node" => Query::FindNode {
+                        target: get_node_id(&arguments, b"target")?,
+                    },
+                    b"get_peers" => Query::GetPeers {
+                        info_hash: Sha1Hash(get_node_id(&arguments, b"info_hash")?),
+                    },
+                    b"announce_peer" => {
+                        let port = arguments.get_from_dict(b"port")?.try_into_integer()?;
+                        let implied_port = matches!(arguments.get_from_dict(b"implied_port"), Ok(BencodedValue::Integer(1)));
+
+                        Query::AnnouncePeer {
+                            info_hash: Sha1Hash(get_node_id(&arguments, b"info_hash")?),
+                            port: u16::try_from(port).map_err(|_| anyhow!("Invalid port in announce_peer: {}", port))?,
+                            implied_port,
+                            token: arguments.get_from_dict(b"token")?.try_into_byte_string()?.clone(),
+                        }
+                    },
+                    _ => Query::Unknown{method},
+                };
+
+                Ok(KrpcMessage::Query{transaction_id, id, query})
+            },
+            b"r" => {
+                let values = message.get_from_dict(b"r")?;
+
+                let nodes = match values.get_from_dict(b"nodes") {
+                    Ok(BencodedValue::ByteString(nodes)) => compact_to_nodes(&nodes)?,
+                    _ => Vec::new()
+                };
+
+                let peers = match values.get_from_dict(b"values") {
+                    Ok(BencodedValue::List(peers)) => peers
+                        .iter()
+                        .filter_map(|peer| match peer {
+                            BencodedValue::ByteString(peer) => compact_to_address(peer),
+                            _ => None
+                        })
+                        .collect(),
+                    _ => Vec::new()
+                };
+
+                let token = match values.get_from_dict(b"token") {
+                    Ok(BencodedValue::ByteString(token)) => Some(token),
+                    _ => None
+                };
+
+                let response = Response {
+                    id: get_node_id(&values, b"id")?,
+                    nodes,
+                    values: peers,
+                    token,
+                };
+
+                Ok(KrpcMessage::Response{transaction_id, response})
+            },
+            b"e" => {
+                let error = message.get_from_dict(b"e")?;
+                let code = error.get_from_list(0)?.try_into_integer()?;
+                let error_message = String::from_utf8_lossy(error.get_from_list(1)?.try_into_byte_string()?).to_string();
+
+                Ok(KrpcMessage::Error{transaction_id, code, message: error_message})
+            },
+            _ => Err(anyhow!("Unknown KRPC message type: {}", String::from_utf8_lossy(&message_type)))
+        }
+    }
+
+    pub fn as_bytes(&self) -> Result<Vec<u8>> {
+        let mut message = BTreeMap::new();
+        message.insert(b"t".to_vec(), BencodedValue::ByteString(self.transaction_id().to_vec()));
+
+        match self {
+            KrpcMessage::Query{id, query, ..} => {
+                let mut arguments = BTreeMap::new();
+                arguments.insert(b"id".to_vec(), BencodedValue::ByteString(id.to_vec()));
+
+                let method: &[u8] = match query {
+                    Query::Ping => b"ping",
+                    Query::FindNode{target} => {
+                        arguments.insert(b"target".to_vec(), BencodedValue::ByteString(target.to_vec()));
+                        b"find_node"
+                    },
+                    Query::GetPeers{info_hash} => {
+                        arguments.insert(b"info_hash".to_vec(), BencodedValue::ByteString(info_hash.0.to_vec()));
+                        b"get_peers"
+                    },
+                    Query::AnnouncePeer{info_hash, port, implied_port, token} => {
+                        arguments.insert(b"info_hash".to_vec(), BencodedValue::ByteString(info_hash.0.to_vec()));
+                        arguments.insert(b"port".to_vec(), BencodedValue::Integer(*port as i64));
+                        arguments.insert(b"implied_port".to_vec(), BencodedValue::Integer(*implied_port as i64));
+                        arguments.insert(b"token".to_vec(), BencodedValue::ByteString(token.clone()));
+                        b"announce_peer"
+                    },
+                    Query::Unknown{method} => method,
+                };
+
+                message.insert(b"y".to_vec(), BencodedValue::ByteString(b"q".to_vec()));
+                message.insert(b"q".to_vec(), BencodedValue::ByteString(method.to_vec()));
+                message.insert(b"a".to_vec(), BencodedValue::Dict(arguments));
+            },
+            KrpcMessage::Response{response, ..} => {
+                let mut values = BTreeMap::new();
+                values.insert(b"id".to_vec(), BencodedValue::ByteString(response.id.to_vec()));
+
+                let nodes = nodes_to_compact(&response.nodes);
+                if !nodes.is_empty() {
+                    values.insert(b"nodes".to_vec(), BencodedValue::ByteString(nodes));
+                }
+
+                if !response.values.is_empty() {
+                    let peers = response.values
+                        .iter()
+                        .filter_map(address_to_compact)
+                        .map(BencodedValue::ByteString)
+                        .collect();
+                    values.insert(b"values".to_vec(), BencodedValue::List(peers));
+                }
+
+                if let Some(token) = &response.token {
+                    values.insert(b"token".to_vec(), BencodedValue::ByteString(token.clone()));
+                }
+
+                message.insert(b"y".to_vec(), BencodedValue::ByteString(b"r".to_vec()));
+                message.insert(b"r".to_vec(), BencodedValue::Dict(values));
+            },
+            KrpcMessage::Error{code, message: error_message, ..} => {
+                let error = vec![BencodedValue::Integer(*code), BencodedValue::ByteString(error_message.as_bytes().to_vec())];
+
+                message.insert(b"y".to_vec(), BencodedValue::ByteString(b"e".to_vec()));
+                message.insert(b"e".to_vec(), BencodedValue::List(error));
+            },
+        }
+
+        BencodedValue::Dict(message).as_bytes()
+    }
+}
+
+fn get_node_id(dict: &BencodedValue, key: &[u8]) -> Result<NodeId> {
+    let id = dict.get_from_dict(key)?;
+    let id = id.try_into_byte_string()?;
+
+    id.as_slice().try_into().map_err(|_| anyhow!("Invalid node id length: {}", id.len()))
+}
+
+/// Only ipv4 addresses fit the compact formats of BEP 5.
+pub fn address_to_compact(address: &SocketAddr) -> Option<Vec<u8>> {
+    match address {
+        SocketAddr::V4(address) => {
+            let mut bytes = address.ip().octets().to_vec();
+            bytes.extend_from_slice(&address.port().to_be_bytes());
+            Some(bytes)
+        },
+        SocketAddr::V6(_) => None
+    }
+}
+
+pub fn compact_to_address(bytes: &[u8]) -> Option<SocketAddr> {
+    if bytes.len() != 6 {
+        return None;
+    }
+
+    let ip = Ipv4Addr::new(bytes[0], bytes[1], bytes[2], bytes[3]);
+    let port = u16::from_be_bytes([bytes[4], bytes[5]]);
+
+    Some(SocketAddr::V4(SocketAddrV4::new(ip, port)))
+}
+
+pub fn nodes_to_compact(nodes: &[NodeInfo]) -> Vec<u8> {
+    let mut bytes = Vec::new();
+    for node in nodes {
+        if let Some(address) = address_to_compact(&node.address) {
+            bytes.extend_from_slice(&node.id);
+            bytes.extend_from_slice(&address);
+        }
+    }
+
+    bytes
+}
+
+pub fn compact_to_nodes(bytes: &[u8]) -> Result<Vec<NodeInfo>> {
+    if !bytes.len().is_multiple_of(26) {
+        return Err(anyhow!("Invalid compact node info length: {}", bytes.len()));
+    }
+
+    Ok(bytes
+        .chunks_exact(26)
+        .filter_map(|node| Some(NodeInfo {
+            id: node[..20].try_into().unwrap(), // chunks are always 26 bytes
+            address: compact_to_address(&node[20..])?,
+        }))
+        .filter(|node| node.address.port() != 0)
+        .collect())
+}
+
+#[cfg(test)]
+mod krpc_tests {
+    use super::*;
+
+    #[test]
+    fn test_ping_query_matches_bep_example() {
+        let message = KrpcMessage::Query {
+            transaction_id: b"aa".to_vec(),
+            id: *b"abcdefghij0123456789",
+            query: Query::Ping,
+        };
+
+        let bytes = message.as_bytes().unwrap();
+        assert_eq!(bytes, b"d1:ad2:id20:abcdefghij0123456789e1:q4:ping1:t2:aa1:y1:qe".to_vec());
+        assert_eq!(KrpcMessage::from_bytes(&bytes).unwrap(), message);
+    }
+
+    #[test]
+    fn test_get_peers_response_roundtrip() {
+        let message = KrpcMessage::Response {
+            transaction_id: b"aa".to_vec(),
+            response: Response {
+                id: [1; 20],
+                nodes: vec![NodeInfo{id: [2; 20], address: "10.0.0.2:6881".parse().unwrap()}],
+                values: vec!["10.0.0.3:51413".parse().unwrap()],
+                token: Some(b"aoeusnth".to_vec()),
+            },
+        };
+
+        let bytes = message.as_bytes().unwrap();
+        assert_eq!(KrpcMessage::from_bytes(&bytes).unwrap(), message);
+    }
+
+    #[test]
+    fn test_error_roundtrip() {
+        let bytes = b"d1:eli201e23:A Generic Error Ocurrede1:t2:aa1:y1:ee";
+
+        let message = KrpcMessage::from_bytes(bytes).unwrap();
+        assert_eq!(message, KrpcMessage::Error{transaction_id: b"aa".to_vec(), code: 201, message: "A Generic Error Ocurred".to_string()});
+        assert_eq!(message.as_bytes().unwrap(), bytes.to_vec());
+    }
+}
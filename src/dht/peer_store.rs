@@ -0,0 +1,132 @@
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::time::{Duration, Instant};
+
+use crate::utils::sha1hash::{Sha1Hash, sha1_hash};
+
+/// Tokens are valid for up to twice this long, the secret is rotated this often.
+const TOKEN_SECRET_ROTATION_SECS: u64 = 5 * 60;
+/// Announced peers are forgotten after this long unless they announce again.
+const PEER_EXPIRATION_SECS: u64 = 30 * 60;
+/// Most peers stored and returned per info hash.
+const MAX_PEERS_PER_INFO_HASH: usize = 100;
+const MAX_RETURNED_PEERS: usize = 50;
+
+/// Tokens handed out in get_peers responses and the peers announced with them.
+pub struct PeerStore {
+    secret: [u8; 20],
+    previous_secret: [u8; 20],
+    rotated_at: Instant,
+    peers: HashMap<[u8; 20], Vec<(SocketAddr, Instant)>>,
+}
+
+impl Default for PeerStore {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl PeerStore {
+    pub fn new() -> Self {
+        let secret = random_secret();
+
+        Self {
+            secret,
+            previous_secret: secret,
+            rotated_at: Instant::now(),
+            peers: HashMap::new(),
+        }
+    }
+
+    fn rotate_secret(&mut self) {
+        if self.rotated_at.elapsed() < Duration::from_secs(TOKEN_SECRET_ROTATION_SECS) {
+            return;
+        }
+
+        self.previous_secret = self.secret;
+        self.secret = random_secret();
+        self.rotated_at = Instant::now();
+    }
+
+    /// The token a node at `ip` has to present to announce itself.
+    pub fn token(&mut self, ip: &IpAddr) -> Vec<u8> {
+        self.rotate_secret();
+        make_token(&self.secret, ip)
+    }
+
+    pub fn is_valid_token(&mut self, ip: &IpAddr, token: &[u8]) -> bool {
+        self.rotate_secret();
+        token == make_token(&self.secret, ip).as_slice() || token == make_token(&self.previous_secret, ip).as_slice()
+    }
+
+    pub fn announce(&mut self, info_hash: &Sha1Hash, address: SocketAddr) {
+        let peers = self.peers.entry(info_hash.0).or_default();
+        peers.retain(|(peer, announced_at)| peer != &address && announced_at.elapsed() < Duration::from_secs(PEER_EXPIRATION_SECS));
+
+        if peers.len() >= MAX_PEERS_PER_INFO_HASH {
+            peers.remove(0);
+        }
+        peers.push((address, Instant::now()));
+    }
+
+    pub fn peers(&mut self, info_hash: &Sha1Hash) -> Vec<SocketAddr> {
+        let peers = match self.peers.get_mut(&info_hash.0) {
+            Some(peers) => peers,
+            None => return Vec::new()
+        };
+        peers.retain(|(_, announced_at)| announced_at.elapsed() < Duration::from_secs(PEER_EXPIRATION_SECS));
+
+        peers
+            .iter()
+            .rev()
+            .take(MAX_RETURNED_PEERS)
+            .map(|(peer, _)| *peer)
+            .collect()
+    }
+}
+
+fn make_token(secret: &[u8; 20], ip: &IpAddr) -> Vec<u8> {
+    let mut value = secret.to_vec();
+    match ip {
+        IpAddr::V4(ip) => value.extend_from_slice(&ip.octets()),
+        IpAddr::V6(ip) => value.extend_from_slice(&ip.octets()),
+    }
+
+    sha1_hash(value).0[..8].to_vec()
+}
+
+fn random_secret() -> [u8; 20] {
+    let mut secret = [0; 20];
+    getrandom::getrandom(&mut secret).expect("getrandom failed");
+
+    secret
+}
+
+#[cfg(test)]
+mod peer_store_tests {
+    use super::*;
+
+    #[test]
+    fn test_tokens_are_bound_to_the_ip() {
+        let mut peer_store = PeerStore::new();
+        let ip: IpAddr = "10.0.0.1".parse().unwrap();
+        let other_ip: IpAddr = "10.0.0.2".parse().unwrap();
+
+        let token = peer_store.token(&ip);
+        assert!(peer_store.is_valid_token(&ip, &token));
+        assert!(!peer_store.is_valid_token(&other_ip, &token));
+    }
+
+    #[test]
+    fn test_announced_peers_are_deduplicated() {
+        let mut peer_store = PeerStore::new();
+        let info_hash = Sha1Hash([7; 20]);
+        let peer: SocketAddr = "10.0.0.1:6881".parse().unwrap();
+
+        peer_store.announce(&info_hash, peer);
+        peer_store.announce(&info_hash, peer);
+
+        assert_eq!(peer_store.peers(&info_hash), vec![peer]);
+        assert!(peer_store.peers(&Sha1Hash([8; 20])).is_empty());
+    }
+}
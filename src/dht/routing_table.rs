@@ -0,0 +1,189 @@
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+use super::krpc::{NodeId, NodeInfo};
+
+/// Maximum number of nodes in a bucket.
+pub const K: usize = 8;
+/// Nodes that haven't been heard from for this long can be replaced by new ones.
+const STALE_NODE_SECS: u64 = 15 * 60;
+/// Nodes are removed after failing to answer this many queries in a row.
+const MAX_FAILED_QUERIES: u32 = 3;
+
+#[derive(Debug, Clone)]
+struct Node {
+    info: NodeInfo,
+    last_seen: Instant,
+    failed_queries: u32,
+}
+
+impl Node {
+    fn is_replaceable(&self) -> bool {
+        self.failed_queries > 0 || self.last_seen.elapsed() > Duration::from_secs(STALE_NODE_SECS)
+    }
+}
+
+pub fn distance(a: &NodeId, b: &NodeId) -> NodeId {
+    let mut distance = [0; 20];
+    for i in 0..20 {
+        distance[i] = a[i] ^ b[i];
+    }
+
+    distance
+}
+
+/// Kademlia routing table with one bucket per length of the prefix shared with our own id.
+#[derive(Debug)]
+pub struct RoutingTable {
+    id: NodeId,
+    buckets: Vec<Vec<Node>>,
+}
+
+impl RoutingTable {
+    pub fn new(id: NodeId) -> Self {
+        Self {
+            id,
+            buckets: vec![Vec::new(); 160],
+        }
+    }
+
+    fn bucket_index(&self, id: &NodeId) -> Option<usize> {
+        let distance = distance(&self.id, id);
+
+        let leading_zeros = match distance.iter().position(|byte| *byte != 0) {
+            Some(position) => position * 8 + distance[position].leading_zeros() as usize,
+            None => return None // our own id
+        };
+
+        Some(159 - leading_zeros)
+    }
+
+    /// Adds a node that just proved to be alive, or refreshes it if it is known.
+    /// Returns false if its bucket is full of good nodes.
+    pub fn insert(&mut self, info: NodeInfo) -> bool {
+        let bucket_index = match self.bucket_index(&info.id) {
+            Some(bucket_index) => bucket_index,
+            None => return false
+        };
+        let bucket = &mut self.buckets[bucket_index];
+
+        if let Some(node) = bucket.iter_mut().find(|node| node.info.id == info.id) {
+            node.info.address = info.address;
+            node.last_seen = Instant::now();
+            node.failed_queries = 0;
+            return true;
+        }
+
+        let node = Node {
+            info,
+            last_seen: Instant::now(),
+            failed_queries: 0,
+        };
+
+        if bucket.len() < K {
+            bucket.push(node);
+            return true;
+        }
+
+        // replace the node we are least sure about
+        let replaceable = bucket
+            .iter()
+            .enumerate()
+            .filter(|(_, node)| node.is_replaceable())
+            .max_by_key(|(_, node)| (node.failed_queries, node.last_seen.elapsed()))
+            .map(|(index, _)| index);
+
+        match replaceable {
+            Some(index) => {
+                bucket[index] = node;
+                true
+            },
+            None => false
+        }
+    }
+
+    /// Records that the node at `address` didn't answer a query.
+    pub fn failed(&mut self, address: &SocketAddr) {
+        for bucket in self.buckets.iter_mut() {
+            if let Some(node) = bucket.iter_mut().find(|node| &node.info.address == address) {
+                node.failed_queries += 1;
+            }
+            bucket.retain(|node| node.failed_queries < MAX_FAILED_QUERIES);
+        }
+    }
+
+    /// Returns up to `count` known nodes closest to `target`.
+    pub fn closest(&self, target: &NodeId, count: usize) -> Vec<NodeInfo> {
+        let mut nodes = self.nodes();
+        nodes.sort_by_key(|node| distance(&node.id, target));
+        nodes.truncate(count);
+
+        nodes
+    }
+
+    pub fn nodes(&self) -> Vec<NodeInfo> {
+        self.buckets
+            .iter()
+            .flatten()
+            .map(|node| node.info.clone())
+            .collect()
+    }
+
+    pub fn len(&self) -> usize {
+        self.buckets.iter().map(|bucket| bucket.len()).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+#[cfg(test)]
+mod routing_table_tests {
+    use super::*;
+
+    fn node(id: NodeId, port: u16) -> NodeInfo {
+        NodeInfo {
+            id,
+            address: SocketAddr::from(([127, 0, 0, 1], port)),
+        }
+    }
+
+    #[test]
+    fn test_closest_nodes_are_sorted_by_distance() {
+        let mut routing_table = RoutingTable::new([0; 20]);
+        let mut far = [0; 20];
+        far[0] = 0x80;
+        let mut near = [0; 20];
+        near[19] = 1;
+        let mut middle = [0; 20];
+        middle[10] = 1;
+
+        assert!(routing_table.insert(node(far, 1)));
+        assert!(routing_table.insert(node(near, 2)));
+        assert!(routing_table.insert(node(middle, 3)));
+        assert!(!routing_table.insert(node([0; 20], 4)));
+
+        let closest = routing_table.closest(&[0; 20], 2);
+        assert_eq!(closest.iter().map(|node| node.id).collect::<Vec<_>>(), vec![near, middle]);
+    }
+
+    #[test]
+    fn test_full_bucket_only_replaces_failing_nodes() {
+        let mut routing_table = RoutingTable::new([0; 20]);
+        // all of these share no prefix with our id so they land in the same bucket
+        for i in 0..K as u8 {
+            let mut id = [0; 20];
+            id[0] = 0x80 | i;
+            assert!(routing_table.insert(node(id, 1000 + i as u16)));
+        }
+
+        let mut id = [0; 20];
+        id[0] = 0xff;
+        assert!(!routing_table.insert(node(id, 2000)));
+
+        routing_table.failed(&SocketAddr::from(([127, 0, 0, 1], 1000)));
+        assert!(routing_table.insert(node(id, 2000)));
+        assert_eq!(routing_table.len(), K);
+    }
+}
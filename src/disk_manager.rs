@@ -3,14 +3,19 @@ use tokio::task::JoinHandle;
 use tokio::sync::{mpsc, Mutex};
 use anyhow::{anyhow, Context, Result};
 
+use std::collections::HashMap;
 use std::sync::Arc;
 
 use crate::peer::block_picker::Piece;
 use crate::peer::Block;
 use crate::messager::ClientMessage;
+use crate::utils::sha1hash::{Sha1Hash, sha1_hash};
 
 pub mod torrent_context;
-pub use torrent_context::DiskTorrentContext;
+pub use torrent_context::{DiskTorrentContext, UnsafeTorrentPath};
+
+/// After this many md5sum mismatches of a file the md5sum in the torrent file is taken to be wrong.
+const MAX_MD5_MISMATCHES: u32 = 3;
 
 #[derive(Debug, Clone)]
 pub struct DownloadableFile {
@@ -18,8 +23,34 @@ pub struct DownloadableFile {
     pub size: u64,
     pub path: String,
 
-    // should be 32 HEX characters, but for future implementation
-    pub _md5sum: Option<Vec<u8>>
+    // should be 32 HEX characters
+    pub md5sum: Option<Vec<u8>>
+}
+
+impl DownloadableFile {
+    /// Indexes of the first and last piece that contain data of this file.
+    pub fn piece_range(&self, piece_length: usize) -> Option<std::ops::RangeInclusive<u32>> {
+        if self.size == 0 {
+            return None;
+        }
+
+        let first = self.start / piece_length as u64;
+        let last = (self.start + self.size - 1) / piece_length as u64;
+
+        Some(first as u32..=last as u32)
+    }
+
+    /// Indexes of the pieces that only contain data of this file, the last piece of the torrent can be shorter.
+    pub fn inner_piece_range(&self, piece_length: usize, torrent_size: u64) -> std::ops::Range<u32> {
+        let end = self.start + self.size;
+        let first = self.start.div_ceil(piece_length as u64);
+        let last = match end == torrent_size {
+            true => end.div_ceil(piece_length as u64),
+            false => end / piece_length as u64
+        };
+
+        first as u32..last.max(first) as u32
+    }
 }
 
 pub struct DiskManagerHandle {
@@ -28,10 +59,11 @@ pub struct DiskManagerHandle {
 }
 
 impl DiskManagerHandle {
-    pub fn new(torrent_context: DiskTorrentContext) -> Self {
+    /// `written_pieces` are the blocks of unverified pieces that were written in a previous session.
+    pub fn new(torrent_context: DiskTorrentContext, bitfield: &[u8], written_pieces: Vec<Piece>) -> Self {
         let (tx, rx) = mpsc::channel(100);
 
-        let disk_writer = DiskManager::new(rx, torrent_context);
+        let disk_writer = DiskManager::new(rx, torrent_context, bitfield, written_pieces);
         let join_handle = tokio::spawn(async move {
             if let Err(e) = disk_writer.run().await {
                tracing::error!("Disk writer error: {:?}", e);
@@ -63,6 +95,11 @@ impl DiskManagerHandle {
         Ok(())
     }
 
+    pub async fn recheck(&mut self, info_hash: Sha1Hash) -> Result<()> {
+        self.tx.send(ClientMessage::Recheck{ info_hash }).await?;
+        Ok(())
+    }
+
     pub async fn read_block(&mut self, block: Block, tx: mpsc::Sender<ClientMessage>) -> Result<()> {
         self.tx.send(ClientMessage::Request{ block, tx }).await?;
         Ok(())
@@ -74,16 +111,42 @@ struct DiskManager {
     
     downloaded: Arc<Mutex<Vec<Piece>>>,
     downloaded_pieces_count: Arc<Mutex<usize>>,
+    verified_pieces: Arc<Mutex<Vec<bool>>>,
+    /// md5sum mismatches of every file that failed the check
+    md5_mismatches: Arc<Mutex<HashMap<String, u32>>>,
     torrent_context: Arc<DiskTorrentContext>,
 }
 
 impl DiskManager {
-    pub fn new(rx: mpsc::Receiver<ClientMessage>, torrent_context: DiskTorrentContext) -> Self {
+    pub fn new(rx: mpsc::Receiver<ClientMessage>, torrent_context: DiskTorrentContext, bitfield: &[u8], written_pieces: Vec<Piece>) -> Self {
+        let torrent_info = &torrent_context.torrent_info;
+
+        // pieces that were verified in a previous session
+        let verified_pieces = (0..torrent_info.pieces_count)
+            .map(|index| bitfield.get(index / 8).is_some_and(|byte| byte & 1 << (7 - index % 8) != 0))
+            .collect::<Vec<bool>>();
+
+        let mut downloaded = verified_pieces
+            .iter()
+            .enumerate()
+            .filter(|(_, verified)| **verified)
+            .map(|(index, _)| Piece {
+                index: index as u32,
+                block_count: torrent_info.get_specific_piece_block_count(index as u32),
+            })
+            .collect::<Vec<Piece>>();
+        let downloaded_pieces_count = downloaded.len();
+
+        // the rest of their blocks is counted as it arrives
+        downloaded.extend(written_pieces.into_iter().filter(|piece| !verified_pieces[piece.index as usize]));
+
         Self {
             rx,
             
-            downloaded: Arc::new(Mutex::new(Vec::new())),
-            downloaded_pieces_count: Arc::new(Mutex::new(0)),
+            downloaded_pieces_count: Arc::new(Mutex::new(downloaded_pieces_count)),
+            downloaded: Arc::new(Mutex::new(downloaded)),
+            verified_pieces: Arc::new(Mutex::new(verified_pieces)),
+            md5_mismatches: Arc::new(Mutex::new(HashMap::new())),
             torrent_context: Arc::new(torrent_context),
         }
     }
@@ -215,6 +278,170 @@ impl DiskManager {
         Ok(block)
     }
 
+    async fn verify_piece(torrent_context: &DiskTorrentContext, index: u32) -> Result<bool> {
+        let piece = Block {
+            index,
+            begin: 0,
+            length: torrent_context.torrent_info.get_specific_piece_length(index) as u32,
+
+            number: 0, // doesn't matter
+            data: None,
+        };
+
+        let data = match DiskManager::read_block(torrent_context, piece).await?.data {
+            Some(data) => data,
+            None => return Err(anyhow!("Read piece {} with no data", index))
+        };
+
+        let expected_hash = torrent_context.torrent_file.get_piece_hash(index as usize)?;
+
+        // hashing a whole piece is cpu heavy so it shouldn't block the reactor
+        let hash = tokio::task::spawn_blocking(move || sha1_hash(data)).await?;
+
+        Ok(hash == expected_hash)
+    }
+
+    /// Files with an md5sum that contain `piece` and have all of their pieces verified.
+    fn completed_files(torrent_context: &DiskTorrentContext, verified_pieces: &[bool], piece: u32) -> Vec<DownloadableFile> {
+        let piece_length = torrent_context.torrent_info.piece_length;
+
+        torrent_context.files
+            .iter()
+            .filter(|file| file.md5sum.is_some())
+            .filter(|file| {
+                match file.piece_range(piece_length) {
+                    Some(range) => range.contains(&piece) && range.clone().all(|index| verified_pieces[index as usize]),
+                    None => false
+                }
+            })
+            .cloned()
+            .collect()
+    }
+
+    async fn md5_matches(torrent_context: &DiskTorrentContext, file: &DownloadableFile) -> Result<bool> {
+        let expected = match &file.md5sum {
+            Some(md5sum) => String::from_utf8_lossy(md5sum).to_lowercase(),
+            None => return Ok(true)
+        };
+
+        let file_path = std::path::Path::new(&torrent_context.dest_path).join(&file.path);
+
+        // hashing a whole file is cpu heavy so it shouldn't block the reactor
+        let hash = tokio::task::spawn_blocking(move || -> Result<String> {
+            use std::io::Read;
+            use md5::Digest;
+
+            let mut fd = std::fs::File::open(file_path)?;
+            let mut hasher = md5::Md5::new();
+            let mut buffer = vec![0u8; 1 << 20];
+            loop {
+                let bytes_read = fd.read(&mut buffer)?;
+                if bytes_read == 0 {
+                    break;
+                }
+                hasher.update(&buffer[..bytes_read]);
+            }
+
+            Ok(hex::encode(hasher.finalize()))
+        }).await??;
+
+        Ok(hash == expected)
+    }
+
+    /// Whether the piece lies inside a file with an md5sum, its file has to pass the check before the piece is announced.
+    fn awaits_md5_check(torrent_context: &DiskTorrentContext, piece: u32) -> bool {
+        let torrent_info = &torrent_context.torrent_info;
+
+        torrent_context.files
+            .iter()
+            .filter(|file| file.md5sum.is_some())
+            .any(|file| file.inner_piece_range(torrent_info.piece_length, torrent_info.torrent_size).contains(&piece))
+    }
+
+    /// Checks the md5sum of a completed file, announces the pieces inside it on a match and queues them for downloading again otherwise.
+    /// Pieces shared with other files passed their hash check and are kept either way.
+    async fn check_file(torrent_context: &DiskTorrentContext, downloaded: &Mutex<Vec<Piece>>, downloaded_pieces_count: &Mutex<usize>, verified_pieces: &Mutex<Vec<bool>>, md5_mismatches: &Mutex<HashMap<String, u32>>, file: DownloadableFile) -> Result<()> {
+        let torrent_info = &torrent_context.torrent_info;
+        let valid = DiskManager::md5_matches(torrent_context, &file).await?;
+        let inner_pieces = file.inner_piece_range(torrent_info.piece_length, torrent_info.torrent_size);
+
+        let bad_md5sum = !valid && {
+            let mut md5_mismatches_guard = md5_mismatches.lock().await;
+            let mismatches = md5_mismatches_guard.entry(file.path.clone()).or_insert(0);
+            *mismatches += 1;
+            *mismatches >= MAX_MD5_MISMATCHES
+        };
+
+        let mut pieces = Vec::new();
+        if valid || bad_md5sum {
+            if bad_md5sum {
+                tracing::warn!("File '{}' failed the md5sum check {} times, keeping its pieces since they passed their hash check", file.path, MAX_MD5_MISMATCHES);
+            }
+
+            for index in inner_pieces {
+                torrent_context.tx.send(ClientMessage::Have { piece: index }).await?;
+            }
+        } else {
+            tracing::warn!("File '{}' failed the md5sum check, discarding its pieces", file.path);
+
+            let mut verified_guard = verified_pieces.lock().await;
+            for index in inner_pieces {
+                if verified_guard[index as usize] {
+                    verified_guard[index as usize] = false;
+                    *downloaded_pieces_count.lock().await -= 1;
+
+                    let piece_length = torrent_info.get_specific_piece_length(index) as u64;
+                    let mut downloaded_guard = torrent_context.downloaded.lock().await;
+                    *downloaded_guard = downloaded_guard.saturating_sub(piece_length);
+                }
+                downloaded.lock().await.retain(|piece| piece.index != index);
+
+                pieces.push(index);
+            }
+        }
+
+        torrent_context.tx.send(ClientMessage::FileChecked { path: file.path, valid, bad_md5sum, pieces }).await?;
+
+        Ok(())
+    }
+
+    /// Hashes every piece on disk and resets the download bookkeeping to the verified pieces.
+    async fn recheck(&mut self) -> Result<Vec<u32>> {
+        let torrent_info = &self.torrent_context.torrent_info;
+
+        let mut verified_pieces = Vec::new();
+        let mut verified_bytes = 0;
+        for index in 0..torrent_info.pieces_count as u32 {
+            match DiskManager::verify_piece(&self.torrent_context, index).await {
+                Ok(true) => {
+                    verified_pieces.push(index);
+                    verified_bytes += torrent_info.get_specific_piece_length(index) as u64;
+                },
+                Ok(false) => tracing::debug!("Piece {} failed the hash check during recheck", index),
+                // missing or truncated files
+                Err(e) => tracing::trace!("Couldn't read piece {} during recheck: {:?}", index, e),
+            }
+        }
+
+        *self.downloaded.lock().await = verified_pieces
+            .iter()
+            .map(|index| Piece {
+                index: *index,
+                block_count: torrent_info.get_specific_piece_block_count(*index),
+            })
+            .collect();
+        *self.downloaded_pieces_count.lock().await = verified_pieces.len();
+        *self.torrent_context.downloaded.lock().await = verified_bytes;
+
+        let mut verified_guard = self.verified_pieces.lock().await;
+        verified_guard.iter_mut().for_each(|verified| *verified = false);
+        for index in &verified_pieces {
+            verified_guard[*index as usize] = true;
+        }
+
+        Ok(verified_pieces)
+    }
+
     async fn run(mut self) -> Result<()> {
         let mut writer_handles = Vec::new();
         let mut reader_handles = Vec::new();
@@ -235,10 +462,13 @@ impl DiskManager {
                             let torrent_context = Arc::clone(&self.torrent_context);
                             let downloaded = Arc::clone(&self.downloaded);
                             let downloaded_pieces_count = Arc::clone(&self.downloaded_pieces_count);
+                            let verified_pieces = Arc::clone(&self.verified_pieces);
+                            let md5_mismatches = Arc::clone(&self.md5_mismatches);
 
                             let handle = tokio::spawn(async move {
                                 let index = block.index;
                                 let length = block.length;
+                                let number = block.number;
 
                                 tracing::debug!("writing block index '{}', piece index '{}', begin '{}', size '{}'", block.number, block.index, block.begin, block.length);
                                 if let Err(e) = DiskManager::write_to_file(&torrent_context, block).await {
@@ -249,37 +479,78 @@ impl DiskManager {
                                 *torrent_context.downloaded.lock().await += length as u64;
                                 tracing::trace!("finished writing block");
 
-                                {
+                                if let Err(e) = torrent_context.tx.send(ClientMessage::BlockWritten { number }).await {
+                                    tracing::error!("Disk writer error: {:?}", e);
+                                }
+
+                                let piece_completed = {
                                     let mut downloaded_guard = downloaded.lock().await;
-                                    if let Some(piece) = downloaded_guard.iter_mut().find(|piece| piece.index == index) {
-                                        piece.block_count += 1;
+                                    let piece = match downloaded_guard.iter_mut().find(|piece| piece.index == index) {
+                                        Some(piece) => {
+                                            piece.block_count += 1;
+                                            piece
+                                        },
+                                        None => {
+                                            downloaded_guard.push(Piece {
+                                                index,
+                                                block_count: 1,
+                                            });
+                                            downloaded_guard.last_mut().unwrap() // just pushed
+                                        }
+                                    };
 
-                                        if piece.block_count == torrent_context.torrent_info.get_specific_piece_block_count(piece.index) {
-                                            if let Err(e) = torrent_context.tx.send(ClientMessage::Have { piece: piece.index }).await {
+                                    piece.block_count == torrent_context.torrent_info.get_specific_piece_block_count(piece.index)
+                                };
+
+                                if !piece_completed {
+                                    return;
+                                }
+
+                                match DiskManager::verify_piece(&torrent_context, index).await {
+                                    Ok(true) => {
+                                        // pieces inside a file with an md5sum are announced by its check
+                                        if !DiskManager::awaits_md5_check(&torrent_context, index) {
+                                            if let Err(e) = torrent_context.tx.send(ClientMessage::Have { piece: index }).await {
                                                 tracing::error!("Disk writer error: {:?}", e);
                                                 return;
                                             }
-                                            *downloaded_pieces_count.lock().await += 1;
                                         }
-                                    }
-                                    else {
-                                        let piece = Piece {
-                                            index,
-                                            block_count: 1,
+                                        *downloaded_pieces_count.lock().await += 1;
+
+                                        let completed_files = {
+                                            let mut verified_guard = verified_pieces.lock().await;
+                                            verified_guard[index as usize] = true;
+
+                                            DiskManager::completed_files(&torrent_context, &verified_guard, index)
                                         };
 
-                                        if piece.block_count == torrent_context.torrent_info.get_specific_piece_block_count(piece.index) {
-                                            if let Err(e) = torrent_context.tx.send(ClientMessage::Have { piece: piece.index }).await {
+                                        for file in completed_files {
+                                            if let Err(e) = DiskManager::check_file(&torrent_context, &downloaded, &downloaded_pieces_count, &verified_pieces, &md5_mismatches, file).await {
                                                 tracing::error!("Disk writer error: {:?}", e);
-                                                return;
                                             }
-                                            *downloaded_pieces_count.lock().await += 1;
                                         }
+                                    },
+                                    Ok(false) => {
+                                        tracing::warn!("Piece {} failed the hash check, discarding it", index);
+
+                                        downloaded.lock().await.retain(|piece| piece.index != index);
+
+                                        let piece_length = torrent_context.torrent_info.get_specific_piece_length(index) as u64;
+                                        let mut downloaded_guard = torrent_context.downloaded.lock().await;
+                                        *downloaded_guard = downloaded_guard.saturating_sub(piece_length);
+                                        drop(downloaded_guard);
 
-                                        downloaded_guard.push(piece);
+                                        if let Err(e) = torrent_context.tx.send(ClientMessage::HashFailed { piece: index }).await {
+                                            tracing::error!("Disk writer error: {:?}", e);
+                                        }
+                                        return;
+                                    },
+                                    Err(e) => {
+                                        tracing::error!("Disk writer error: {:?}", e);
+                                        return;
                                     }
                                 }
-                                
+
                                 if *downloaded_pieces_count.lock().await == torrent_context.torrent_info.pieces_count {
                                     if let Err(e) = torrent_context.tx.send(ClientMessage::FinishedDownloading).await {
                                         tracing::error!("Disk writer error: {:?}", e);
@@ -290,6 +561,19 @@ impl DiskManager {
 
                             writer_handles.push(handle);
                         },
+                        ClientMessage::Recheck{ .. } => {
+                            // wait for the pending writes so they are included in the recheck
+                            for handle in writer_handles.drain(..) {
+                                if let Err(e) = handle.await {
+                                    tracing::error!("Disk writer task failed before the recheck: {:?}", e);
+                                }
+                            }
+
+                            let pieces = self.recheck().await?;
+                            if let Err(e) = self.torrent_context.tx.send(ClientMessage::Rechecked{ pieces }).await {
+                                tracing::error!("Disk manager error: {:?}", e);
+                            }
+                        },
                         ClientMessage::Request{ block, tx } => {
                             let torrent_context = Arc::clone(&self.torrent_context);
 
@@ -332,3 +616,28 @@ impl DiskManager {
     }
 }
 
+#[cfg(test)]
+mod disk_manager_tests {
+    use super::*;
+
+    fn file(start: u64, size: u64) -> DownloadableFile {
+        DownloadableFile {
+            start,
+            size,
+            path: String::new(),
+            md5sum: None,
+        }
+    }
+
+    #[test]
+    fn test_inner_pieces_exclude_shared_pieces() {
+        // pieces of 10 bytes, the last one is 5 bytes long
+        assert_eq!(file(0, 15).inner_piece_range(10, 45), 0..1);
+        assert_eq!(file(15, 20).inner_piece_range(10, 45), 2..3);
+        assert_eq!(file(35, 10).inner_piece_range(10, 45), 4..5);
+
+        // a file inside a single piece has none of its own
+        assert!(file(12, 5).inner_piece_range(10, 45).is_empty());
+        assert!(file(10, 10).inner_piece_range(10, 45).contains(&1));
+    }
+}
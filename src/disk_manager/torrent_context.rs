@@ -2,6 +2,7 @@ use tokio::sync::{mpsc, Mutex};
 use anyhow::{anyhow, Result};
 
 use std::collections::BTreeMap;
+use std::fmt::Display;
 use std::sync::Arc;
 
 use crate::utils::bencode::BencodedValue;
@@ -10,6 +11,18 @@ use crate::messager::ClientMessage;
 
 use super::DownloadableFile;
 
+/// Returned when a torrent file contains a path that could escape the destination directory.
+#[derive(Debug)]
+pub struct UnsafeTorrentPath(pub String);
+
+impl Display for UnsafeTorrentPath {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "unsafe path in torrent file: {}", self.0)
+    }
+}
+
+impl std::error::Error for UnsafeTorrentPath {}
+
 #[derive(Debug, Clone)]
 pub struct DiskTorrentContext {
     pub tx: mpsc::Sender<ClientMessage>,
@@ -85,12 +98,7 @@ fn get_files_to_download(torrent_file: &TorrentFile) -> Result<Vec<DownloadableF
                         .iter()
                         .map(|path| {
                             match path {
-                                BencodedValue::ByteString(path) => {
-                                    match String::from_utf8(path.to_vec()) {
-                                        Ok(path) => Ok(path),
-                                        Err(_) => Err(anyhow!("invalid utf8 in torrent file path"))
-                                    }
-                                },
+                                BencodedValue::ByteString(path) => sanitize_path_component(path),
                                 _ => Err(anyhow!("invalid torrent file path"))
                             }
                         })
@@ -99,9 +107,13 @@ fn get_files_to_download(torrent_file: &TorrentFile) -> Result<Vec<DownloadableF
                 _ => return Err(anyhow!("invalid torrent file path"))
             };
 
+            if path.is_empty() {
+                return Err(UnsafeTorrentPath("empty file path".to_string()).into());
+            }
+
             let path = path.join("/");
 
-            let _md5sum = match file.get(&b"md5sum".to_vec()) {
+            let md5sum = match file.get(&b"md5sum".to_vec()) {
                 Some(BencodedValue::ByteString(md5sum)) => Some(md5sum.clone()),
                 _ => None
             };
@@ -110,7 +122,7 @@ fn get_files_to_download(torrent_file: &TorrentFile) -> Result<Vec<DownloadableF
                 start: 0,
                 size,
                 path,
-                _md5sum
+                md5sum
             })
         })
         .collect::<Result<Vec<DownloadableFile>>>()?;
@@ -121,4 +133,63 @@ fn get_files_to_download(torrent_file: &TorrentFile) -> Result<Vec<DownloadableF
     }
     
     Ok(files_to_download)
-}
\ No newline at end of file
+}
+
+/// Validates a single component of a torrent file path and replaces the characters
+/// that aren't allowed in file names on common filesystems.
+pub(crate) fn sanitize_path_component(component: &[u8]) -> Result<String> {
+    let component = match String::from_utf8(component.to_vec()) {
+        Ok(component) => component,
+        Err(_) => return Err(anyhow!("invalid utf8 in torrent file path"))
+    };
+
+    if component.contains('\0') {
+        return Err(UnsafeTorrentPath(format!("NUL byte in '{}'", component.escape_default())).into());
+    }
+
+    if component.is_empty() || component == "." || component == ".." {
+        return Err(UnsafeTorrentPath(format!("invalid path component '{}'", component)).into());
+    }
+
+    // separators would turn a single component into several, possibly absolute, ones
+    if component.contains('/') || component.contains('\\') {
+        return Err(UnsafeTorrentPath(format!("path separator in '{}'", component)).into());
+    }
+
+    let component = component
+        .chars()
+        .map(|c| match c {
+            '<' | '>' | ':' | '"' | '|' | '?' | '*' => '_',
+            c if c.is_control() => '_',
+            c => c,
+        })
+        .collect::<String>();
+
+    // windows silently drops trailing dots and spaces
+    let trimmed = component.trim_end_matches(['.', ' ']);
+    if trimmed.is_empty() {
+        return Err(UnsafeTorrentPath(format!("invalid path component '{}'", component)).into());
+    }
+
+    Ok(trimmed.to_string())
+}
+
+#[cfg(test)]
+mod path_sanitizing_tests {
+    use super::*;
+
+    #[test]
+    fn test_sanitize_path_component() {
+        assert_eq!(sanitize_path_component(b"file.txt").unwrap(), "file.txt");
+        assert_eq!(sanitize_path_component(b"a:b?c*.txt").unwrap(), "a_b_c_.txt");
+        assert_eq!(sanitize_path_component(b"name. ").unwrap(), "name");
+    }
+
+    #[test]
+    fn test_sanitize_path_component_rejects_traversal() {
+        for component in [&b".."[..], b".", b"", b"/etc", b"a/../b", b"..\\b", b"a\0b", b"..."] {
+            let error = sanitize_path_component(component).unwrap_err();
+            assert!(error.downcast_ref::<UnsafeTorrentPath>().is_some());
+        }
+    }
+}
@@ -4,6 +4,7 @@ pub mod tracker;
 pub mod peer;
 pub mod messager;
 pub mod disk_manager;
+pub mod dht;
 pub mod utils;
 pub mod client_options;
 
@@ -6,6 +6,7 @@ use torrent_client::client_options;
 use torrent_client::messager::{TerminalClientMessage, ClientMessage};
 use torrent_client::utils::{ExitCode, valid_src_and_dst};
 use torrent_client::utils::terminal::TerminalClient;
+use torrent_client::utils::sha1hash::Sha1Hash;
 
 use std::path::Path;
 
@@ -71,21 +72,70 @@ async fn main() -> Result<()> {
                         tracing::info!("Received shutdown message in main loop, shutting down");
                         break;
                     },
-                    TerminalClientMessage::AddTorrent{src, dst} => {
+                    TerminalClientMessage::AddTorrent{src, dst, picking_mode, file_priorities} => {
                         if !valid_src_and_dst(&src, &dst) {
                             if let Err(e) = terminal_client.send_message(&TerminalClientMessage::Status { exit_code: ExitCode::InvalidSrcOrDst }).await {
                                 tracing::error!("Failed to send status message to Terminal Client {}: {}", terminal_client.pid, e);
                             }
                             tracing::error!("Invalid torrent file source path or destination received: {} {}", src, dst);
+                            continue;
                         }
-                        if let Err(e) = client.client_add_torrent(src, dst).await {
-                            if let Err(e) = terminal_client.send_message(&TerminalClientMessage::Status { exit_code: ExitCode::InvalidSrcOrDst }).await {
-                                tracing::error!("Failed to send status message to Terminal Client {}: {}", terminal_client.pid, e);
+                        let exit_code = match client.client_add_torrent(src, dst, picking_mode, file_priorities).await {
+                            Ok(exit_code) => exit_code,
+                            Err(e) => {
+                                if let Err(e) = terminal_client.send_message(&TerminalClientMessage::Status { exit_code: ExitCode::InvalidSrcOrDst }).await {
+                                    tracing::error!("Failed to send status message to Terminal Client {}: {}", terminal_client.pid, e);
+                                }
+                                return Err(anyhow!("Failed to send an add message to client: {}", e));
+                            }
+                        };
+
+                        if let Err(e) = terminal_client.send_message(&TerminalClientMessage::Status { exit_code }).await {
+                            tracing::error!("Failed to send status message to Terminal Client {}: {}", terminal_client.pid, e);
+                        }
+                    },
+                    TerminalClientMessage::Recheck{info_hash} => {
+                        let exit_code = match Sha1Hash::from_hex(&info_hash) {
+                            Ok(info_hash) => client.client_recheck(info_hash).await?,
+                            Err(e) => {
+                                tracing::error!("Invalid info hash received for recheck: {} {}", info_hash, e);
+                                ExitCode::InvalidInfoHash
+                            }
+                        };
+
+                        if let Err(e) = terminal_client.send_message(&TerminalClientMessage::Status { exit_code }).await {
+                            tracing::error!("Failed to send status message to Terminal Client {}: {}", terminal_client.pid, e);
+                        }
+                    },
+                    TerminalClientMessage::SetPickingMode{info_hash, picking_mode} => {
+                        let exit_code = match Sha1Hash::from_hex(&info_hash) {
+                            Ok(info_hash) => {
+                                client.client_set_picking_mode(info_hash, picking_mode).await?;
+                                ExitCode::SUCCESS
+                            },
+                            Err(e) => {
+                                tracing::error!("Invalid info hash received for picking mode: {} {}", info_hash, e);
+                                ExitCode::InvalidInfoHash
                             }
-                            return Err(anyhow!("Failed to send an add message to client: {}", e));
+                        };
+
+                        if let Err(e) = terminal_client.send_message(&TerminalClientMessage::Status { exit_code }).await {
+                            tracing::error!("Failed to send status message to Terminal Client {}: {}", terminal_client.pid, e);
                         }
+                    },
+                    TerminalClientMessage::SetFilePriorities{info_hash, file_priorities} => {
+                        let exit_code = match Sha1Hash::from_hex(&info_hash) {
+                            Ok(info_hash) => {
+                                client.client_set_file_priorities(info_hash, file_priorities).await?;
+                                ExitCode::SUCCESS
+                            },
+                            Err(e) => {
+                                tracing::error!("Invalid info hash received for file priorities: {} {}", info_hash, e);
+                                ExitCode::InvalidInfoHash
+                            }
+                        };
 
-                        if let Err(e) = terminal_client.send_message(&TerminalClientMessage::Status { exit_code: ExitCode::SUCCESS }).await {
+                        if let Err(e) = terminal_client.send_message(&TerminalClientMessage::Status { exit_code }).await {
                             tracing::error!("Failed to send status message to Terminal Client {}: {}", terminal_client.pid, e);
                         }
                     },
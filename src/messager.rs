@@ -1,15 +1,20 @@
 use serde::{Serialize, Deserialize};
 use tokio::sync::{mpsc, oneshot};
 
-use crate::peer::{Block, PeerAddress, PeerSession};
+use std::net::SocketAddr;
+
+use crate::peer::{Block, PeerAddress, PeerSession, PexPeer, PickingMode};
+use crate::torrent::FilePriority;
 use crate::torrent::torrent_state::TorrentState;
+use crate::tracker::TrackerSet;
 use crate::utils::ExitCode;
+use crate::utils::sha1hash::Sha1Hash;
 
 
 #[derive(Debug)]
 pub enum ClientMessage {
     Shutdown,
-    AddTorrent{src: String, dst: String},
+    AddTorrent{src: String, dst: String, picking_mode: PickingMode, file_priorities: Vec<(usize, FilePriority)>, tx: oneshot::Sender<ExitCode>},
     DownloadedBlock{block: Block},
     FinishedDownloading,
     SendTorrentInfo{tx: oneshot::Sender<TorrentState>},
@@ -21,15 +26,35 @@ pub enum ClientMessage {
     Request{block: Block, tx: mpsc::Sender<ClientMessage>},
     RequestedBlock{block: Block},
     Cancel{block: Block},
+    BlockWritten{number: usize},
     Have{piece: u32},
+    HashFailed{piece: u32},
+    FileChecked{path: String, valid: bool, bad_md5sum: bool, pieces: Vec<u32>},
+    TrackersUpdated{trackers: TrackerSet, peer_addresses: Vec<PeerAddress>},
+    Recheck{info_hash: Sha1Hash},
+    /// A recheck asked for by a terminal client, which hears back whether the torrent exists.
+    RecheckTorrent{info_hash: Sha1Hash, tx: oneshot::Sender<ExitCode>},
+    SetPickingMode{info_hash: Sha1Hash, picking_mode: PickingMode},
+    SetFilePriorities{info_hash: Sha1Hash, file_priorities: Vec<(usize, FilePriority)>},
+    Rechecked{pieces: Vec<u32>},
+    PexPeers{peers: Vec<PexPeer>},
+    AdvertisePeers{peers: Vec<PeerAddress>},
+    Choke,
+    Unchoke,
+    FindDhtPeers{info_hash: Sha1Hash, port: Option<u16>, tx: mpsc::Sender<ClientMessage>},
+    DhtPeers{peers: Vec<PeerAddress>},
+    DhtNode{address: SocketAddr},
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 pub enum TerminalClientMessage {
     Status{exit_code: ExitCode},
     Shutdown,
-    AddTorrent{src: String, dst: String},
+    AddTorrent{src: String, dst: String, picking_mode: PickingMode, file_priorities: Vec<(usize, FilePriority)>},
     ListTorrents,
     TorrentsInfo{torrents: Vec<TorrentState>},
     TerminalClientClosed,
+    Recheck{info_hash: String},
+    SetPickingMode{info_hash: String, picking_mode: PickingMode},
+    SetFilePriorities{info_hash: String, file_priorities: Vec<(usize, FilePriority)>},
 }
\ No newline at end of file
@@ -1,12 +1,13 @@
-use tokio::sync::mpsc;
+use tokio::sync::{mpsc, Mutex};
 use tokio::task::JoinHandle;
 use anyhow::{anyhow, Result};
 
 use crate::messager::ClientMessage;
-use crate::peer::block_picker::Piece;
 use crate::utils::CommunicationPipe;
 use crate::utils::sha1hash::Sha1Hash;
 
+use std::sync::Arc;
+
 pub mod peer_address;
 pub use peer_address::PeerAddress;  
 
@@ -14,10 +15,23 @@ pub mod peer_message;
 pub use peer_message::{PeerMessage, PeerSession, ConnectionType, Handshake};
 
 pub mod block_picker;
-pub use block_picker::{BlockPicker, BlockPickerState, Block};
+pub use block_picker::{BlockPicker, BlockPickerState, Block, PickingMode};
 
 pub mod context;
-pub use context::PeerTorrentContext;
+pub use context::{PeerStats, PeerTorrentContext};
+
+pub mod extension;
+pub use extension::{Extension, ExtensionHandshake, ExtensionRegistry};
+
+pub mod ut_metadata;
+
+pub mod fast_extension;
+
+pub mod ut_pex;
+pub use ut_pex::{PexPeer, UtPex};
+
+pub mod request_queue;
+use request_queue::RequestQueue;
 use context::PeerContext;
 
 
@@ -26,6 +40,7 @@ pub struct PeerHandle {
     join_handle: JoinHandle<Result<()>>,
 
     pub peer_address: PeerAddress,
+    pub stats: Arc<Mutex<PeerStats>>,
 }
 
 impl PeerHandle {
@@ -37,7 +52,9 @@ impl PeerHandle {
             rx: receiver
         };
 
-        let peer = Peer::new(client_id, torrent_context, peer_address.clone(), self_pipe, disk_tx).await;
+        let stats = Arc::new(Mutex::new(PeerStats::default()));
+
+        let peer = Peer::new(client_id, torrent_context, peer_address.clone(), self_pipe, disk_tx, Arc::clone(&stats)).await;
         let join_handle: JoinHandle<Result<()>> = tokio::spawn(async move {
             peer.run(connection_type, None).await
         });
@@ -46,14 +63,12 @@ impl PeerHandle {
             tx: sender,
             join_handle,
             peer_address,
+            stats,
         })
     }
 
     pub async fn from_session(client_id: [u8; 20], torrent_context: PeerTorrentContext, session: PeerSession, disk_tx: mpsc::Sender<ClientMessage>) -> Result<PeerHandle> {
-        let peer_address = PeerAddress {
-            address: session.stream.peer_addr()?.ip().to_string(),
-            port: session.stream.peer_addr()?.port().to_string(),
-        };
+        let peer_address = PeerAddress::from(session.stream.peer_addr()?);
 
         let connection_type = session.connection_type.clone();
 
@@ -64,7 +79,9 @@ impl PeerHandle {
             rx: receiver
         };
 
-        let peer = Peer::new(client_id, torrent_context, peer_address.clone(), self_pipe, disk_tx).await;
+        let stats = Arc::new(Mutex::new(PeerStats::default()));
+
+        let peer = Peer::new(client_id, torrent_context, peer_address.clone(), self_pipe, disk_tx, Arc::clone(&stats)).await;
         let join_handle: JoinHandle<Result<()>> = tokio::spawn(async move {
             peer.run(connection_type, Some(session)).await
         });
@@ -73,6 +90,7 @@ impl PeerHandle {
             tx: sender,
             join_handle,
             peer_address,
+            stats,
         })
     }
 
@@ -90,6 +108,21 @@ impl PeerHandle {
         Ok(())
     }
 
+    pub async fn advertise_peers(&mut self, peers: Vec<PeerAddress>) -> Result<()> {
+        self.tx.send(ClientMessage::AdvertisePeers{peers}).await?;
+        Ok(())
+    }
+
+    pub async fn choke(&mut self) -> Result<()> {
+        self.tx.send(ClientMessage::Choke).await?;
+        Ok(())
+    }
+
+    pub async fn unchoke(&mut self) -> Result<()> {
+        self.tx.send(ClientMessage::Unchoke).await?;
+        Ok(())
+    }
+
     pub async fn shutdown(&mut self) -> Result<()> {
         self.tx.send(ClientMessage::Shutdown).await?;
         Ok(())
@@ -103,6 +136,8 @@ struct Peer {
     peer_context: PeerContext,
     torrent_context: PeerTorrentContext,
     disk_tx: mpsc::Sender<ClientMessage>,
+    extensions: ExtensionRegistry,
+    stats: Arc<Mutex<PeerStats>>,
 
     client_id: [u8; 20],
 }
@@ -114,7 +149,7 @@ impl std::fmt::Display for Peer {
 }
 
 impl Peer {
-    pub async fn new(client_id: [u8; 20], torrent_context: PeerTorrentContext, addr: PeerAddress, self_pipe: CommunicationPipe, disk_tx: mpsc::Sender<ClientMessage>) -> Self {
+    pub async fn new(client_id: [u8; 20], torrent_context: PeerTorrentContext, addr: PeerAddress, self_pipe: CommunicationPipe, disk_tx: mpsc::Sender<ClientMessage>, stats: Arc<Mutex<PeerStats>>) -> Self {
         let peer_context = PeerContext {
             id: [0; 20],
             ip: addr,
@@ -123,14 +158,25 @@ impl Peer {
             interested: false,
             choking: true,
             bitfield: Vec::new(),
+            fast_extension: false,
+            allowed_fast: Vec::new(),
+            sent_allowed_fast: Vec::new(),
         };
 
+        let mut extensions = ExtensionRegistry::new();
+        // private torrents must only get their peers from the tracker
+        if !torrent_context.torrent_info.private {
+            extensions.register(Box::new(UtPex::new()));
+        }
+
         Self {
             self_tx: self_pipe.tx,
             rx: self_pipe.rx,
             peer_context,
             torrent_context,
             disk_tx,
+            extensions,
+            stats,
 
             client_id,
         }
@@ -144,10 +190,44 @@ impl Peer {
     }
 
     async fn handshake(&mut self, peer_session: &mut PeerSession) -> Result<()> {
-        let bitfield = self.torrent_context.bitfield.lock().await.clone();
-        peer_session.handshake(self.torrent_context.info_hash.clone(), self.client_id, bitfield).await?;
+        peer_session.handshake(self.torrent_context.info_hash.clone(), self.client_id).await?;
        
         self.peer_context.id = peer_session.peer_handshake.peer_id;
+        self.peer_context.fast_extension = peer_session.peer_handshake.supports_fast_extension();
+
+        let bitfield = self.torrent_context.bitfield.lock().await.clone();
+        let pieces_count = self.torrent_context.torrent_info.pieces_count;
+        if self.peer_context.fast_extension {
+            if fast_extension::has_all_pieces(&bitfield, pieces_count) {
+                peer_session.send(PeerMessage::HaveAll).await?;
+            }
+            else if crate::utils::is_zero_aligned(&bitfield) {
+                peer_session.send(PeerMessage::HaveNone).await?;
+            }
+            else {
+                peer_session.bitfield(bitfield).await?;
+            }
+
+            // BEP 6 only defines the allowed fast set for ipv4 peers
+            if let std::net::IpAddr::V4(ip) = self.peer_context.ip.ip() {
+                let allowed_fast = fast_extension::allowed_fast_set(ip, &self.torrent_context.info_hash, pieces_count, fast_extension::ALLOWED_FAST_SET_SIZE);
+                for piece in &allowed_fast {
+                    peer_session.send(PeerMessage::AllowedFast(*piece)).await?;
+                }
+                self.peer_context.sent_allowed_fast = allowed_fast;
+            }
+        }
+        else if !crate::utils::is_zero_aligned(&bitfield) {
+            peer_session.bitfield(bitfield).await?;
+        }
+
+        if unsafe { crate::CLIENT_OPTIONS.dht_enabled } && peer_session.peer_handshake.supports_dht() && !self.torrent_context.torrent_info.private {
+            peer_session.send(PeerMessage::Port(unsafe { crate::CLIENT_OPTIONS.dht_port })).await?;
+        }
+
+        if peer_session.peer_handshake.supports_extension_protocol() {
+            peer_session.send(self.extensions.handshake_message(Some(self.peer_context.ip.ip()))?).await?;
+        }
 
         Ok(())
     }
@@ -176,48 +256,101 @@ impl Peer {
         Ok(())
     }
 
-    async fn request(&mut self, peer_session: &mut PeerSession, downloading_blocks: &mut Vec<Block>, end_game_blocks: &mut Vec<Block>) -> Result<()> {
-        tracing::trace!("before not end game block");
-        if self.torrent_context.needed.lock().await.block_count() > unsafe { crate::CLIENT_OPTIONS.block_request_count } {
-            tracing::trace!("before while");
-            while downloading_blocks.len() < unsafe { crate::CLIENT_OPTIONS.block_request_count } {
-                tracing::trace!("before pick random");
-                match self.torrent_context.needed.lock().await.pick_random(&self.peer_context.bitfield).await {
-                    Some(block) => {
-                        peer_session.send(PeerMessage::Request(block.index, block.begin, block.length)).await?;
-                        tracing::debug!("Requested block {} from peer: '{self}' with piece index {}, offset {} and size {}", block.number, block.index, block.begin, block.length);
-                        
-                        downloading_blocks.push(block);
-                    },
-                    None => return Ok(())
-                }
-                tracing::trace!("after pick random");
+    /// Replaces the bitfield of the peer and keeps the piece availability of the block picker up to date.
+    async fn set_bitfield(&mut self, bitfield: Vec<u8>) {
+        let mut needed_guard = self.torrent_context.needed.lock().await;
+        needed_guard.remove_bitfield(&self.peer_context.bitfield);
+        needed_guard.add_bitfield(&bitfield);
+
+        self.peer_context.bitfield = bitfield;
+    }
+
+    /// Number of requests to keep outstanding, the depth of the peer's queue capped by the `reqq` it advertised.
+    /// A snubbed peer gets a single request until it sends a block again.
+    fn request_count(&self, downloading_blocks: &RequestQueue) -> usize {
+        if downloading_blocks.is_snubbed() {
+            return 1;
+        }
+
+        match self.extensions.peer_handshake().and_then(|handshake| handshake.reqq) {
+            Some(reqq) => std::cmp::min(downloading_blocks.depth(), reqq as usize),
+            None => downloading_blocks.depth()
+        }
+    }
+
+    /// The pieces we can request from the peer, only its allowed fast pieces while it is choking us.
+    fn requestable_bitfield(&self) -> Vec<u8> {
+        if !self.peer_context.choking {
+            return self.peer_context.bitfield.clone();
+        }
+
+        let mut bitfield = vec![0; self.peer_context.bitfield.len()];
+        for piece in &self.peer_context.allowed_fast {
+            let (byte, bit) = (*piece as usize / 8, 1 << (7 - piece % 8));
+            if byte < bitfield.len() {
+                bitfield[byte] |= self.peer_context.bitfield[byte] & bit;
             }
         }
-        else if downloading_blocks.is_empty() {
-            match self.torrent_context.needed.lock().await.get_end_game_blocks(&self.peer_context.bitfield).await? {
-                Some(blocks) => {
-                    for block in blocks {
-                        peer_session.send(PeerMessage::Request(block.index, block.begin, block.length)).await?;
-                        tracing::debug!("Requested end game block {} from peer: '{self}' with piece index {}, offset {} and size {}", block.number, block.index, block.begin, block.length);
-                        
-                        if !end_game_blocks.iter().any(|b| b.number == block.number) {
-                            end_game_blocks.push(block.clone());
-                        }
 
-                        downloading_blocks.push(block);
-                    }
-                },
+        bitfield
+    }
+
+    /// Keeps `request_count` requests outstanding, in end game the picker hands out blocks other peers are sending too.
+    async fn request(&mut self, peer_session: &mut PeerSession, downloading_blocks: &mut RequestQueue) -> Result<()> {
+        let request_count = self.request_count(downloading_blocks);
+        let bitfield = self.requestable_bitfield();
+        while downloading_blocks.len() < request_count {
+            let block = match self.torrent_context.needed.lock().await.pick_block(&self.peer_context.ip, &bitfield) {
+                Some(block) => block,
                 None => return Ok(())
+            };
+
+            peer_session.send(PeerMessage::Request(block.index, block.begin, block.length)).await?;
+            tracing::debug!("Requested block {} from peer: '{self}' with piece index {}, offset {} and size {}", block.number, block.index, block.begin, block.length);
+
+            downloading_blocks.push(block, std::time::Instant::now());
+        }
+
+        Ok(())
+    }
+
+    /// Hands the requests the peer didn't answer in time back to the block picker, marks the peer snubbed
+    /// if it stopped sending blocks and resizes its queue to its rate.
+    async fn check_requests(&mut self, peer_session: &mut PeerSession, downloading_blocks: &mut RequestQueue) -> Result<()> {
+        let now = std::time::Instant::now();
+
+        let timed_out = downloading_blocks.take_timed_out(now);
+        if !timed_out.is_empty() {
+            tracing::debug!("{} requests to peer '{self}' timed out", timed_out.len());
+
+            let mut needed_guard = self.torrent_context.needed.lock().await;
+            for block in &timed_out {
+                needed_guard.release_block(block.number, &self.peer_context.ip);
+            }
+            drop(needed_guard);
+
+            for block in timed_out {
+                peer_session.send(PeerMessage::Cancel(block.index, block.begin, block.length)).await?;
             }
         }
 
+        if downloading_blocks.check_snubbed(now) {
+            tracing::info!("Peer '{self}' snubbed us");
+        }
+        downloading_blocks.update_rate(now);
+
+        let request_count = self.request_count(downloading_blocks);
+        let mut stats_guard = self.stats.lock().await;
+        stats_guard.snubbed = downloading_blocks.is_snubbed();
+        stats_guard.queue_depth = request_count;
+        stats_guard.download_rate = downloading_blocks.rate();
+
         Ok(())
     }
 
     async fn get_peer_session(&self, connection_type: ConnectionType) -> Result<PeerSession> {
         let stream = tokio::select! {
-            stream = tokio::net::TcpStream::connect(format!("{}:{}", self.peer_context.ip.address, self.peer_context.ip.port)) => stream,
+            stream = tokio::net::TcpStream::connect(self.peer_context.ip.0) => stream,
             _ = tokio::time::sleep(std::time::Duration::from_secs(10)) => return Err(anyhow!("Failed to connect to peer '{self}'"))
         }?;
 
@@ -235,6 +368,21 @@ impl Peer {
         )
     )]
     pub async fn run(mut self, connection_type: ConnectionType, peer_session: Option<PeerSession>) -> Result<()> {
+        let result = self.connect_and_handle_session(connection_type, peer_session).await;
+
+        // the pieces of the peer can't be downloaded from it anymore and its requests go to other peers
+        let mut needed_guard = self.torrent_context.needed.lock().await;
+        needed_guard.remove_bitfield(&self.peer_context.bitfield);
+        needed_guard.release_peer(&self.peer_context.ip);
+        drop(needed_guard);
+
+        // however the peer ended the torrent frees its slot, this only fails when the torrent already shut down
+        let _ = self.torrent_context.tx.send(ClientMessage::PeerDisconnected{peer_address: self.peer_context.ip.clone()}).await;
+
+        result
+    }
+
+    async fn connect_and_handle_session(&mut self, connection_type: ConnectionType, peer_session: Option<PeerSession>) -> Result<()> {
         let mut peer_session = match peer_session {
             Some(peer_session) => peer_session,
             None => self.get_peer_session(connection_type).await?,
@@ -243,9 +391,18 @@ impl Peer {
 
         self.handshake(&mut peer_session).await?;
 
-        let mut end_game_blocks: Vec<Block> = Vec::new();
-        let mut downloading_blocks: Vec<Block> = Vec::new();
+        self.handle_session(peer_session).await
+    }
+
+    async fn handle_session(&mut self, mut peer_session: PeerSession) -> Result<()> {
+        let initial_depth = unsafe { crate::CLIENT_OPTIONS.block_request_count };
+        let mut downloading_blocks = RequestQueue::new(std::time::Instant::now(), initial_depth, self.torrent_context.torrent_info.block_length);
         let mut seeding_blocks: Vec<Block> = Vec::new();
+
+        let keep_alive_interval = std::time::Duration::from_secs(unsafe { crate::CLIENT_OPTIONS.client_keep_alive_message_interval_secs });
+        let keep_alive = tokio::time::sleep(keep_alive_interval);
+        tokio::pin!(keep_alive);
+        let mut request_check = tokio::time::interval(std::time::Duration::from_secs(request_queue::REQUEST_CHECK_INTERVAL_SECS));
         loop {
             tracing::trace!("Peer '{self}' waiting for message");
             tokio::select! {
@@ -253,19 +410,6 @@ impl Peer {
                 Some(msg) = self.rx.recv() => {
                     match msg {
                         ClientMessage::Shutdown => {
-                            for block in downloading_blocks {
-                                let mut needed_guard = self.torrent_context.needed.lock().await;
-
-                                if let Some(piece) = needed_guard.pieces.iter_mut().find(|piece| piece.index == block.index) {
-                                    piece.block_count = self.torrent_context.torrent_info.blocks_in_piece;
-                                }
-                                else {
-                                    needed_guard.pieces.push(Piece {
-                                        index: block.index,
-                                        block_count: self.torrent_context.torrent_info.blocks_in_piece,
-                                    });
-                                }
-                            }
                             break;
                         },
                         ClientMessage::RequestedBlock{block} => {
@@ -289,6 +433,7 @@ impl Peer {
 
                                 peer_session.send(PeerMessage::Piece(block.index, block.begin, data)).await?;
                                 *self.torrent_context.uploaded.lock().await += length;
+                                self.stats.lock().await.uploaded += length;
                             }
                             else {
                                 tracing::debug!("Block {} was canceled and not sending to peer {}", block.number, self.peer_context.ip);
@@ -297,41 +442,115 @@ impl Peer {
                         },
                         ClientMessage::Have{piece} => {
                             tracing::trace!("Peer '{self}' received have message for piece {}", piece);
-                            if !self.peer_context.bitfield.is_empty() &&  0 == self.peer_context.bitfield[piece as usize / 8] & 1 << (7 - piece % 8) {
+                            let peer_has_piece = self.peer_context.bitfield.get(piece as usize / 8).is_some_and(|byte| byte & 1 << (7 - piece % 8) != 0);
+                            if !self.peer_context.bitfield.is_empty() && !peer_has_piece {
                                 peer_session.send(PeerMessage::Have(piece)).await?;
                             }
                         },
                         ClientMessage::Cancel{ block } => {
-                            if let Some(block_index) = downloading_blocks.iter().position(|b| b.index == block.index && b.begin == block.begin && b.length == block.length) {
-                                downloading_blocks.remove(block_index);
+                            // the block arrived from another peer, it isn't needed from this one anymore
+                            let cancelled = downloading_blocks.remove(block.index, block.begin, block.length);
+                            if cancelled.is_some() {
                                 peer_session.send(PeerMessage::Cancel(block.index, block.begin, block.length)).await?;
                             }
                         },
+                        ClientMessage::AdvertisePeers{..} => {
+                            for peer_message in self.extensions.on_client_message(&msg)? {
+                                peer_session.send(peer_message).await?;
+                            }
+                        },
+                        ClientMessage::Choke => {
+                            if self.peer_context.am_choking {
+                                continue;
+                            }
+                            self.choke(&mut peer_session).await?;
+
+                            // choking drops the pending requests except for allowed fast pieces,
+                            // with the fast extension the dropped ones have to be rejected
+                            let (allowed_fast_blocks, dropped_blocks): (Vec<Block>, Vec<Block>) = seeding_blocks
+                                .drain(..)
+                                .partition(|block| self.peer_context.sent_allowed_fast.contains(&block.index));
+                            seeding_blocks = allowed_fast_blocks;
+
+                            if self.peer_context.fast_extension {
+                                for block in dropped_blocks {
+                                    peer_session.send(PeerMessage::Reject(block.index, block.begin, block.length)).await?;
+                                }
+                            }
+                        },
+                        ClientMessage::Unchoke if self.peer_context.am_choking => {
+                            self.unchoke(&mut peer_session).await?;
+                        },
                         _ => {}
                     }
                 }
                 peer_message = peer_session.recv() => {
                     tracing::trace!("Peer '{self}' received message");
+                    keep_alive.as_mut().reset(tokio::time::Instant::now() + keep_alive_interval);
                     match peer_message? {
                         PeerMessage::Choke => {
                             self.peer_context.choking = true;
+
+                            // without the fast extension a choke silently drops our requests
+                            if !self.peer_context.fast_extension {
+                                let mut needed_guard = self.torrent_context.needed.lock().await;
+                                for block in downloading_blocks.drain() {
+                                    needed_guard.release_block(block.number, &self.peer_context.ip);
+                                }
+                            }
                         },
                         PeerMessage::Unchoke => {
                             self.peer_context.choking = false;
                         },
+                        // the torrent's choker decides who gets unchoked
                         PeerMessage::Interested => {
                             self.peer_context.interested = true;
-                            self.unchoke(&mut peer_session).await?;
+                            self.stats.lock().await.interested = true;
                         },
                         PeerMessage::NotInterested => {
                             self.peer_context.interested = false;
-                            self.choke(&mut peer_session).await?;
+                            self.stats.lock().await.interested = false;
                         },
                         PeerMessage::Have(index) => {
-                            self.peer_context.bitfield[index as usize / 8] |= 1 << (7 - index % 8);
+                            if index as usize >= self.torrent_context.torrent_info.pieces_count {
+                                tracing::error!("Peer '{self}' sent have for piece {index} that doesn't exist");
+                                return Err(anyhow!("Peer '{self}' sent have for piece {index} that doesn't exist"));
+                            }
+
+                            if self.peer_context.bitfield.is_empty() {
+                                self.peer_context.bitfield = vec![0; self.torrent_context.torrent_info.pieces_count.div_ceil(8)];
+                            }
+                            if self.peer_context.bitfield[index as usize / 8] & 1 << (7 - index % 8) == 0 {
+                                self.peer_context.bitfield[index as usize / 8] |= 1 << (7 - index % 8);
+                                self.torrent_context.needed.lock().await.add_have(index);
+                            }
+                        },
+                        PeerMessage::HaveAll if self.peer_context.fast_extension => {
+                            self.set_bitfield(fast_extension::full_bitfield(self.torrent_context.torrent_info.pieces_count)).await;
+                        },
+                        PeerMessage::HaveNone if self.peer_context.fast_extension => {
+                            self.set_bitfield(vec![0; self.torrent_context.torrent_info.pieces_count.div_ceil(8)]).await;
+                        },
+                        PeerMessage::Suggest(index) if self.peer_context.fast_extension => {
+                            tracing::debug!("Peer '{self}' suggested piece {index}, ignoring it");
+                        },
+                        PeerMessage::AllowedFast(index) if self.peer_context.fast_extension => {
+                            if (index as usize) < self.torrent_context.torrent_info.pieces_count && !self.peer_context.allowed_fast.contains(&index) {
+                                self.peer_context.allowed_fast.push(index);
+                            }
+                        },
+                        PeerMessage::Reject(index, begin, length) if self.peer_context.fast_extension => {
+                            if let Some(block) = downloading_blocks.remove(index, begin, length) {
+                                tracing::debug!("Peer '{self}' rejected block with piece index {index}, offset {begin} and size {length}");
+                                self.torrent_context.needed.lock().await.release_block(block.number, &self.peer_context.ip);
+                            }
                         },
                         PeerMessage::Bitfield(bitfield) => {
-                            self.peer_context.bitfield = bitfield;
+                            if !peer_message::is_valid_bitfield(&bitfield, self.torrent_context.torrent_info.pieces_count) {
+                                tracing::error!("Peer '{self}' sent a bitfield that doesn't match the torrent");
+                                return Err(anyhow!("Peer '{self}' sent a bitfield that doesn't match the torrent"));
+                            }
+                            self.set_bitfield(bitfield).await;
                         },
                         PeerMessage::Request(index, begin, length) => {
                             if  self.torrent_context.torrent_info.pieces_count as u32 <= index ||
@@ -340,14 +559,32 @@ impl Peer {
                                 return Err(anyhow!("Peer '{self}' sent an invalid request"));
                             }
 
-                            if self.peer_context.am_choking || !self.peer_context.interested {
-                                tracing::error!("Peer '{self}' sent request when I am choking or they are not interested");
-                                return Err(anyhow!("Peer '{self}' sent request when I am choking or they are not interested"));
+                            let have_piece = self.torrent_context.bitfield.lock().await[index as usize / 8] & 1 << (7 - index % 8) != 0;
+                            let allowed_fast = self.peer_context.sent_allowed_fast.contains(&index);
+
+                            if self.peer_context.fast_extension {
+                                if !have_piece || !self.peer_context.interested || (self.peer_context.am_choking && !allowed_fast) {
+                                    tracing::debug!("Rejecting request of peer '{self}' for piece {index}");
+                                    peer_session.send(PeerMessage::Reject(index, begin, length)).await?;
+                                    continue;
+                                }
                             }
+                            else {
+                                // requests sent before our choke arrived are dropped
+                                if self.peer_context.am_choking {
+                                    tracing::debug!("Ignoring request of choked peer '{self}' for piece {index}");
+                                    continue;
+                                }
+
+                                if !self.peer_context.interested {
+                                    tracing::error!("Peer '{self}' sent request when they are not interested");
+                                    return Err(anyhow!("Peer '{self}' sent request when they are not interested"));
+                                }
 
-                            if self.torrent_context.bitfield.lock().await[index as usize / 8] & 1 << (7 - index % 8) == 0 {
-                                tracing::error!("Peer '{self}' sent request for piece that I don't have");
-                                return Err(anyhow!("Peer '{self}' sent request for piece that I don't have"));
+                                if !have_piece {
+                                    tracing::error!("Peer '{self}' sent request for piece that I don't have");
+                                    return Err(anyhow!("Peer '{self}' sent request for piece that I don't have"));
+                                }
                             }
 
                             let block_index = {
@@ -372,57 +609,91 @@ impl Peer {
                             self.disk_tx.send(ClientMessage::Request{block, tx: self.self_tx.clone()}).await?;
 
                         },
-                        PeerMessage::Piece(index, begin, block) => {
+                        PeerMessage::Piece(index, begin, data) => {
                             if  self.torrent_context.torrent_info.pieces_count as u32 <= index ||
-                                begin + block.len() as u32 > self.torrent_context.torrent_info.get_specific_piece_length(index) as u32 {
+                                begin + data.len() as u32 > self.torrent_context.torrent_info.get_specific_piece_length(index) as u32 {
                                 tracing::error!("Peer '{self}' sent an invalid request");
                                 return Err(anyhow!("Peer '{self}' sent an invalid request"));
                             }
                             
-                            if !self.peer_context.am_interested || self.peer_context.choking {
+                            // with the fast extension requests survive a choke, so blocks can still arrive
+                            if !self.peer_context.fast_extension && (!self.peer_context.am_interested || self.peer_context.choking) {
                                 tracing::error!("Peer '{self}' sent piece block when I am not interested or they are choking");
                                 return Err(anyhow!("Peer '{self}' sent piece block when I am not interested or they are choking"));
                             }
 
-                            tracing::trace!("Peer '{self}' received block with index {}, begin {} and length {}", index, begin, block.len());
-
-                            if downloading_blocks.iter().any(|b| b.index == index && b.begin == begin) {
-                                let block_index = {
-                                    index as usize * self.torrent_context.torrent_info.blocks_in_piece + begin.div_ceil(unsafe { crate::CLIENT_OPTIONS.block_size } as u32) as usize
-                                };
-                                let block = Block {
-                                    index,
-                                    begin,
-                                    length: block.len() as u32,
-    
-                                    number: block_index,
-                                    data: Some(block),
-                                };
+                            tracing::trace!("Peer '{self}' received block with index {}, begin {} and length {}", index, begin, data.len());
+
+                            // a block that timed out is still taken as long as no other peer sent it first, the picker drops it otherwise
+                            let now = std::time::Instant::now();
+                            let block = downloading_blocks.receive(index, begin, data.len() as u32, now)
+                                .or_else(|| downloading_blocks.receive_late(index, begin, data.len() as u32, now));
+                            if let Some(mut block) = block {
+                                block.data = Some(data);
 
                                 tracing::trace!("Peer '{self}' retaining block: {} {}", block.index, block.begin);
-                                
-                                if end_game_blocks.iter().any(|b| b.number == block.number) {
-                                    tracing::trace!("Peer '{self}' received an end game block: {}", block.number);
-                                    tracing::trace!("Peer '{self}' sending block to torrent task: {}", block.number);
-                                    self.torrent_context.tx.send(ClientMessage::Cancel{ block }).await?;
+                                let mut stats_guard = self.stats.lock().await;
+                                stats_guard.downloaded += block.length as u64;
+                                stats_guard.snubbed = false;
+                                drop(stats_guard);
+
+                                {
+                                    // remember who sent data for this piece in case it fails the hash check
+                                    let mut contributors_guard = self.torrent_context.piece_contributors.lock().await;
+                                    let contributors = contributors_guard.entry(index).or_default();
+                                    if !contributors.contains(&self.peer_context.ip) {
+                                        contributors.push(self.peer_context.ip.clone());
+                                    }
                                 }
-                                else {
-                                    downloading_blocks.retain(|b| !(b.index == index && b.begin == begin));
-                                tracing::trace!("before Peer '{downloading_blocks:?}' ");
-
-                                    tracing::trace!("Peer '{self}' sending block to disk task: {}", block.number);
-                                tracing::trace!("after Peer '{downloading_blocks:?}' ");
-
-                                    self.disk_tx.send(ClientMessage::DownloadedBlock{ block }).await?;
+                                
+                                let other_requesters = self.torrent_context.needed.lock().await.receive_block(block.number, &self.peer_context.ip);
+                                match other_requesters {
+                                    Some(other_requesters) => {
+                                        // in end game the block was requested from other peers as well
+                                        if !other_requesters.is_empty() {
+                                            let cancel = Block {
+                                                index,
+                                                begin,
+                                                length: block.length,
+
+                                                number: block.number,
+                                                data: None,
+                                            };
+                                            self.torrent_context.tx.send(ClientMessage::Cancel{ block: cancel }).await?;
+                                        }
+
+                                        tracing::trace!("Peer '{self}' sending block to disk task: {}", block.number);
+                                        self.disk_tx.send(ClientMessage::DownloadedBlock{ block }).await?;
+                                    },
+                                    None => tracing::trace!("Peer '{self}' sent block {} that was already received", block.number),
                                 }
                             }
                         },
                         PeerMessage::Cancel(index, begin, length) => {
                             seeding_blocks.retain(|block| !(block.index == index && block.begin == begin && block.length == length));
                         },
+                        PeerMessage::Extended(id, payload) => {
+                            let messages = match self.extensions.handle(id, &payload) {
+                                Ok(messages) => messages,
+                                Err(e) => {
+                                    tracing::warn!("Peer '{self}' sent an invalid extended message: {e}");
+                                    continue;
+                                }
+                            };
+
+                            if id == extension::EXTENSION_HANDSHAKE_ID {
+                                if let Some(handshake) = self.extensions.peer_handshake() {
+                                    tracing::debug!("Peer '{self}' runs {:?} and supports extensions {:?}", handshake.v, handshake.m.keys());
+                                }
+                            }
+
+                            for message in messages {
+                                self.torrent_context.tx.send(message).await?;
+                            }
+                        },
                         PeerMessage::Port(port) => {
-                            tracing::warn!("Peer '{self}' sent port: {port}, ignoring it");
-                            continue;
+                            tracing::debug!("Peer '{self}' runs a DHT node on port {port}");
+                            self.torrent_context.tx.send(ClientMessage::DhtNode{address: std::net::SocketAddr::new(self.peer_context.ip.ip(), port)}).await?;
                         },
                         PeerMessage::KeepAlive => {
                             tracing::debug!("Peer '{self}' sent keep alive");
@@ -434,8 +705,12 @@ impl Peer {
                         }
                     }
                 }
-                _ = tokio::time::sleep(std::time::Duration::from_secs(unsafe { crate::CLIENT_OPTIONS.client_keep_alive_message_interval_secs })) => {
+                _ = request_check.tick() => {
+                    self.check_requests(&mut peer_session, &mut downloading_blocks).await?;
+                }
+                _ = &mut keep_alive => {
                     // 2 minutes without any message from peer elapsed, sending keep alive
+                    keep_alive.as_mut().reset(tokio::time::Instant::now() + keep_alive_interval);
                     self.keep_alive(&mut peer_session).await?;
                 }
             }
@@ -446,8 +721,9 @@ impl Peer {
                 self.interested(&mut peer_session).await?;
             }
 
-            // if interested in downloading and unchoked
-            if !self.peer_context.choking && self.peer_context.am_interested {
+            // if interested in downloading and unchoked, or allowed to request some pieces while choked
+            let can_request = !self.peer_context.choking || (self.peer_context.fast_extension && !self.peer_context.allowed_fast.is_empty());
+            if can_request && self.peer_context.am_interested {
                 tracing::trace!("in if");
 
                 if crate::utils::is_zero_aligned(&self.peer_context.bitfield) {
@@ -457,7 +733,7 @@ impl Peer {
                     self.not_interested(&mut peer_session).await?;
                 }
                 else {
-                    self.request(&mut peer_session, &mut downloading_blocks, &mut end_game_blocks).await?;
+                    self.request(&mut peer_session, &mut downloading_blocks).await?;
                 }
             }
 
@@ -465,7 +741,6 @@ impl Peer {
             if !self.peer_context.am_interested && !self.peer_context.interested {
                 tokio::time::sleep(std::time::Duration::from_secs(1)).await;
                 tracing::info!("Peer '{self}' disconnecting");
-                self.torrent_context.tx.send(ClientMessage::PeerDisconnected{peer_address: self.peer_context.ip}).await?;
                 break;
             }
 
@@ -1,13 +1,24 @@
 use serde::{Serialize, Deserialize};
-use anyhow::Result;
 
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
 use std::sync::Arc;
 
 use crate::torrent::torrent_info::TorrentInfo;
+use crate::torrent::FilePriority;
+use crate::utils::Bitset;
+
+use super::PeerAddress;
 
 pub mod block_picker_state;
 pub use block_picker_state::BlockPickerState;
 
+pub mod picking_mode;
+pub use picking_mode::PickingMode;
+
+pub mod block_state;
+pub use block_state::BlockState;
+
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Piece {
@@ -25,101 +36,677 @@ pub struct Block {
     pub data: Option<Vec<u8>>,
 }
 
+/// Higher priorities, then started pieces and then rarer pieces are picked first.
+type Rank = (Reverse<FilePriority>, bool, u32);
+
+/// Keeps the state of every block of a torrent, blocks are numbered `piece index * blocks_in_piece + block in piece`.
 #[derive(Debug, Clone)]
 pub struct BlockPicker {
-    pub pieces: Vec<Piece>,
-    pub torrent_info: Arc<TorrentInfo>,    
+    pub torrent_info: Arc<TorrentInfo>,
+    pub picking_mode: PickingMode,
+    /// The priority of every piece, pieces past its end are normal.
+    pub piece_priorities: Vec<FilePriority>,
+    /// How many connected peers have each piece.
+    availability: Vec<u32>,
+
+    /// Blocks with at least one request that wasn't answered yet, and the peers they were requested from.
+    requested: Bitset,
+    requesters: HashMap<usize, Vec<PeerAddress>>,
+    received: Bitset,
+    written: Bitset,
+    /// Pieces that passed the hash check.
+    verified: Bitset,
+
+    /// Blocks of every piece that are neither requested nor received.
+    free_blocks: Vec<u32>,
+    /// Blocks of every piece that weren't received.
+    missing_blocks: Vec<u32>,
+    /// Free and missing blocks of the wanted pieces.
+    wanted_free_blocks: usize,
+    wanted_missing_blocks: usize,
+
+    /// The wanted pieces with free blocks grouped by rank, so a pick doesn't have to rank every piece.
+    ranked_pieces: BTreeMap<Rank, BTreeSet<u32>>,
+    /// The rank every piece is grouped by.
+    ranks: Vec<Option<Rank>>,
 }
 
 impl BlockPicker {
-    pub fn new(pieces: Vec<Piece>, torrent_info: Arc<TorrentInfo>) -> BlockPicker {
-        BlockPicker {
-            pieces,
-            torrent_info, 
-        }
+    pub fn new(torrent_info: Arc<TorrentInfo>) -> BlockPicker {
+        let blocks = torrent_info.pieces_count * torrent_info.blocks_in_piece;
+        let block_counts = (0..torrent_info.pieces_count as u32)
+            .map(|index| torrent_info.get_specific_piece_block_count(index) as u32)
+            .collect::<Vec<u32>>();
+
+        let mut block_picker = BlockPicker {
+            picking_mode: PickingMode::default(),
+            piece_priorities: Vec::new(),
+            availability: vec![0; torrent_info.pieces_count],
+
+            requested: Bitset::new(blocks),
+            requesters: HashMap::new(),
+            received: Bitset::new(blocks),
+            written: Bitset::new(blocks),
+            verified: Bitset::new(torrent_info.pieces_count),
+
+            free_blocks: block_counts.clone(),
+            missing_blocks: block_counts,
+            wanted_free_blocks: 0,
+            wanted_missing_blocks: 0,
+
+            ranked_pieces: BTreeMap::new(),
+            ranks: vec![None; torrent_info.pieces_count],
+            torrent_info,
+        };
+        block_picker.count_wanted_blocks();
+        block_picker.rank_pieces();
+
+        block_picker
     }
 
-    pub fn from_state(block_picker_state: BlockPickerState) -> BlockPicker {
-        BlockPicker {
-            pieces: block_picker_state.pieces,
-            torrent_info: Arc::new(block_picker_state.torrent_info),
+    /// Restores the verified pieces from the torrent's bitfield and the blocks of the other pieces that were already written.
+    pub fn from_state(block_picker_state: BlockPickerState, bitfield: &[u8]) -> BlockPicker {
+        let mut block_picker = BlockPicker::new(Arc::new(block_picker_state.torrent_info));
+        block_picker.picking_mode = block_picker_state.picking_mode;
+        block_picker.rebuild(bitfield);
+
+        for number in block_picker_state.written.ones() {
+            let index = (number / block_picker.torrent_info.blocks_in_piece) as u32;
+            if block_picker.block_number(index, 0).is_some() && !block_picker.verified.get(index as usize) && !block_picker.received.get(number) {
+                block_picker.received.set(number, true);
+                block_picker.written.set(number, true);
+                block_picker.update_counts(index, -1, -1);
+            }
         }
+
+        block_picker
     }
 
-    pub fn is_empty(&self) -> bool {
-        self.pieces.is_empty()
+    pub fn get_priority(&self, piece_index: u32) -> FilePriority {
+        self.piece_priorities.get(piece_index as usize).copied().unwrap_or_default()
     }
 
-    pub fn contains(&self, piece_index: u32) -> bool {
-        self.pieces.iter().any(|p| p.index == piece_index)
+    /// Replaces the priority of every piece, pieces of skipped files aren't picked anymore.
+    pub fn set_piece_priorities(&mut self, piece_priorities: Vec<FilePriority>) {
+        self.piece_priorities = piece_priorities;
+        self.count_wanted_blocks();
+        self.rank_pieces();
     }
 
-    pub fn block_count(&self) -> usize {
-        self.pieces.iter().map(|p| p.block_count).sum()
+    /// Whether a piece has data of a file that isn't skipped.
+    pub fn is_wanted(&self, piece_index: u32) -> bool {
+        self.get_priority(piece_index) != FilePriority::Skip
     }
 
-    pub async fn pick_random(&mut self, peer_bitfield: &[u8]) -> Option<Block> {
-        if self.is_empty() {
-            tracing::warn!("Trying to pick a random block from an empty block picker.");
+    fn count_wanted_blocks(&mut self) {
+        let wanted = (0..self.torrent_info.pieces_count as u32)
+            .filter(|index| self.is_wanted(*index))
+            .collect::<Vec<u32>>();
+
+        self.wanted_free_blocks = wanted.iter().map(|index| self.free_blocks[*index as usize] as usize).sum();
+        self.wanted_missing_blocks = wanted.iter().map(|index| self.missing_blocks[*index as usize] as usize).sum();
+    }
+
+    /// Changes the free and missing block counts of a piece by the given amounts.
+    fn update_counts(&mut self, piece_index: u32, free: i64, missing: i64) {
+        let index = piece_index as usize;
+        self.free_blocks[index] = (self.free_blocks[index] as i64 + free) as u32;
+        self.missing_blocks[index] = (self.missing_blocks[index] as i64 + missing) as u32;
+
+        if self.is_wanted(piece_index) {
+            self.wanted_free_blocks = (self.wanted_free_blocks as i64 + free) as usize;
+            self.wanted_missing_blocks = (self.wanted_missing_blocks as i64 + missing) as usize;
+        }
+        self.update_rank(piece_index);
+    }
+
+    /// `None` for pieces that can't be picked, which are the skipped ones and those without free blocks.
+    fn rank(&self, piece_index: u32) -> Option<Rank> {
+        let index = piece_index as usize;
+        if self.free_blocks[index] == 0 || !self.is_wanted(piece_index) {
             return None;
         }
 
-        let block = 
-        if let Some(piece) = self.pieces.iter_mut().find(|p| {
-            tracing::trace!("piece {p:?} - {}", 0 < peer_bitfield[p.index as usize / 8 ] & 1 << (7 - p.index % 8)); 
-            0 < peer_bitfield[p.index as usize / 8 ] & 1 << (7 - p.index % 8) 
-        }) {
-            piece.block_count -= 1;
+        let partial = self.free_blocks[index] < self.torrent_info.get_specific_piece_block_count(piece_index) as u32;
+        Some((Reverse(self.get_priority(piece_index)), !partial, self.availability[index]))
+    }
 
-            let block_number = piece.index as usize * self.torrent_info.blocks_in_piece + piece.block_count;
-            Block {
-                index: piece.index,
-                begin: (piece.block_count * self.torrent_info.block_length) as u32,
-                length: self.torrent_info.get_specific_block_length(block_number as u32) as u32,
+    /// Moves a piece to the group of its rank, called whenever its priority, free blocks or availability change.
+    fn update_rank(&mut self, piece_index: u32) {
+        let rank = self.rank(piece_index);
+        let old_rank = std::mem::replace(&mut self.ranks[piece_index as usize], rank);
+        if old_rank == rank {
+            return;
+        }
 
-                number: block_number,
-                data: None,
+        if let Some(old_rank) = old_rank {
+            if let Some(pieces) = self.ranked_pieces.get_mut(&old_rank) {
+                pieces.remove(&piece_index);
+                if pieces.is_empty() {
+                    self.ranked_pieces.remove(&old_rank);
+                }
             }
         }
-        else {
-            tracing::trace!("No piece found in block picker.");
+
+        if let Some(rank) = rank {
+            self.ranked_pieces.entry(rank).or_default().insert(piece_index);
+        }
+    }
+
+    fn rank_pieces(&mut self) {
+        for index in 0..self.torrent_info.pieces_count as u32 {
+            self.update_rank(index);
+        }
+    }
+
+    /// The number of a block in a piece, `None` if either doesn't exist.
+    fn block_number(&self, piece_index: u32, block: usize) -> Option<usize> {
+        if piece_index as usize >= self.torrent_info.pieces_count || block >= self.torrent_info.get_specific_piece_block_count(piece_index) {
             return None;
-        };
+        }
 
-        if block.begin == 0 {
-            tracing::trace!("Removing piece {} from block picker.", block.index);
-            self.pieces.retain(|p| p.index != block.index);
+        Some(piece_index as usize * self.torrent_info.blocks_in_piece + block)
+    }
+
+    fn piece_blocks(&self, piece_index: u32) -> std::ops::Range<usize> {
+        let first = piece_index as usize * self.torrent_info.blocks_in_piece;
+        first..first + self.torrent_info.get_specific_piece_block_count(piece_index)
+    }
+
+    pub fn block_state(&self, number: usize) -> BlockState {
+        if self.verified.get(number / self.torrent_info.blocks_in_piece) {
+            BlockState::Verified
+        } else if self.written.get(number) {
+            BlockState::Written
+        } else if self.received.get(number) {
+            BlockState::Received
+        } else if self.requested.get(number) {
+            BlockState::Requested
+        } else {
+            BlockState::Free
         }
+    }
 
+    /// Counts the pieces of a peer that sent its bitfield.
+    pub fn add_bitfield(&mut self, bitfield: &[u8]) {
+        for index in 0..self.torrent_info.pieces_count as u32 {
+            if has_piece(bitfield, index) {
+                self.availability[index as usize] += 1;
+                self.update_rank(index);
+            }
+        }
+    }
+
+    /// Forgets the pieces of a peer that disconnected or replaced its bitfield.
+    pub fn remove_bitfield(&mut self, bitfield: &[u8]) {
+        for index in 0..self.torrent_info.pieces_count as u32 {
+            if has_piece(bitfield, index) {
+                self.availability[index as usize] = self.availability[index as usize].saturating_sub(1);
+                self.update_rank(index);
+            }
+        }
+    }
+
+    /// Counts a piece a peer announced with `Have`.
+    pub fn add_have(&mut self, piece_index: u32) {
+        if let Some(availability) = self.availability.get_mut(piece_index as usize) {
+            *availability += 1;
+            self.update_rank(piece_index);
+        }
+    }
+
+    pub fn get_availability(&self, piece_index: u32) -> u32 {
+        self.availability.get(piece_index as usize).copied().unwrap_or(0)
+    }
+
+    /// Whether every block of the wanted pieces was received.
+    pub fn is_empty(&self) -> bool {
+        self.wanted_missing_blocks == 0
+    }
+
+    pub fn is_verified(&self, piece_index: u32) -> bool {
+        self.verified.get(piece_index as usize)
+    }
+
+    /// Picks a free block of a piece the peer has. In sequential mode the readahead window comes first, in file order.
+    /// Otherwise the pieces of the files with the highest priority come first, among them pieces that are already partly requested
+    /// are finished first and then the rarest piece in the swarm is picked, ties are broken at random.
+    /// Once every wanted block is requested, blocks that other peers are still sending are requested again (end game).
+    pub fn pick_block(&mut self, peer_address: &PeerAddress, peer_bitfield: &[u8]) -> Option<Block> {
+        if self.wanted_free_blocks == 0 {
+            return self.pick_end_game_block(peer_address, peer_bitfield);
+        }
+
+        let index = match self.pick_sequential(peer_bitfield).or_else(|| self.pick_rarest(peer_bitfield)) {
+            Some(index) => index,
+            None => {
+                tracing::trace!("No piece found in block picker.");
+                return None;
+            }
+        };
+
+        let number = self.piece_blocks(index).find(|number| self.block_state(*number) == BlockState::Free)?;
+        self.requested.set(number, true);
+        self.requesters.insert(number, vec![peer_address.clone()]);
+        self.update_counts(index, -1, 0);
+
+        let block = self.block(number);
         tracing::trace!("Picked block: {:?}", block);
         Some(block)
     }
 
-    pub async fn get_end_game_blocks(&mut self, peer_bitfield: &[u8]) -> Result<Option<Vec<Block>>> {
-        if self.is_empty() {
-            tracing::warn!("Trying to pick a random block from an empty block picker.");
-            return Ok(None);
+    /// A requested block the peer has and wasn't asked for yet.
+    fn pick_end_game_block(&mut self, peer_address: &PeerAddress, peer_bitfield: &[u8]) -> Option<Block> {
+        let blocks_in_piece = self.torrent_info.blocks_in_piece;
+        let (number, requesters) = self.requesters
+            .iter_mut()
+            .filter(|(number, requesters)| {
+                let index = (**number / blocks_in_piece) as u32;
+                has_piece(peer_bitfield, index) && !requesters.contains(peer_address)
+            })
+            .min_by_key(|(number, requesters)| (requesters.len(), **number))?;
+
+        requesters.push(peer_address.clone());
+        let number = *number;
+
+        let block = self.block(number);
+        tracing::trace!("Picked end game block: {:?}", block);
+        Some(block)
+    }
+
+    fn block(&self, number: usize) -> Block {
+        let blocks_in_piece = self.torrent_info.blocks_in_piece;
+
+        Block {
+            index: (number / blocks_in_piece) as u32,
+            begin: ((number % blocks_in_piece) * self.torrent_info.block_length) as u32,
+            length: self.torrent_info.get_specific_block_length(number as u32) as u32,
+
+            number,
+            data: None,
         }
+    }
 
-        let mut blocks = Vec::new();
-        for piece in self.pieces.iter() {
-            if 0 < peer_bitfield[piece.index as usize / 8 ] & 1 << (7 - piece.index % 8) {
-                for n in 0..piece.block_count {
-                    let block_number = piece.index as usize * self.torrent_info.blocks_in_piece + n;
-                    let block = Block {
-                        index: piece.index,
-                        begin: (n * self.torrent_info.block_length) as u32,
-                        length: self.torrent_info.get_specific_block_length(block_number as u32) as u32,
+    /// Whether a wanted piece still has free blocks the peer can send.
+    fn is_pickable(&self, piece_index: u32, peer_bitfield: &[u8]) -> bool {
+        self.free_blocks[piece_index as usize] > 0 && self.is_wanted(piece_index) && has_piece(peer_bitfield, piece_index)
+    }
 
-                        number: block_number,
-                        data: None,
-                    };
+    /// The first piece of the readahead window the peer has,
+    /// the window starts at the first piece with free blocks from the playback position on.
+    fn pick_sequential(&self, peer_bitfield: &[u8]) -> Option<u32> {
+        let position = match self.picking_mode {
+            PickingMode::Sequential { position } => position,
+            PickingMode::RarestFirst => return None,
+        };
 
-                    blocks.push(block);
-                }
+        let pieces_count = self.torrent_info.pieces_count as u32;
+        let start = (position..pieces_count).find(|index| self.free_blocks[*index as usize] > 0 && self.is_wanted(*index))?;
+        let end = start.saturating_add(unsafe { crate::CLIENT_OPTIONS.readahead_pieces } as u32).min(pieces_count);
+
+        (start..end).find(|index| self.is_pickable(*index, peer_bitfield))
+    }
+
+    /// The highest priority piece the peer has, started and rare pieces first.
+    fn pick_rarest(&self, peer_bitfield: &[u8]) -> Option<u32> {
+        // the best group the peer has any piece of, a random one of them is picked
+        self.ranked_pieces.values().find_map(|pieces| {
+            let candidates = pieces.iter().filter(|index| has_piece(peer_bitfield, **index));
+            let count = candidates.clone().count();
+            if count == 0 {
+                return None;
+            }
+
+            candidates.copied().nth(crate::utils::random_index(count))
+        })
+    }
+
+    /// Gives back a block a peer won't send, it becomes free again unless other peers were asked for it too.
+    pub fn release_block(&mut self, number: usize, peer_address: &PeerAddress) {
+        let requesters = match self.requesters.get_mut(&number) {
+            Some(requesters) => requesters,
+            None => return
+        };
+        requesters.retain(|requester| requester != peer_address);
+        if !requesters.is_empty() {
+            return;
+        }
+
+        self.requesters.remove(&number);
+        self.requested.set(number, false);
+        self.update_counts((number / self.torrent_info.blocks_in_piece) as u32, 1, 0);
+    }
+
+    /// Gives back every block that was requested from a peer, used when it disconnects.
+    pub fn release_peer(&mut self, peer_address: &PeerAddress) {
+        let numbers = self.requesters
+            .iter()
+            .filter(|(_, requesters)| requesters.contains(peer_address))
+            .map(|(number, _)| *number)
+            .collect::<Vec<usize>>();
+
+        for number in numbers {
+            self.release_block(number, peer_address);
+        }
+    }
+
+    /// Marks a block as received from a peer. Returns the other peers it was requested from so they can be cancelled,
+    /// or `None` if it was already received and the data has to be dropped.
+    pub fn receive_block(&mut self, number: usize, peer_address: &PeerAddress) -> Option<Vec<PeerAddress>> {
+        let index = (number / self.torrent_info.blocks_in_piece) as u32;
+        let was_free = match self.block_state(number) {
+            BlockState::Free => true,
+            BlockState::Requested => false,
+            _ => return None
+        };
+
+        let mut requesters = self.requesters.remove(&number).unwrap_or_default();
+        requesters.retain(|requester| requester != peer_address);
+
+        self.requested.set(number, false);
+        self.received.set(number, true);
+        self.update_counts(index, if was_free { -1 } else { 0 }, -1);
+
+        Some(requesters)
+    }
+
+    pub fn write_block(&mut self, number: usize) {
+        if self.block_state(number) == BlockState::Received {
+            self.written.set(number, true);
+        }
+    }
+
+    pub fn verify_piece(&mut self, piece_index: u32) {
+        if self.block_number(piece_index, 0).is_none() || self.is_verified(piece_index) {
+            return;
+        }
+
+        self.clear_piece(piece_index);
+        self.verified.set(piece_index as usize, true);
+        let (free, missing) = (self.free_blocks[piece_index as usize], self.missing_blocks[piece_index as usize]);
+        self.update_counts(piece_index, -(free as i64), -(missing as i64));
+    }
+
+    /// Forgets everything about a piece that failed a check so all of its blocks are downloaded again.
+    pub fn reset_piece(&mut self, piece_index: u32) {
+        if self.block_number(piece_index, 0).is_none() {
+            return;
+        }
+
+        self.clear_piece(piece_index);
+        self.verified.set(piece_index as usize, false);
+        let block_count = self.torrent_info.get_specific_piece_block_count(piece_index) as i64;
+        let (free, missing) = (self.free_blocks[piece_index as usize], self.missing_blocks[piece_index as usize]);
+        self.update_counts(piece_index, block_count - free as i64, block_count - missing as i64);
+    }
+
+    fn clear_piece(&mut self, piece_index: u32) {
+        for number in self.piece_blocks(piece_index) {
+            self.requesters.remove(&number);
+            self.requested.set(number, false);
+            self.received.set(number, false);
+            self.written.set(number, false);
+        }
+    }
+
+    /// Replaces the state of every block with the pieces of `bitfield`, as after a recheck.
+    pub fn rebuild(&mut self, bitfield: &[u8]) {
+        self.requesters.clear();
+        self.requested.clear();
+        self.received.clear();
+        self.written.clear();
+        self.verified = Bitset::from_bytes(bitfield, self.torrent_info.pieces_count);
+
+        for index in 0..self.torrent_info.pieces_count as u32 {
+            let block_count = match self.is_verified(index) {
+                true => 0,
+                false => self.torrent_info.get_specific_piece_block_count(index) as u32,
+            };
+            self.free_blocks[index as usize] = block_count;
+            self.missing_blocks[index as usize] = block_count;
+        }
+        self.count_wanted_blocks();
+        self.rank_pieces();
+    }
+
+    /// The written blocks of the pieces that aren't verified yet, for the disk manager to count them.
+    pub fn written_pieces(&self) -> Vec<Piece> {
+        let mut pieces: Vec<Piece> = Vec::new();
+        for number in self.written.ones() {
+            let index = (number / self.torrent_info.blocks_in_piece) as u32;
+            match pieces.last_mut() {
+                Some(piece) if piece.index == index => piece.block_count += 1,
+                _ => pieces.push(Piece { index, block_count: 1 }),
             }
         }
 
-        Ok(Some(blocks))
+        pieces
+    }
+}
+
+fn has_piece(bitfield: &[u8], piece_index: u32) -> bool {
+    bitfield
+        .get(piece_index as usize / 8)
+        .is_some_and(|byte| byte & 1 << (7 - piece_index % 8) != 0)
+}
+
+#[cfg(test)]
+mod block_picker_tests {
+    use super::*;
+
+    /// 8 pieces of 2 blocks each.
+    fn block_picker() -> BlockPicker {
+        let torrent_info = TorrentInfo {
+            pieces_count: 8,
+            blocks_count: 16,
+            torrent_size: 16 * 16384,
+            piece_length: 2 * 16384,
+            block_length: 16384,
+            blocks_in_piece: 2,
+            private: false,
+        };
+
+        BlockPicker::new(Arc::new(torrent_info))
+    }
+
+    fn peer(port: u16) -> PeerAddress {
+        PeerAddress(([127, 0, 0, 1], port).into())
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_picks_rarest_piece() {
+        let mut block_picker = block_picker();
+        block_picker.add_bitfield(&[0b1111_1111]);
+        block_picker.add_bitfield(&[0b1111_1011]);
+        block_picker.add_have(0);
+
+        // piece 5 is the only one a single peer has
+        let block = block_picker.pick_block(&peer(1), &[0b1111_1111]).unwrap();
+        assert_eq!(block.index, 5);
+
+        block_picker.remove_bitfield(&[0b1111_1111]);
+        assert_eq!(block_picker.get_availability(5), 0);
+        assert_eq!(block_picker.get_availability(0), 2);
+    }
+
+    #[test]
+    fn test_ranks_follow_piece_changes() {
+        let mut block_picker = block_picker();
+        block_picker.add_bitfield(&[0b1111_0000]);
+        block_picker.add_have(7);
+        block_picker.pick_block(&peer(1), &[0b0000_0001]).unwrap();
+
+        let mut priorities = vec![FilePriority::Normal; 8];
+        priorities[2] = FilePriority::Skip;
+        priorities[3] = FilePriority::High;
+        block_picker.set_piece_priorities(priorities);
+        block_picker.receive_block(14, &peer(1));
+        block_picker.receive_block(15, &peer(2));
+        block_picker.reset_piece(0);
+        block_picker.remove_bitfield(&[0b1000_0000]);
+
+        // the groups are the same as ranking every piece from scratch
+        let mut ranked_pieces: BTreeMap<Rank, BTreeSet<u32>> = BTreeMap::new();
+        for index in 0..8 {
+            if let Some(rank) = block_picker.rank(index) {
+                ranked_pieces.entry(rank).or_default().insert(index);
+            }
+        }
+        assert_eq!(block_picker.ranked_pieces, ranked_pieces);
+        assert_eq!(ranked_pieces.values().next().unwrap(), &BTreeSet::from([3]));
+        assert!(!ranked_pieces.values().any(|pieces| pieces.contains(&2) || pieces.contains(&7)));
+    }
+
+    #[test]
+    fn test_finishes_partial_pieces_first() {
+        let mut block_picker = block_picker();
+        block_picker.add_bitfield(&[0b1111_1111]);
+        block_picker.add_bitfield(&[0b0111_1111]);
+
+        let first = block_picker.pick_block(&peer(1), &[0b1111_1111]).unwrap();
+        assert_eq!(first.index, 0);
+
+        // piece 0 is now the most common one, but it was started so its last block comes first
+        block_picker.add_have(0);
+        block_picker.add_have(0);
+        let second = block_picker.pick_block(&peer(1), &[0b1111_1111]).unwrap();
+        assert_eq!(second.index, 0);
+        assert_eq!(block_picker.block_state(0), BlockState::Requested);
+        assert_eq!(block_picker.block_state(1), BlockState::Requested);
+    }
+
+    #[test]
+    fn test_only_picks_pieces_the_peer_has() {
+        let mut block_picker = block_picker();
+
+        assert!(block_picker.pick_block(&peer(1), &[0]).is_none());
+        for _ in 0..2 {
+            assert_eq!(block_picker.pick_block(&peer(1), &[0b0000_0100]).unwrap().index, 5);
+        }
+        assert!(block_picker.pick_block(&peer(1), &[0b0000_0100]).is_none());
+    }
+
+    #[test]
+    fn test_sequential_picks_in_order_from_position() {
+        let mut block_picker = block_picker();
+        block_picker.picking_mode = PickingMode::Sequential { position: 3 };
+        block_picker.add_bitfield(&[0b1111_1111]);
+        block_picker.add_have(1);
+        block_picker.add_have(2);
+        block_picker.add_have(2);
+
+        let mut picked = Vec::new();
+        while let Some(block) = block_picker.pick_block(&peer(1), &[0b1110_1111]) {
+            picked.push(block.index);
+        }
+
+        // piece 3 is missing from the peer, the pieces before the position come last and rarest first
+        assert_eq!(picked, vec![4, 4, 5, 5, 6, 6, 7, 7, 0, 0, 1, 1, 2, 2]);
+    }
+
+    #[test]
+    fn test_skips_pieces_and_picks_by_priority() {
+        let mut block_picker = block_picker();
+        block_picker.add_bitfield(&[0b1111_1111]);
+        block_picker.add_have(6);
+
+        let mut priorities = vec![FilePriority::Normal; 8];
+        priorities[0] = FilePriority::Skip;
+        priorities[1] = FilePriority::Skip;
+        priorities[5] = FilePriority::Low;
+        priorities[6] = FilePriority::High;
+        block_picker.set_piece_priorities(priorities);
+
+        // piece 6 is the most common one but has the highest priority, the low priority piece comes last
+        let mut picked = Vec::new();
+        while let Some(block) = block_picker.pick_block(&peer(1), &[0b1111_1111]) {
+            picked.push(block.index);
+        }
+        assert_eq!(picked.len(), 12);
+        assert_eq!(picked[..2], [6, 6]);
+        assert_eq!(picked[picked.len() - 2..], [5, 5]);
+        assert!(!picked.contains(&0) && !picked.contains(&1));
+
+        // wanting the skipped pieces again brings them back
+        block_picker.set_piece_priorities(Vec::new());
+        assert_eq!(block_picker.pick_block(&peer(1), &[0b1100_0000]).unwrap().index / 2, 0);
+    }
+
+    #[test]
+    fn test_released_blocks_are_picked_again() {
+        let mut block_picker = block_picker();
+
+        let first = block_picker.pick_block(&peer(1), &[0b1000_0000]).unwrap();
+        let second = block_picker.pick_block(&peer(1), &[0b1000_0000]).unwrap();
+        block_picker.release_block(second.number, &peer(1));
+        assert_eq!(block_picker.block_state(second.number), BlockState::Free);
+
+        // only the released block is free, the other one is still requested
+        assert_eq!(block_picker.pick_block(&peer(2), &[0b1000_0000]).unwrap().number, second.number);
+
+        block_picker.release_peer(&peer(1));
+        assert_eq!(block_picker.block_state(first.number), BlockState::Free);
+        assert_eq!(block_picker.block_state(second.number), BlockState::Requested);
+    }
+
+    #[test]
+    fn test_end_game_requests_blocks_again() {
+        let mut block_picker = block_picker();
+        for _ in 0..16 {
+            block_picker.pick_block(&peer(1), &[0b1111_1111]).unwrap();
+        }
+
+        // every block is requested from peer 1, so peer 2 gets the same ones
+        let block = block_picker.pick_block(&peer(2), &[0b0000_0001]).unwrap();
+        assert_eq!(block.index, 7);
+
+        // the first peer to send the block wins, the other one gets cancelled
+        assert_eq!(block_picker.receive_block(block.number, &peer(2)), Some(vec![peer(1)]));
+        assert_eq!(block_picker.receive_block(block.number, &peer(1)), None);
+        assert_eq!(block_picker.block_state(block.number), BlockState::Received);
+
+        assert!(!block_picker.is_empty());
+        for number in 0..16 {
+            block_picker.receive_block(number, &peer(1));
+        }
+        assert!(block_picker.is_empty());
+    }
+
+    #[test]
+    fn test_piece_states() {
+        let mut block_picker = block_picker();
+        let block = block_picker.pick_block(&peer(1), &[0b1000_0000]).unwrap();
+        block_picker.receive_block(block.number, &peer(1));
+        block_picker.write_block(block.number);
+        assert_eq!(block_picker.block_state(block.number), BlockState::Written);
+        assert_eq!(block_picker.written_pieces().len(), 1);
+
+        block_picker.verify_piece(0);
+        assert_eq!(block_picker.block_state(0), BlockState::Verified);
+        assert_eq!(block_picker.block_state(1), BlockState::Verified);
+
+        block_picker.reset_piece(0);
+        assert_eq!(block_picker.block_state(0), BlockState::Free);
+        assert_eq!(block_picker.pick_block(&peer(1), &[0b1000_0000]).unwrap().index, 0);
+    }
+
+    #[test]
+    fn test_from_state_keeps_written_blocks() {
+        let mut block_picker = block_picker();
+        for number in [2, 4, 5] {
+            block_picker.receive_block(number, &peer(1));
+            block_picker.write_block(number);
+        }
+
+        let state = BlockPickerState::from_context(block_picker);
+        let serialized = serde_json::to_string(&state).unwrap();
+        let state = serde_json::from_str::<BlockPickerState>(&serialized).unwrap();
+
+        // piece 2 was verified after the state was saved
+        let block_picker = BlockPicker::from_state(state, &[0b0010_0000]);
+        assert_eq!(block_picker.block_state(2), BlockState::Written);
+        assert_eq!(block_picker.block_state(3), BlockState::Free);
+        assert_eq!(block_picker.block_state(4), BlockState::Verified);
+        assert_eq!(block_picker.written_pieces().len(), 1);
+    }
+}
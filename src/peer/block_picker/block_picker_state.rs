@@ -1,21 +1,58 @@
 use serde::{Serialize, Deserialize};
 
 use crate::torrent::TorrentInfo;
+use crate::utils::Bitset;
 
-use super::{BlockPicker, Piece};
+use super::{BlockPicker, PickingMode};
 
 
+/// The verified pieces are the torrent's bitfield and the priorities come from its files, so only the blocks
+/// of unfinished pieces that are already on disk are saved.
 #[derive(Debug, Serialize, Deserialize)]
 pub struct BlockPickerState {
-    pub pieces: Vec<Piece>,
-    pub torrent_info: TorrentInfo,  
+    pub torrent_info: TorrentInfo,
+    #[serde(default)]
+    pub picking_mode: PickingMode,
+    #[serde(default)]
+    pub written: Bitset,
+    /// pieces with data of wanted files, empty if every piece is wanted
+    #[serde(default)]
+    pub wanted: Bitset,
 }
 
 impl BlockPickerState {
     pub fn from_context(block_picker: BlockPicker) -> BlockPickerState {
+        let mut wanted = Bitset::new(block_picker.torrent_info.pieces_count);
+        for index in 0..block_picker.torrent_info.pieces_count {
+            wanted.set(index, block_picker.is_wanted(index as u32));
+        }
+
         BlockPickerState {
-            pieces: block_picker.pieces,
             torrent_info: (*block_picker.torrent_info).clone(),
+            picking_mode: block_picker.picking_mode,
+            written: block_picker.written,
+            wanted,
         }
     }
-}
\ No newline at end of file
+
+    pub fn is_wanted(&self, piece_index: u32) -> bool {
+        self.wanted.is_empty() || self.wanted.get(piece_index as usize)
+    }
+
+    /// How many pieces have data of wanted files.
+    pub fn wanted_pieces_count(&self) -> usize {
+        match self.wanted.is_empty() {
+            true => self.torrent_info.pieces_count,
+            false => self.wanted.count_ones()
+        }
+    }
+
+    /// How many pieces of wanted files aren't in the torrent's bitfield yet.
+    pub fn pieces_left(&self, bitfield: &[u8]) -> usize {
+        let verified = Bitset::from_bytes(bitfield, self.torrent_info.pieces_count);
+
+        (0..self.torrent_info.pieces_count as u32)
+            .filter(|index| self.is_wanted(*index) && !verified.get(*index as usize))
+            .count()
+    }
+}
@@ -0,0 +1,14 @@
+/// Where a block is on its way from the swarm to the disk.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BlockState {
+    /// Nobody was asked for it yet, or every request for it was cancelled.
+    Free,
+    /// Requested from one peer, or from several in end game.
+    Requested,
+    /// Its data arrived and was handed to the disk manager.
+    Received,
+    /// The disk manager wrote it, the piece isn't verified yet.
+    Written,
+    /// Its piece passed the hash check.
+    Verified,
+}
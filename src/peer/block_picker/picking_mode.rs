@@ -0,0 +1,21 @@
+use serde::{Serialize, Deserialize};
+
+/// How a torrent chooses the next pieces to download.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum PickingMode {
+    /// Rarest pieces first, which keeps every piece alive in the swarm.
+    #[default]
+    RarestFirst,
+    /// Pieces in file order from the playback position on, so media can be played while it downloads.
+    /// Only the readahead window after the first missing piece is sequential, the rest stays rarest first.
+    Sequential { position: u32 },
+}
+
+impl std::fmt::Display for PickingMode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PickingMode::RarestFirst => write!(f, "rarest first"),
+            PickingMode::Sequential { position } => write!(f, "sequential from piece {}", position),
+        }
+    }
+}
@@ -1,5 +1,6 @@
 use tokio::sync::{mpsc, Mutex};
 
+use std::collections::HashMap;
 use std::sync::Arc;
 
 use crate::torrent::TorrentInfo;
@@ -16,12 +17,13 @@ pub struct PeerTorrentContext {
     pub info_hash: Sha1Hash,
     pub needed: Arc<Mutex<BlockPicker>>,
     pub bitfield: Arc<Mutex<Vec<u8>>>,
+    pub piece_contributors: Arc<Mutex<HashMap<u32, Vec<PeerAddress>>>>,
 
     pub uploaded: Arc<Mutex<u64>>
 }
 
 impl PeerTorrentContext {
-    pub fn new(tx: mpsc::Sender<ClientMessage>, torrent_info: Arc<TorrentInfo>, info_hash: Sha1Hash, needed: Arc<Mutex<BlockPicker>>, bitfield: Arc<Mutex<Vec<u8>>>, uploaded: Arc<Mutex<u64>>) -> Self {
+    pub fn new(tx: mpsc::Sender<ClientMessage>, torrent_info: Arc<TorrentInfo>, info_hash: Sha1Hash, needed: Arc<Mutex<BlockPicker>>, bitfield: Arc<Mutex<Vec<u8>>>, piece_contributors: Arc<Mutex<HashMap<u32, Vec<PeerAddress>>>>, uploaded: Arc<Mutex<u64>>) -> Self {
         Self {
             tx,
 
@@ -29,11 +31,28 @@ impl PeerTorrentContext {
             info_hash,
             needed,
             bitfield,
+            piece_contributors,
             uploaded,
         }
     }
 }
 
+/// Shared with the torrent, whose choker ranks the peers with it.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct PeerStats {
+    /// Bytes of blocks we downloaded from the peer.
+    pub downloaded: u64,
+    /// Bytes of blocks we uploaded to the peer.
+    pub uploaded: u64,
+    pub interested: bool,
+    /// The peer stopped sending the blocks we requested.
+    pub snubbed: bool,
+    /// How many requests we keep outstanding with the peer.
+    pub queue_depth: usize,
+    /// Bytes per second we download from the peer.
+    pub download_rate: u64,
+}
+
 pub(super) struct PeerContext {
     pub id: [u8;20],
     pub ip: PeerAddress,
@@ -42,4 +61,10 @@ pub(super) struct PeerContext {
     pub interested: bool,
     pub choking: bool,
     pub bitfield: Vec<u8>,
+    /// Both sides support the fast extension (BEP 6).
+    pub fast_extension: bool,
+    /// Pieces the peer lets us request while it is choking us.
+    pub allowed_fast: Vec<u32>,
+    /// Pieces we let the peer request while we are choking it.
+    pub sent_allowed_fast: Vec<u32>,
 }
\ No newline at end of file
@@ -0,0 +1,279 @@
+use anyhow::{anyhow, Result};
+
+use std::collections::BTreeMap;
+use std::net::IpAddr;
+
+use crate::messager::ClientMessage;
+use crate::utils::bencode::BencodedValue;
+
+use super::PeerMessage;
+
+/// Id of the extension handshake message, every other id is assigned through the `m` dictionary.
+pub const EXTENSION_HANDSHAKE_ID: u8 = 0;
+/// Number of outstanding requests we advertise in the `reqq` field.
+const MAX_OUTSTANDING_REQUESTS: i64 = 250;
+
+/// The bencoded dictionary exchanged in the extension handshake (BEP 10).
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ExtensionHandshake {
+    /// Maps extension names to the message ids the sender wants to receive them under.
+    pub m: BTreeMap<String, u8>,
+    /// Client name and version.
+    pub v: Option<String>,
+    /// Listening port of the sender.
+    pub p: Option<u16>,
+    /// Number of outstanding requests the sender supports.
+    pub reqq: Option<i64>,
+    /// The ip address of the receiver as seen by the sender.
+    pub yourip: Option<IpAddr>,
+    pub metadata_size: Option<usize>,
+}
+
+impl ExtensionHandshake {
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        if bytes.is_empty() {
+            return Err(anyhow!("Empty extension handshake"));
+        }
+        let handshake = BencodedValue::from_bytes(bytes)?;
+        let handshake = handshake.try_into_dict()?;
+
+        let mut m = BTreeMap::new();
+        if let Some(BencodedValue::Dict(extensions)) = handshake.get(&b"m".to_vec()) {
+            for (name, id) in extensions {
+                // an id of 0 means the extension is disabled
+                if let (Ok(name), BencodedValue::Integer(id @ 1..=255)) = (String::from_utf8(name.clone()), id) {
+                    m.insert(name, *id as u8);
+                }
+            }
+        }
+
+        let v = match handshake.get(&b"v".to_vec()) {
+            Some(BencodedValue::ByteString(v)) => Some(String::from_utf8_lossy(v).to_string()),
+            _ => None
+        };
+
+        let p = match handshake.get(&b"p".to_vec()) {
+            Some(BencodedValue::Integer(p @ 1..=65535)) => Some(*p as u16),
+            _ => None
+        };
+
+        let reqq = match handshake.get(&b"reqq".to_vec()) {
+            Some(BencodedValue::Integer(reqq)) if *reqq > 0 => Some(*reqq),
+            _ => None
+        };
+
+        let yourip = match handshake.get(&b"yourip".to_vec()) {
+            Some(BencodedValue::ByteString(ip)) => match ip.len() {
+                4 => Some(IpAddr::from(<[u8; 4]>::try_from(ip.as_slice()).unwrap())),
+                16 => Some(IpAddr::from(<[u8; 16]>::try_from(ip.as_slice()).unwrap())),
+                _ => None
+            },
+            _ => None
+        };
+
+        let metadata_size = match handshake.get(&b"metadata_size".to_vec()) {
+            Some(BencodedValue::Integer(size)) if *size > 0 => Some(*size as usize),
+            _ => None
+        };
+
+        Ok(Self {
+            m,
+            v,
+            p,
+            reqq,
+            yourip,
+            metadata_size,
+        })
+    }
+
+    pub fn as_bytes(&self) -> Result<Vec<u8>> {
+        let m = self.m
+            .iter()
+            .map(|(name, id)| (name.as_bytes().to_vec(), BencodedValue::Integer(*id as i64)))
+            .collect();
+
+        let mut handshake = BTreeMap::new();
+        handshake.insert(b"m".to_vec(), BencodedValue::Dict(m));
+        if let Some(v) = &self.v {
+            handshake.insert(b"v".to_vec(), BencodedValue::ByteString(v.as_bytes().to_vec()));
+        }
+        if let Some(p) = self.p {
+            handshake.insert(b"p".to_vec(), BencodedValue::Integer(p as i64));
+        }
+        if let Some(reqq) = self.reqq {
+            handshake.insert(b"reqq".to_vec(), BencodedValue::Integer(reqq));
+        }
+        if let Some(yourip) = self.yourip {
+            let ip = match yourip {
+                IpAddr::V4(ip) => ip.octets().to_vec(),
+                IpAddr::V6(ip) => ip.octets().to_vec(),
+            };
+            handshake.insert(b"yourip".to_vec(), BencodedValue::ByteString(ip));
+        }
+        if let Some(metadata_size) = self.metadata_size {
+            handshake.insert(b"metadata_size".to_vec(), BencodedValue::Integer(metadata_size as i64));
+        }
+
+        BencodedValue::Dict(handshake).as_bytes()
+    }
+}
+
+/// A handler for the messages of one extension, plugged into a peer through the `ExtensionRegistry`.
+pub trait Extension: Send + Sync {
+    /// Name the extension is advertised under in the `m` dictionary, e.g. `ut_pex`.
+    fn name(&self) -> &'static str;
+
+    /// Handles a message the peer sent to this extension.
+    /// Returns messages that should be forwarded to the torrent.
+    fn on_message(&mut self, payload: &[u8]) -> Result<Vec<ClientMessage>>;
+
+    /// Called once the peer sent its extension handshake and supports this extension.
+    fn on_handshake(&mut self, _handshake: &ExtensionHandshake) -> Result<Vec<ClientMessage>> {
+        Ok(Vec::new())
+    }
+
+    /// Handles a message the torrent sent to the peer.
+    /// Returns the payload to send to the peer, if any.
+    fn on_client_message(&mut self, _message: &ClientMessage) -> Result<Option<Vec<u8>>> {
+        Ok(None)
+    }
+}
+
+/// The extensions a peer connection supports, and the ids negotiated for them on both sides.
+#[derive(Default)]
+pub struct ExtensionRegistry {
+    /// Our extensions, the local message id of each is its index + 1.
+    extensions: Vec<Box<dyn Extension>>,
+    peer_handshake: Option<ExtensionHandshake>,
+}
+
+impl ExtensionRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn register(&mut self, extension: Box<dyn Extension>) {
+        self.extensions.push(extension);
+    }
+
+    /// Returns the handshake advertising all registered extensions to a peer with address `peer_ip`.
+    pub fn handshake(&self, peer_ip: Option<IpAddr>) -> ExtensionHandshake {
+        let m = self.extensions
+            .iter()
+            .enumerate()
+            .map(|(index, extension)| (extension.name().to_string(), index as u8 + 1))
+            .collect();
+
+        ExtensionHandshake {
+            m,
+            v: Some(format!("tttorrent {}", env!("CARGO_PKG_VERSION"))),
+            p: Some(unsafe { crate::CLIENT_OPTIONS.listening_port }),
+            reqq: Some(MAX_OUTSTANDING_REQUESTS),
+            yourip: peer_ip,
+            metadata_size: None,
+        }
+    }
+
+    pub fn handshake_message(&self, peer_ip: Option<IpAddr>) -> Result<PeerMessage> {
+        Ok(PeerMessage::Extended(EXTENSION_HANDSHAKE_ID, self.handshake(peer_ip).as_bytes()?))
+    }
+
+    /// The extension handshake the peer sent, if it sent one yet.
+    pub fn peer_handshake(&self) -> Option<&ExtensionHandshake> {
+        self.peer_handshake.as_ref()
+    }
+
+    /// Routes an extended message from the peer to the handshake or to the extension registered under `id`.
+    pub fn handle(&mut self, id: u8, payload: &[u8]) -> Result<Vec<ClientMessage>> {
+        if id == EXTENSION_HANDSHAKE_ID {
+            let handshake = ExtensionHandshake::from_bytes(payload)?;
+
+            let mut messages = Vec::new();
+            for extension in self.extensions.iter_mut().filter(|extension| handshake.m.contains_key(extension.name())) {
+                messages.append(&mut extension.on_handshake(&handshake)?);
+            }
+
+            self.peer_handshake = Some(handshake);
+            return Ok(messages);
+        }
+
+        if self.peer_handshake.is_none() {
+            return Err(anyhow!("Received extended message {} before the extension handshake", id));
+        }
+
+        match self.extensions.get_mut(id as usize - 1) {
+            Some(extension) => extension.on_message(payload),
+            None => Err(anyhow!("Received extended message with unknown id {}", id))
+        }
+    }
+
+    /// Lets the extensions the peer supports react to a message from the torrent.
+    /// Returns the extended messages to send to the peer.
+    pub fn on_client_message(&mut self, message: &ClientMessage) -> Result<Vec<PeerMessage>> {
+        let peer_handshake = match &self.peer_handshake {
+            Some(peer_handshake) => peer_handshake,
+            None => return Ok(Vec::new())
+        };
+
+        let mut peer_messages = Vec::new();
+        for extension in self.extensions.iter_mut() {
+            let id = match peer_handshake.m.get(extension.name()) {
+                Some(id) => *id,
+                None => continue
+            };
+
+            if let Some(payload) = extension.on_client_message(message)? {
+                peer_messages.push(PeerMessage::Extended(id, payload));
+            }
+        }
+
+        Ok(peer_messages)
+    }
+
+    /// Builds a message for the extension `name` with the id the peer asked for.
+    /// Returns `None` if the peer doesn't support the extension.
+    pub fn message(&self, name: &str, payload: Vec<u8>) -> Option<PeerMessage> {
+        let id = *self.peer_handshake.as_ref()?.m.get(name)?;
+        Some(PeerMessage::Extended(id, payload))
+    }
+}
+
+#[cfg(test)]
+mod extension_tests {
+    use super::*;
+
+    #[test]
+    fn test_extension_handshake_roundtrip() {
+        let mut m = BTreeMap::new();
+        m.insert("ut_metadata".to_string(), 3);
+        m.insert("ut_pex".to_string(), 1);
+
+        let handshake = ExtensionHandshake {
+            m,
+            v: Some("tttorrent 0.1.0".to_string()),
+            p: Some(6881),
+            reqq: Some(250),
+            yourip: Some("127.0.0.1".parse().unwrap()),
+            metadata_size: Some(31235),
+        };
+
+        let bytes = handshake.as_bytes().unwrap();
+        assert_eq!(ExtensionHandshake::from_bytes(&bytes).unwrap(), handshake);
+    }
+
+    #[test]
+    fn test_extension_handshake_ignores_disabled_extensions() {
+        let handshake = ExtensionHandshake::from_bytes(b"d1:md6:ut_pexi0e11:ut_metadatai2eee").unwrap();
+
+        assert_eq!(handshake.m.len(), 1);
+        assert_eq!(handshake.m.get("ut_metadata"), Some(&2));
+        assert_eq!(handshake.v, None);
+    }
+
+    #[test]
+    fn test_extension_handshake_rejects_empty_and_truncated_payloads() {
+        assert!(ExtensionHandshake::from_bytes(b"").is_err());
+        assert!(ExtensionHandshake::from_bytes(b"d1:md6:ut_pexi1").is_err());
+        assert!(ExtensionHandshake::from_bytes(b"d1:v20:abc").is_err());
+    }
+}
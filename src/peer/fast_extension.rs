@@ -0,0 +1,79 @@
+use std::net::Ipv4Addr;
+
+use crate::utils::sha1hash::{Sha1Hash, sha1_hash};
+
+/// Number of pieces a peer is allowed to request from us while choked.
+pub const ALLOWED_FAST_SET_SIZE: usize = 10;
+
+/// Computes the canonical allowed fast set (BEP 6) of the peer at `ip`.
+pub fn allowed_fast_set(ip: Ipv4Addr, info_hash: &Sha1Hash, pieces_count: usize, set_size: usize) -> Vec<u32> {
+    let set_size = std::cmp::min(set_size, pieces_count);
+    let mut allowed_fast = Vec::with_capacity(set_size);
+
+    // only the /24 network of the peer counts, so peers can't get more pieces by switching addresses
+    let mut x = (u32::from(ip) & 0xFFFFFF00).to_be_bytes().to_vec();
+    x.extend_from_slice(&info_hash.0);
+
+    while allowed_fast.len() < set_size {
+        x = sha1_hash(x).0.to_vec();
+
+        for chunk in x.chunks_exact(4) {
+            if allowed_fast.len() == set_size {
+                break;
+            }
+
+            let index = u32::from_be_bytes(chunk.try_into().unwrap()) % pieces_count as u32; // chunks are always 4 bytes
+            if !allowed_fast.contains(&index) {
+                allowed_fast.push(index);
+            }
+        }
+    }
+
+    allowed_fast
+}
+
+/// Returns whether `bitfield` has every one of the `pieces_count` pieces.
+pub fn has_all_pieces(bitfield: &[u8], pieces_count: usize) -> bool {
+    (0..pieces_count).all(|piece| bitfield.get(piece / 8).is_some_and(|byte| byte & 1 << (7 - piece % 8) != 0))
+}
+
+/// The bitfield of a peer that has every piece, as announced by HaveAll.
+pub fn full_bitfield(pieces_count: usize) -> Vec<u8> {
+    let mut bitfield = vec![0; pieces_count.div_ceil(8)];
+    for piece in 0..pieces_count {
+        bitfield[piece / 8] |= 1 << (7 - piece % 8);
+    }
+
+    bitfield
+}
+
+#[cfg(test)]
+mod fast_extension_tests {
+    use super::*;
+
+    #[test]
+    fn test_allowed_fast_set() {
+        // example from BEP 6
+        let ip = Ipv4Addr::new(80, 4, 4, 200);
+        let info_hash = Sha1Hash([0xaa; 20]);
+
+        assert_eq!(allowed_fast_set(ip, &info_hash, 1313, 7), vec![1059, 431, 808, 1217, 287, 376, 1188]);
+        assert_eq!(allowed_fast_set(ip, &info_hash, 1313, 9), vec![1059, 431, 808, 1217, 287, 376, 1188, 353, 508]);
+    }
+
+    #[test]
+    fn test_allowed_fast_set_is_capped_by_pieces_count() {
+        let allowed_fast = allowed_fast_set(Ipv4Addr::new(10, 0, 0, 1), &Sha1Hash([1; 20]), 3, ALLOWED_FAST_SET_SIZE);
+
+        assert_eq!(allowed_fast.len(), 3);
+    }
+
+    #[test]
+    fn test_full_bitfield() {
+        let bitfield = full_bitfield(10);
+
+        assert_eq!(bitfield, vec![0xff, 0xc0]);
+        assert!(has_all_pieces(&bitfield, 10));
+        assert!(!has_all_pieces(&[0xff, 0x80], 10));
+    }
+}
@@ -2,47 +2,100 @@ use serde::{Serialize, Deserialize};
 use anyhow::{anyhow, Result};
 
 use std::fmt::Display;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
 
 use crate::utils::bencode::BencodedValue;
 
-#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
-pub struct PeerAddress {
-    pub address: String,
-    pub port: String
+/// Hostnames in tracker responses that take longer to resolve are skipped.
+const RESOLVE_TIMEOUT_SECS: u64 = 5;
+
+/// The ip and port of a peer, ipv4 mapped ipv6 addresses are kept as ipv4 so the same peer always compares equal.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(try_from = "SerializedPeerAddress")]
+pub struct PeerAddress(pub SocketAddr);
+
+/// Peer addresses in state files saved before they were socket addresses are split into strings.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum SerializedPeerAddress {
+    SocketAddr(SocketAddr),
+    Split { address: String, port: String },
+}
+
+impl TryFrom<SerializedPeerAddress> for PeerAddress {
+    type Error = anyhow::Error;
+
+    fn try_from(serialized: SerializedPeerAddress) -> Result<PeerAddress> {
+        match serialized {
+            SerializedPeerAddress::SocketAddr(address) => Ok(PeerAddress::from(address)),
+            SerializedPeerAddress::Split { address, port } => Ok(PeerAddress::new(address.parse()?, port.parse()?)),
+        }
+    }
+}
+
+impl From<SocketAddr> for PeerAddress {
+    fn from(address: SocketAddr) -> PeerAddress {
+        PeerAddress::new(address.ip(), address.port())
+    }
 }
 
 impl Display for PeerAddress {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "{}:{}", self.address, self.port)
+        // ipv6 addresses are put in brackets
+        write!(f, "{}", self.0)
     }
 }
 
 impl PeerAddress {
-    pub fn new(peer_address: [u8; 6]) -> PeerAddress {
-        let address = peer_address[..4]
-            .iter()
-            .map(|&byte| byte.to_string())
-            .collect::<Vec<String>>()
-            .join(".");
-
-        let port = u16::from_be_bytes(peer_address[4..].try_into().unwrap()).to_string();
-
-        PeerAddress { address, port }
-    }
-
-    pub fn to_vec(&self) -> Vec<u8> {
-        let mut bytes = self.address
-            .split('.')
-            .map(|octet| octet.parse::<u8>().unwrap())
-            .collect::<Vec<u8>>();
-        bytes.append(
-            self.port
-                .parse::<u16>()
-                .unwrap()
-                .to_be_bytes()
-                .to_vec()
-                .as_mut()   
-        );
+    pub fn new(ip: IpAddr, port: u16) -> PeerAddress {
+        PeerAddress(SocketAddr::new(ip.to_canonical(), port))
+    }
+
+    pub fn ip(&self) -> IpAddr {
+        self.0.ip()
+    }
+
+    pub fn port(&self) -> u16 {
+        self.0.port()
+    }
+
+    /// Parses the compact model of ipv4 peers, 4 bytes of ip and 2 bytes of port for every peer.
+    pub fn from_compact_v4(bytes: &[u8]) -> Result<Vec<PeerAddress>> {
+        if !bytes.len().is_multiple_of(6) {
+            return Err(anyhow!("Invalid number of bytes in compact ipv4 peers: {}", bytes.len()));
+        }
+
+        Ok(bytes
+            .chunks_exact(6)
+            .map(|peer| {
+                let ip: [u8; 4] = peer[..4].try_into().unwrap(); // chunks are always 6 bytes
+                PeerAddress::new(Ipv4Addr::from(ip).into(), u16::from_be_bytes([peer[4], peer[5]]))
+            })
+            .collect())
+    }
+
+    /// Parses the compact model of ipv6 peers (BEP 7), 16 bytes of ip and 2 bytes of port for every peer.
+    pub fn from_compact_v6(bytes: &[u8]) -> Result<Vec<PeerAddress>> {
+        if !bytes.len().is_multiple_of(18) {
+            return Err(anyhow!("Invalid number of bytes in compact ipv6 peers: {}", bytes.len()));
+        }
+
+        Ok(bytes
+            .chunks_exact(18)
+            .map(|peer| {
+                let ip: [u8; 16] = peer[..16].try_into().unwrap(); // chunks are always 18 bytes
+                PeerAddress::new(Ipv6Addr::from(ip).into(), u16::from_be_bytes([peer[16], peer[17]]))
+            })
+            .collect())
+    }
+
+    /// The compact form of the address, 6 bytes for ipv4 peers and 18 bytes for ipv6 peers.
+    pub fn to_compact(&self) -> Vec<u8> {
+        let mut bytes = match self.ip() {
+            IpAddr::V4(ip) => ip.octets().to_vec(),
+            IpAddr::V6(ip) => ip.octets().to_vec(),
+        };
+        bytes.extend_from_slice(&self.port().to_be_bytes());
 
         bytes
     }
@@ -50,20 +103,140 @@ impl PeerAddress {
     pub async fn from_tracker_response(bencoded_response: BencodedValue) -> Result<Vec<PeerAddress>> {
         let bencoded_dict = bencoded_response.try_into_dict()?;
 
-        match bencoded_dict.get(&b"peers".to_vec()) {
-            Some(BencodedValue::ByteAddresses(byte_addresses)) => Ok(byte_addresses.to_vec()),
-            Some(BencodedValue::Dict(_peer_dict)) => unimplemented!(),
+        let mut peer_addresses = match (bencoded_dict.get(&b"peers".to_vec()), bencoded_dict.get(&b"peers6".to_vec())) {
+            (Some(BencodedValue::ByteString(compact_peers)), _) => PeerAddress::from_compact_v4(compact_peers)?,
+            (Some(BencodedValue::List(peer_dicts)), _) => PeerAddress::from_peer_dicts(peer_dicts).await,
+            // ipv6 only trackers can leave out the ipv4 peers
+            (None, Some(BencodedValue::ByteString(_))) => Vec::new(),
             _ => {
                 if let Some(failure) = bencoded_dict.get(&b"failure reason".to_vec()) {
                     tracing::debug!("Failure reason: {}", String::from_utf8(failure.try_into_byte_string()?.to_vec())?);
-                    Err(anyhow!("Failure reason: {}", String::from_utf8(failure.try_into_byte_string()?.to_vec())?))
+                    return Err(anyhow!("Failure reason: {}", String::from_utf8(failure.try_into_byte_string()?.to_vec())?));
                 }
                 else {
                     tracing::debug!("Invalid peers key in tracker response");
-                    Err(anyhow!("Invalid peers key in tracker response"))
+                    return Err(anyhow!("Invalid peers key in tracker response"));
+                }
+            }
+        };
+
+        if let Some(BencodedValue::ByteString(compact_peers)) = bencoded_dict.get(&b"peers6".to_vec()) {
+            peer_addresses.extend(PeerAddress::from_compact_v6(compact_peers)?);
+        }
+
+        Ok(peer_addresses)
+    }
+
+    /// Dictionary model, every peer has a `peer id`, an `ip` which can also be a hostname and a `port`.
+    /// Peers that are invalid or don't resolve in time are skipped, the hostnames are resolved all at once.
+    async fn from_peer_dicts(peer_dicts: &[BencodedValue]) -> Vec<PeerAddress> {
+        let mut peers = Vec::new();
+
+        for peer_dict in peer_dicts {
+            let (ip, port) = match (peer_dict.get_from_dict(b"ip"), peer_dict.get_from_dict(b"port")) {
+                (Ok(BencodedValue::ByteString(ip)), Ok(BencodedValue::Integer(port))) => (ip, port),
+                _ => {
+                    tracing::debug!("Invalid peer dictionary in tracker response: {:?}", peer_dict);
+                    continue;
+                }
+            };
+
+            let (ip, port) = match (String::from_utf8(ip), u16::try_from(port)) {
+                (Ok(ip), Ok(port)) => (ip, port),
+                _ => {
+                    tracing::debug!("Invalid ip or port in tracker response peer dictionary");
+                    continue;
                 }
+            };
+
+            peers.push((ip, port));
+        }
+
+        let resolved = futures::future::join_all(peers.iter().map(|(ip, port)| PeerAddress::resolve(ip, *port))).await;
+
+        let mut peer_addresses = Vec::new();
+        for ((ip, _), peer_address) in peers.iter().zip(resolved) {
+            match peer_address {
+                Some(peer_address) if !peer_addresses.contains(&peer_address) => peer_addresses.push(peer_address),
+                Some(_) => {},
+                None => tracing::debug!("Peer address '{}' doesn't resolve", ip),
             }
         }
+
+        peer_addresses
     }
 
-}
\ No newline at end of file
+    async fn resolve(ip: &str, port: u16) -> Option<PeerAddress> {
+        match ip.parse::<IpAddr>() {
+            Ok(ip) => Some(PeerAddress::new(ip, port)),
+            Err(_) => tokio::time::timeout(std::time::Duration::from_secs(RESOLVE_TIMEOUT_SECS), tokio::net::lookup_host((ip, port)))
+                .await
+                .ok()?
+                .ok()?
+                .next()
+                .map(PeerAddress::from)
+        }
+    }
+}
+
+#[cfg(test)]
+mod peer_address_tests {
+    use super::*;
+
+    use std::collections::BTreeMap;
+
+    fn peer(address: &str) -> PeerAddress {
+        PeerAddress(address.parse().unwrap())
+    }
+
+    fn peer_dict(ip: &str, port: i64) -> BencodedValue {
+        let mut dict = BTreeMap::new();
+        dict.insert(b"peer id".to_vec(), BencodedValue::ByteString(vec![1; 20]));
+        dict.insert(b"ip".to_vec(), BencodedValue::ByteString(ip.as_bytes().to_vec()));
+        dict.insert(b"port".to_vec(), BencodedValue::Integer(port));
+        BencodedValue::Dict(dict)
+    }
+
+    #[tokio::test]
+    async fn test_compact_peers() {
+        let response = BencodedValue::from_bytes(b"d8:intervali1800e5:peers12:\x0a\x00\x00\x01\x1a\xe1\x7f\x00\x00\x01\x00\x506:peers618:\x20\x01\x0d\xb8\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x01\x1a\xe1e").unwrap();
+        let peers = PeerAddress::from_tracker_response(response).await.unwrap();
+        assert_eq!(peers, vec![peer("10.0.0.1:6881"), peer("127.0.0.1:80"), peer("[2001:db8::1]:6881")]);
+        assert_eq!(peers[2].to_string(), "[2001:db8::1]:6881");
+        assert_eq!(PeerAddress::from_compact_v6(&peers[2].to_compact()).unwrap(), vec![peers[2].clone()]);
+
+        // a `peers` key of the wrong length is no longer rejected by the bencode parser
+        let response = BencodedValue::from_bytes(b"d5:peers5:abcdee").unwrap();
+        assert!(PeerAddress::from_tracker_response(response).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_dictionary_peers() {
+        let mut response = BTreeMap::new();
+        response.insert(b"peers".to_vec(), BencodedValue::List(vec![
+            peer_dict("10.0.0.1", 6881),
+            peer_dict("2001:db8::2", 6881),
+            peer_dict("localhost", 51413),
+            peer_dict("10.0.0.1", 6881),
+            peer_dict("10.0.0.2", 70000),
+            BencodedValue::Integer(1),
+        ]));
+        let response = BencodedValue::from_bytes(&BencodedValue::Dict(response).as_bytes().unwrap()).unwrap();
+
+        let peers = PeerAddress::from_tracker_response(response).await.unwrap();
+        assert_eq!(peers.len(), 3);
+        assert_eq!(peers[..2], [peer("10.0.0.1:6881"), peer("[2001:db8::2]:6881")]);
+        assert!(peers[2].ip().is_loopback() && peers[2].port() == 51413);
+    }
+
+    #[test]
+    fn test_serialization() {
+        let address = PeerAddress::from("[::ffff:10.0.0.1]:6881".parse::<SocketAddr>().unwrap());
+        assert_eq!(address, peer("10.0.0.1:6881"));
+
+        let serialized = serde_json::to_string(&address).unwrap();
+        assert_eq!(serialized, "\"10.0.0.1:6881\"");
+        assert_eq!(serde_json::from_str::<PeerAddress>(&serialized).unwrap(), address);
+        assert_eq!(serde_json::from_str::<PeerAddress>(r#"{"address":"10.0.0.1","port":"6881"}"#).unwrap(), address);
+    }
+}
@@ -1,7 +1,6 @@
 use anyhow::{anyhow, Result};
 use tokio::io::{AsyncWriteExt, AsyncReadExt};
 
-use crate::utils::is_zero_aligned;
 use crate::utils::sha1hash::Sha1Hash;
 
 
@@ -16,15 +15,33 @@ pub struct Handshake {
 
 impl Handshake {
     pub fn new(info_hash: Sha1Hash, peer_id: [u8; 20]) -> Self {
+        // announce support for the extension protocol (BEP 10) and the fast extension (BEP 6)
+        let mut reserved = [0, 0, 0, 0, 0, 0x10, 0, 0x04];
+        if unsafe { crate::CLIENT_OPTIONS.dht_enabled } {
+            reserved[7] |= 0x01;
+        }
+
         Self {
             protocol_len: 19,
             protocol: *b"BitTorrent protocol",
-            reserved: [0; 8],
+            reserved,
             info_hash: info_hash.0,
             peer_id,
         }
     }
 
+    pub fn supports_extension_protocol(&self) -> bool {
+        self.reserved[5] & 0x10 != 0
+    }
+
+    pub fn supports_dht(&self) -> bool {
+        self.reserved[7] & 0x01 != 0
+    }
+
+    pub fn supports_fast_extension(&self) -> bool {
+        self.reserved[7] & 0x04 != 0
+    }
+
     fn from_peer_message(message: PeerMessage) -> Result<Self> {
         match message {
             PeerMessage::Handshake(handshake) => Ok(handshake),
@@ -45,13 +62,48 @@ pub enum PeerMessage {
     Piece(u32, u32, Vec<u8>),
     Cancel(u32, u32, u32),
     Port(u16),
+    Suggest(u32),
+    HaveAll,
+    HaveNone,
+    Reject(u32, u32, u32),
+    AllowedFast(u32),
+    Extended(u8, Vec<u8>),
 
     KeepAlive,
     Handshake(Handshake),
 }
 
+/// Whether a bitfield has a bit for every one of the `pieces_count` pieces and none of the spare bits at the end set.
+pub fn is_valid_bitfield(bitfield: &[u8], pieces_count: usize) -> bool {
+    if bitfield.len() != pieces_count.div_ceil(8) {
+        return false;
+    }
+
+    match pieces_count % 8 {
+        0 => true,
+        used_bits => bitfield.last().is_some_and(|byte| byte & (0xff >> used_bits) == 0)
+    }
+}
+
 impl PeerMessage {
     pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        if bytes.is_empty() {
+            return Ok(Self::KeepAlive);
+        }
+
+        // the shortest each message can be, ids included
+        let min_len = match bytes[0] {
+            4 | 13 | 17 => 5,
+            6 | 8 | 16 => 13,
+            7 => 9,
+            9 => 3,
+            19 => 68,
+            _ => 1
+        };
+        if bytes.len() < min_len {
+            return Err(anyhow!("Peer message with id {} is {} bytes long, expected at least {}", bytes[0], bytes.len(), min_len));
+        }
+
         let message = match bytes[0] {
             0 => Self::Choke,
             1 => Self::Unchoke,
@@ -75,6 +127,22 @@ impl PeerMessage {
                 u32::from_be_bytes(bytes[9..13].try_into().unwrap()),
             ),
             9 => Self::Port(u16::from_be_bytes(bytes[1..3].try_into().unwrap())),
+            13 => Self::Suggest(u32::from_be_bytes(bytes[1..5].try_into().unwrap())),
+            14 => Self::HaveAll,
+            15 => Self::HaveNone,
+            16 => Self::Reject(
+                u32::from_be_bytes(bytes[1..5].try_into().unwrap()),
+                u32::from_be_bytes(bytes[5..9].try_into().unwrap()),
+                u32::from_be_bytes(bytes[9..13].try_into().unwrap()),
+            ),
+            17 => Self::AllowedFast(u32::from_be_bytes(bytes[1..5].try_into().unwrap())),
+            20 => {
+                if bytes.len() < 2 {
+                    return Err(anyhow!("Invalid extended message"));
+                }
+
+                Self::Extended(bytes[1], bytes[2..].to_vec())
+            },
             19 => Self::Handshake(Handshake {
                 protocol_len: bytes[0],
                 protocol: bytes[1..20].try_into().unwrap(),
@@ -143,6 +211,40 @@ impl PeerMessage {
 
                 data
             },
+            PeerMessage::Suggest(index) => {
+                let mut data = vec![0, 0, 0, 5, 13];
+                data.extend_from_slice(&index.to_be_bytes());
+
+                data
+            },
+            PeerMessage::HaveAll => vec![0, 0, 0, 1, 14],
+            PeerMessage::HaveNone => vec![0, 0, 0, 1, 15],
+            PeerMessage::Reject(index, begin, length) => {
+                let mut data = vec![0, 0, 0, 13, 16];
+                data.extend_from_slice(&index.to_be_bytes());
+                data.extend_from_slice(&begin.to_be_bytes());
+                data.extend_from_slice(&length.to_be_bytes());
+
+                data
+            },
+            PeerMessage::AllowedFast(index) => {
+                let mut data = vec![0, 0, 0, 5, 17];
+                data.extend_from_slice(&index.to_be_bytes());
+
+                data
+            },
+
+            PeerMessage::Extended(id, payload) => {
+                let size = (2 + payload.len()) as u32;
+                let mut data = Vec::new();
+
+                data.extend_from_slice(size.to_be_bytes().as_ref());
+                data.push(20);
+                data.push(*id);
+                data.extend_from_slice(payload);
+
+                data
+            },
 
             PeerMessage::KeepAlive => vec![0, 0, 0, 0],
             PeerMessage::Handshake(handshake) => {
@@ -213,7 +315,8 @@ impl PeerSession {
         Ok(())
     }
 
-    pub async fn handshake(&mut self, info_hash: Sha1Hash, client_id: [u8; 20], bitfield: Vec<u8>) -> Result<()> {
+    /// Exchanges handshakes, the caller announces which pieces it has right after.
+    pub async fn handshake(&mut self, info_hash: Sha1Hash, client_id: [u8; 20]) -> Result<()> {
         match self.connection_type {
             ConnectionType::Outgoing => {
                 self.outgoing_handshake(info_hash.clone(), client_id).await?;
@@ -226,10 +329,6 @@ impl PeerSession {
             }
         };
 
-        if !is_zero_aligned(&bitfield) {
-            self.bitfield(bitfield).await?;
-        }
-
         Ok(())
     }
 
@@ -292,4 +391,29 @@ impl PeerSession {
 
         PeerMessage::from_bytes(&message)
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod peer_message_tests {
+    use super::*;
+
+    #[test]
+    fn test_short_messages_are_errors() {
+        for id in [4, 6, 7, 8, 9, 13, 16, 17, 19] {
+            assert!(PeerMessage::from_bytes(&[id, 0]).is_err(), "message with id {id}");
+        }
+
+        assert!(matches!(PeerMessage::from_bytes(&[16, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0, 64, 0]), Ok(PeerMessage::Reject(1, 0, 16384))));
+    }
+
+    #[test]
+    fn test_bitfield_validation() {
+        assert!(is_valid_bitfield(&[0xff, 0xc0], 10));
+        assert!(is_valid_bitfield(&[0xff, 0xff], 16));
+
+        // too short, too long and with a spare bit set
+        assert!(!is_valid_bitfield(&[0xff], 10));
+        assert!(!is_valid_bitfield(&[0xff, 0xc0, 0x00], 10));
+        assert!(!is_valid_bitfield(&[0xff, 0xe0], 10));
+    }
+}
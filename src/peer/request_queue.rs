@@ -0,0 +1,290 @@
+use std::time::{Duration, Instant};
+
+use super::Block;
+
+/// How often the outstanding requests of a peer are checked.
+pub const REQUEST_CHECK_INTERVAL_SECS: u64 = 5;
+/// Requests the peer didn't answer for this long go back to the block picker.
+const REQUEST_TIMEOUT_SECS: u64 = 60;
+/// A peer that has requests but sent no block for this long is snubbed.
+const SNUB_TIMEOUT_SECS: u64 = 60;
+/// The queue never gets shallower than this, unless the peer is snubbed.
+const MIN_QUEUE_DEPTH: usize = 2;
+/// Deepest queue, also the limit for peers that didn't advertise a `reqq` since it is the usual default.
+const MAX_QUEUE_DEPTH: usize = 250;
+/// Besides the blocks arriving in a round trip the queue holds this much more of them, so the peer never runs dry.
+const QUEUE_SLACK_MILLIS: u64 = 1000;
+/// Slow start ends once the rate grew less than 10% over the last measurement.
+const SLOW_START_GROWTH_PERCENT: u64 = 110;
+
+#[derive(Debug, Clone)]
+pub struct PendingRequest {
+    pub block: Block,
+    pub requested_at: Instant,
+}
+
+/// The blocks we requested from a peer and didn't get yet, and how many of them to keep outstanding.
+/// New connections start with a shallow queue that grows by one with every block until the rate stops growing,
+/// after that the depth follows the rate times the round trip time.
+#[derive(Debug)]
+pub struct RequestQueue {
+    requests: Vec<PendingRequest>,
+    /// Requests that timed out and were cancelled, the peer may still send them before it sees the cancel.
+    timed_out: Vec<Block>,
+    /// When the peer last sent a block, or when we started waiting on it.
+    last_progress: Instant,
+    snubbed: bool,
+
+    depth: usize,
+    slow_start: bool,
+    block_length: usize,
+    /// Shortest time a request took to be answered, the queueing at the peer makes the others longer.
+    rtt: Option<Duration>,
+    /// Download rate in bytes per second.
+    rate: u64,
+    last_sample: u64,
+    window_start: Instant,
+    window_bytes: u64,
+}
+
+impl RequestQueue {
+    pub fn new(now: Instant, initial_depth: usize, block_length: usize) -> RequestQueue {
+        RequestQueue {
+            requests: Vec::new(),
+            timed_out: Vec::new(),
+            last_progress: now,
+            snubbed: false,
+
+            depth: initial_depth.clamp(MIN_QUEUE_DEPTH, MAX_QUEUE_DEPTH),
+            slow_start: true,
+            block_length,
+            rtt: None,
+            rate: 0,
+            last_sample: 0,
+            window_start: now,
+            window_bytes: 0,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.requests.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.requests.is_empty()
+    }
+
+    pub fn is_snubbed(&self) -> bool {
+        self.snubbed
+    }
+
+    /// How many requests should be outstanding.
+    pub fn depth(&self) -> usize {
+        self.depth
+    }
+
+    pub fn rate(&self) -> u64 {
+        self.rate
+    }
+
+    pub fn push(&mut self, block: Block, now: Instant) {
+        // a peer we weren't waiting on can't have been slow
+        if self.requests.is_empty() && !self.snubbed {
+            self.last_progress = now;
+        }
+
+        self.requests.push(PendingRequest { block, requested_at: now });
+    }
+
+    /// Removes the request for the block, if there is one.
+    pub fn remove(&mut self, index: u32, begin: u32, length: u32) -> Option<Block> {
+        let position = self.requests
+            .iter()
+            .position(|request| request.block.index == index && request.block.begin == begin && request.block.length == length)?;
+
+        Some(self.requests.remove(position).block)
+    }
+
+    /// Removes the request the block answers, the peer isn't snubbed anymore if it was one.
+    pub fn receive(&mut self, index: u32, begin: u32, length: u32, now: Instant) -> Option<Block> {
+        let position = self.requests
+            .iter()
+            .position(|request| request.block.index == index && request.block.begin == begin && request.block.length == length)?;
+        let request = self.requests.remove(position);
+
+        let rtt = now.saturating_duration_since(request.requested_at);
+        self.rtt = Some(self.rtt.map_or(rtt, |min_rtt| min_rtt.min(rtt)));
+        self.window_bytes += length as u64;
+        self.last_progress = now;
+        self.snubbed = false;
+
+        if self.slow_start {
+            self.depth = (self.depth + 1).min(MAX_QUEUE_DEPTH);
+        }
+
+        Some(request.block)
+    }
+
+    /// Removes a request that already timed out, for a block that arrived after we gave up on it.
+    pub fn receive_late(&mut self, index: u32, begin: u32, length: u32, now: Instant) -> Option<Block> {
+        let position = self.timed_out
+            .iter()
+            .position(|block| block.index == index && block.begin == begin && block.length == length)?;
+        let block = self.timed_out.remove(position);
+
+        self.window_bytes += length as u64;
+        self.last_progress = now;
+        self.snubbed = false;
+
+        Some(block)
+    }
+
+    /// Measures the rate since the last call and resizes the queue to it.
+    pub fn update_rate(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.window_start).as_millis() as u64;
+        if elapsed == 0 {
+            return;
+        }
+
+        let sample = self.window_bytes * 1000 / elapsed;
+        self.rate = match self.rate {
+            0 => sample,
+            rate => (rate + sample) / 2
+        };
+        self.window_start = now;
+        self.window_bytes = 0;
+
+        if self.slow_start {
+            // the rate only means something while the peer had our requests to answer
+            if sample == 0 || sample * 100 >= self.last_sample * SLOW_START_GROWTH_PERCENT {
+                self.last_sample = sample;
+                return;
+            }
+            self.slow_start = false;
+        }
+
+        let rtt = self.rtt.unwrap_or_default() + Duration::from_millis(QUEUE_SLACK_MILLIS);
+        let depth = (self.rate as u128 * rtt.as_millis()).div_ceil(self.block_length as u128 * 1000);
+        self.depth = (depth as usize).clamp(MIN_QUEUE_DEPTH, MAX_QUEUE_DEPTH);
+    }
+
+    /// Removes every request, like when the peer chokes us without the fast extension.
+    pub fn drain(&mut self) -> Vec<Block> {
+        self.requests.drain(..).map(|request| request.block).collect()
+    }
+
+    /// Removes and returns the requests that weren't answered in time.
+    pub fn take_timed_out(&mut self, now: Instant) -> Vec<Block> {
+        let timeout = Duration::from_secs(REQUEST_TIMEOUT_SECS);
+        let (timed_out, pending): (Vec<PendingRequest>, Vec<PendingRequest>) = std::mem::take(&mut self.requests)
+            .into_iter()
+            .partition(|request| now.saturating_duration_since(request.requested_at) >= timeout);
+        self.requests = pending;
+
+        // the peer couldn't keep up with the queue
+        if !timed_out.is_empty() {
+            self.slow_start = false;
+            self.depth = (self.depth / 2).max(MIN_QUEUE_DEPTH);
+        }
+
+        let timed_out: Vec<Block> = timed_out.into_iter().map(|request| request.block).collect();
+        self.timed_out.extend(timed_out.iter().cloned());
+        if self.timed_out.len() > MAX_QUEUE_DEPTH {
+            self.timed_out.drain(..self.timed_out.len() - MAX_QUEUE_DEPTH);
+        }
+
+        timed_out
+    }
+
+    /// Marks the peer snubbed if it sent no block for too long while we were waiting on it.
+    /// Returns true if it just became snubbed.
+    pub fn check_snubbed(&mut self, now: Instant) -> bool {
+        if self.snubbed || self.requests.is_empty() {
+            return false;
+        }
+
+        self.snubbed = now.saturating_duration_since(self.last_progress) >= Duration::from_secs(SNUB_TIMEOUT_SECS);
+        self.snubbed
+    }
+}
+
+#[cfg(test)]
+mod request_queue_tests {
+    use super::*;
+
+    fn block(index: u32) -> Block {
+        Block {
+            index,
+            begin: 0,
+            length: 16384,
+
+            number: index as usize,
+            data: None,
+        }
+    }
+
+    #[test]
+    fn test_timed_out_requests_are_taken() {
+        let start = Instant::now();
+        let mut queue = RequestQueue::new(start, 4, 16384);
+        queue.push(block(0), start);
+        queue.push(block(1), start + Duration::from_secs(30));
+
+        assert!(queue.take_timed_out(start + Duration::from_secs(59)).is_empty());
+
+        let timed_out = queue.take_timed_out(start + Duration::from_secs(60));
+        assert_eq!(timed_out.iter().map(|block| block.index).collect::<Vec<u32>>(), vec![0]);
+        assert_eq!(queue.len(), 1);
+        assert_eq!(queue.depth(), 2);
+
+        // the block can still arrive after it was cancelled, but only once
+        assert!(queue.receive(0, 0, 16384, start + Duration::from_secs(61)).is_none());
+        assert!(queue.receive_late(0, 0, 16384, start + Duration::from_secs(61)).is_some());
+        assert!(queue.receive_late(0, 0, 16384, start + Duration::from_secs(61)).is_none());
+    }
+
+    #[test]
+    fn test_snubbed_until_a_block_arrives() {
+        let start = Instant::now();
+        let mut queue = RequestQueue::new(start, 4, 16384);
+
+        // an idle peer isn't snubbed, the wait starts with the first request
+        queue.push(block(0), start + Duration::from_secs(100));
+        assert!(!queue.check_snubbed(start + Duration::from_secs(120)));
+
+        queue.push(block(1), start + Duration::from_secs(120));
+        assert!(queue.check_snubbed(start + Duration::from_secs(160)));
+        assert!(!queue.check_snubbed(start + Duration::from_secs(165)));
+        assert!(queue.is_snubbed());
+
+        assert!(queue.receive(1, 0, 16384, start + Duration::from_secs(170)).is_some());
+        assert!(!queue.is_snubbed());
+        assert!(queue.receive(1, 0, 16384, start + Duration::from_secs(170)).is_none());
+    }
+
+    #[test]
+    fn test_slow_start_then_depth_follows_rate() {
+        let start = Instant::now();
+        let mut queue = RequestQueue::new(start, 4, 16384);
+
+        // 10 blocks a second with a round trip of 100ms, every block grows the queue during slow start
+        let mut now = start;
+        for second in 1..=2 {
+            for index in 0..10 {
+                queue.push(block(index), now);
+                now += Duration::from_millis(100);
+                queue.receive(index, 0, 16384, now);
+            }
+            assert_eq!(queue.depth(), 4 + second * 10);
+            queue.update_rate(now);
+        }
+
+        // the rate stopped growing, the queue holds the blocks of a round trip and a second
+        assert_eq!(queue.rate(), 163840);
+        assert_eq!(queue.depth(), 11);
+
+        queue.push(block(0), now);
+        queue.receive(0, 0, 16384, now + Duration::from_millis(100));
+        assert_eq!(queue.depth(), 11);
+    }
+}
@@ -0,0 +1,113 @@
+use anyhow::{anyhow, Result};
+
+use std::collections::BTreeMap;
+
+use crate::utils::bencode::BencodedValue;
+use crate::utils::sha1hash::{Sha1Hash, sha1_hash};
+
+use super::{ConnectionType, Handshake, PeerAddress, PeerMessage, PeerSession};
+use super::extension::{ExtensionHandshake, EXTENSION_HANDSHAKE_ID};
+
+/// Id under which we ask peers to send us ut_metadata messages.
+pub const UT_METADATA_ID: u8 = 1;
+/// The metadata is sent in pieces of 16KiB.
+pub const METADATA_PIECE_SIZE: usize = 1 << 14;
+/// Info dictionaries bigger than this are most likely bogus.
+const MAX_METADATA_SIZE: usize = 1 << 24;
+const METADATA_FETCH_TIMEOUT_SECS: u64 = 60;
+
+const MSG_TYPE_REQUEST: i64 = 0;
+const MSG_TYPE_DATA: i64 = 1;
+const MSG_TYPE_REJECT: i64 = 2;
+
+/// Connects to a peer and downloads the info dictionary of `info_hash` from it over the ut_metadata extension (BEP 9).
+/// The returned bytes are verified against the info hash.
+pub async fn fetch_metadata(client_id: [u8; 20], info_hash: Sha1Hash, peer_address: &PeerAddress) -> Result<Vec<u8>> {
+    let fetch = async {
+        let stream = tokio::net::TcpStream::connect(peer_address.0).await?;
+        let mut peer_session = PeerSession::new(stream, ConnectionType::Outgoing, Handshake::default()).await;
+
+        peer_session.send(PeerMessage::Handshake(Handshake::new(info_hash.clone(), client_id))).await?;
+        let peer_handshake = PeerMessage::as_handshake(&peer_session.recv_handshake().await?)?;
+        if peer_handshake.info_hash != info_hash.0 {
+            return Err(anyhow!("Invalid info hash"));
+        }
+        if !peer_handshake.supports_extension_protocol() {
+            return Err(anyhow!("Peer doesn't support the extension protocol"));
+        }
+
+        let extension_handshake = ExtensionHandshake {
+            m: BTreeMap::from([("ut_metadata".to_string(), UT_METADATA_ID)]),
+            ..Default::default()
+        };
+        peer_session.send(PeerMessage::Extended(EXTENSION_HANDSHAKE_ID, extension_handshake.as_bytes()?)).await?;
+
+        let (peer_ut_metadata_id, metadata_size) = loop {
+            if let PeerMessage::Extended(EXTENSION_HANDSHAKE_ID, payload) = peer_session.recv().await? {
+                let extension_handshake = ExtensionHandshake::from_bytes(&payload)?;
+
+                let peer_ut_metadata_id = match extension_handshake.m.get("ut_metadata") {
+                    Some(id) => *id,
+                    None => return Err(anyhow!("Peer doesn't support ut_metadata"))
+                };
+
+                let metadata_size = match extension_handshake.metadata_size {
+                    Some(size) if size <= MAX_METADATA_SIZE => size,
+                    _ => return Err(anyhow!("Peer sent an invalid metadata size"))
+                };
+
+                break (peer_ut_metadata_id, metadata_size);
+            }
+        };
+
+        let pieces_count = metadata_size.div_ceil(METADATA_PIECE_SIZE);
+        for piece in 0..pieces_count {
+            let mut request = BTreeMap::new();
+            request.insert(b"msg_type".to_vec(), BencodedValue::Integer(MSG_TYPE_REQUEST));
+            request.insert(b"piece".to_vec(), BencodedValue::Integer(piece as i64));
+            peer_session.send(PeerMessage::Extended(peer_ut_metadata_id, BencodedValue::Dict(request).as_bytes()?)).await?;
+        }
+
+        let mut metadata = vec![0u8; metadata_size];
+        let mut received_pieces = vec![false; pieces_count];
+        while received_pieces.iter().any(|received| !received) {
+            let payload = match peer_session.recv().await? {
+                PeerMessage::Extended(UT_METADATA_ID, payload) => payload,
+                _ => continue
+            };
+
+            let (message, data_start) = BencodedValue::from_bytes_prefix(&payload)?;
+            let piece = match message.get_from_dict(b"piece") {
+                Ok(BencodedValue::Integer(piece)) if 0 <= piece && (piece as usize) < pieces_count => piece as usize,
+                _ => return Err(anyhow!("Peer sent an invalid metadata piece index"))
+            };
+
+            match message.get_from_dict(b"msg_type") {
+                Ok(BencodedValue::Integer(MSG_TYPE_DATA)) => {
+                    let begin = piece * METADATA_PIECE_SIZE;
+                    let length = std::cmp::min(METADATA_PIECE_SIZE, metadata_size - begin);
+                    let data = &payload[data_start..];
+                    if data.len() != length {
+                        return Err(anyhow!("Peer sent a metadata piece with invalid length"));
+                    }
+
+                    metadata[begin..begin + length].copy_from_slice(data);
+                    received_pieces[piece] = true;
+                },
+                Ok(BencodedValue::Integer(MSG_TYPE_REJECT)) => return Err(anyhow!("Peer rejected metadata piece {}", piece)),
+                _ => continue
+            }
+        }
+
+        if sha1_hash(metadata.clone()) != info_hash {
+            return Err(anyhow!("Metadata doesn't match the info hash"));
+        }
+
+        Ok(metadata)
+    };
+
+    match tokio::time::timeout(std::time::Duration::from_secs(METADATA_FETCH_TIMEOUT_SECS), fetch).await {
+        Ok(result) => result,
+        Err(_) => Err(anyhow!("Timed out fetching metadata from peer '{}'", peer_address))
+    }
+}
@@ -0,0 +1,226 @@
+use anyhow::{anyhow, Result};
+
+use std::collections::BTreeMap;
+use std::time::Instant;
+
+use crate::messager::ClientMessage;
+use crate::utils::bencode::BencodedValue;
+
+use super::{Extension, PeerAddress};
+
+/// Peers must not send more than one ut_pex message per minute.
+pub const PEX_INTERVAL_SECS: u64 = 60;
+/// Neither the added nor the dropped list may hold more than 50 peers.
+pub const MAX_PEX_PEERS: usize = 50;
+/// Slack given to the sender's timer before a message counts as too early.
+const PEX_RECEIVE_SLACK_SECS: u64 = 15;
+
+pub const PEX_FLAG_ENCRYPTION: u8 = 0x01;
+pub const PEX_FLAG_SEED: u8 = 0x02;
+pub const PEX_FLAG_UTP: u8 = 0x04;
+pub const PEX_FLAG_HOLEPUNCH: u8 = 0x08;
+pub const PEX_FLAG_CONNECTABLE: u8 = 0x10;
+
+/// A peer learned through peer exchange, with the flags the sending peer knows about it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PexPeer {
+    pub address: PeerAddress,
+    pub flags: u8,
+}
+
+impl PexPeer {
+    pub fn is_seed(&self) -> bool {
+        self.flags & PEX_FLAG_SEED != 0
+    }
+}
+
+/// The peer exchange extension (BEP 11).
+/// Forwards the peers a peer advertises to the torrent and advertises the torrent's peers back.
+#[derive(Default)]
+pub struct UtPex {
+    /// Peers the remote peer has been told about so far.
+    advertised: Vec<PeerAddress>,
+    last_received: Option<Instant>,
+}
+
+impl UtPex {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn parse_message(payload: &[u8]) -> Result<(Vec<PexPeer>, Vec<PeerAddress>)> {
+        if payload.is_empty() {
+            return Err(anyhow!("Empty ut_pex message"));
+        }
+        let message = BencodedValue::from_bytes(payload)?;
+        let message = message.try_into_dict()?;
+
+        let compact_peers = |key: &[u8], ipv6: bool| -> Result<Vec<PeerAddress>> {
+            match (message.get(key), ipv6) {
+                (Some(BencodedValue::ByteString(peers)), false) => PeerAddress::from_compact_v4(peers),
+                (Some(BencodedValue::ByteString(peers)), true) => PeerAddress::from_compact_v6(peers),
+                _ => Ok(Vec::new())
+            }
+        };
+        let flags = |key: &[u8]| match message.get(key) {
+            Some(BencodedValue::ByteString(flags)) => flags.clone(),
+            _ => Vec::new()
+        };
+        let with_flags = |peers: Vec<PeerAddress>, flags: Vec<u8>| peers
+            .into_iter()
+            .enumerate()
+            .map(move |(index, address)| PexPeer {
+                address,
+                flags: flags.get(index).copied().unwrap_or(0),
+            });
+
+        let mut added = with_flags(compact_peers(b"added", false)?, flags(b"added.f")).collect::<Vec<PexPeer>>();
+        added.extend(with_flags(compact_peers(b"added6", true)?, flags(b"added6.f")));
+
+        let mut dropped = compact_peers(b"dropped", false)?;
+        dropped.extend(compact_peers(b"dropped6", true)?);
+
+        Ok((added, dropped))
+    }
+
+    /// Returns the ut_pex payload telling the peer how `peers` changed since the last message,
+    /// or `None` if nothing changed.
+    pub fn advertise(&mut self, peers: &[PeerAddress]) -> Result<Option<Vec<u8>>> {
+        let added = peers
+            .iter()
+            .filter(|peer| !self.advertised.contains(peer))
+            .take(MAX_PEX_PEERS)
+            .cloned()
+            .collect::<Vec<PeerAddress>>();
+
+        let dropped = self.advertised
+            .iter()
+            .filter(|peer| !peers.contains(peer))
+            .take(MAX_PEX_PEERS)
+            .cloned()
+            .collect::<Vec<PeerAddress>>();
+
+        if added.is_empty() && dropped.is_empty() {
+            return Ok(None);
+        }
+
+        self.advertised.retain(|peer| !dropped.contains(peer));
+        self.advertised.extend(added.iter().cloned());
+
+        // ipv4 and ipv6 peers go in separate lists because their compact forms have different sizes
+        let (added, added6): (Vec<PeerAddress>, Vec<PeerAddress>) = added.into_iter().partition(|peer| peer.ip().is_ipv4());
+        let (dropped, dropped6): (Vec<PeerAddress>, Vec<PeerAddress>) = dropped.into_iter().partition(|peer| peer.ip().is_ipv4());
+        let compact = |peers: &[PeerAddress]| BencodedValue::ByteString(peers.iter().flat_map(|peer| peer.to_compact()).collect());
+
+        let mut message = BTreeMap::new();
+        message.insert(b"added".to_vec(), compact(&added));
+        // we only advertise peers we connected to ourselves
+        message.insert(b"added.f".to_vec(), BencodedValue::ByteString(vec![PEX_FLAG_CONNECTABLE; added.len()]));
+        message.insert(b"dropped".to_vec(), compact(&dropped));
+        if !added6.is_empty() || !dropped6.is_empty() {
+            message.insert(b"added6".to_vec(), compact(&added6));
+            message.insert(b"added6.f".to_vec(), BencodedValue::ByteString(vec![PEX_FLAG_CONNECTABLE; added6.len()]));
+            message.insert(b"dropped6".to_vec(), compact(&dropped6));
+        }
+
+        Ok(Some(BencodedValue::Dict(message).as_bytes()?))
+    }
+}
+
+impl Extension for UtPex {
+    fn name(&self) -> &'static str {
+        "ut_pex"
+    }
+
+    fn on_message(&mut self, payload: &[u8]) -> Result<Vec<ClientMessage>> {
+        if let Some(last_received) = self.last_received {
+            if last_received.elapsed().as_secs() + PEX_RECEIVE_SLACK_SECS < PEX_INTERVAL_SECS {
+                return Err(anyhow!("ut_pex message sent less than {} seconds after the previous one", PEX_INTERVAL_SECS));
+            }
+        }
+        self.last_received = Some(Instant::now());
+
+        let (mut added, dropped) = UtPex::parse_message(payload)?;
+        if added.len() > MAX_PEX_PEERS || dropped.len() > MAX_PEX_PEERS {
+            tracing::debug!("ut_pex message with {} added and {} dropped peers is over the limit, truncating it", added.len(), dropped.len());
+            added.truncate(MAX_PEX_PEERS);
+        }
+
+        if added.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        Ok(vec![ClientMessage::PexPeers{peers: added}])
+    }
+
+    fn on_client_message(&mut self, message: &ClientMessage) -> Result<Option<Vec<u8>>> {
+        match message {
+            ClientMessage::AdvertisePeers{peers} => self.advertise(peers),
+            _ => Ok(None)
+        }
+    }
+}
+
+#[cfg(test)]
+mod ut_pex_tests {
+    use super::*;
+
+    fn peer(address: &str, port: &str) -> PeerAddress {
+        PeerAddress::new(address.parse().unwrap(), port.parse().unwrap())
+    }
+
+    #[test]
+    fn test_advertise_sends_only_changes() {
+        let mut pex = UtPex::new();
+        let first = peer("10.0.0.1", "6881");
+        let second = peer("10.0.0.2", "51413");
+        let third = peer("2001:db8::1", "6881");
+
+        let payload = pex.advertise(&[first.clone(), second.clone(), third.clone()]).unwrap().unwrap();
+        let (added, dropped) = UtPex::parse_message(&payload).unwrap();
+        assert_eq!(added.iter().map(|peer| peer.address.clone()).collect::<Vec<_>>(), vec![first.clone(), second.clone(), third.clone()]);
+        assert!(added.iter().all(|peer| peer.flags == PEX_FLAG_CONNECTABLE));
+        assert!(dropped.is_empty());
+
+        assert!(pex.advertise(&[first.clone(), second.clone(), third.clone()]).unwrap().is_none());
+
+        let payload = pex.advertise(&[second]).unwrap().unwrap();
+        let (added, dropped) = UtPex::parse_message(&payload).unwrap();
+        assert!(added.is_empty());
+        assert_eq!(dropped, vec![first, third]);
+    }
+
+    #[test]
+    fn test_advertise_limits_added_peers() {
+        let mut pex = UtPex::new();
+        let peers = (0..80).map(|i| peer(&format!("10.0.0.{}", i), "6881")).collect::<Vec<_>>();
+
+        let (added, _) = UtPex::parse_message(&pex.advertise(&peers).unwrap().unwrap()).unwrap();
+        assert_eq!(added.len(), MAX_PEX_PEERS);
+
+        let (added, _) = UtPex::parse_message(&pex.advertise(&peers).unwrap().unwrap()).unwrap();
+        assert_eq!(added.len(), 80 - MAX_PEX_PEERS);
+    }
+
+    #[test]
+    fn test_received_messages_are_rate_limited() {
+        let mut pex = UtPex::new();
+        let payload = b"d5:added6:\x0a\x00\x00\x01\x1a\xe17:added.f1:\x02e";
+
+        match pex.on_message(payload).unwrap().as_slice() {
+            [ClientMessage::PexPeers{peers}] => {
+                assert_eq!(peers[0].address, peer("10.0.0.1", "6881"));
+                assert!(peers[0].is_seed());
+            },
+            _ => panic!("expected the added peers"),
+        }
+
+        assert!(pex.on_message(payload).is_err());
+    }
+
+    #[test]
+    fn test_empty_and_truncated_messages_are_errors() {
+        assert!(UtPex::new().on_message(b"").is_err());
+        assert!(UtPex::new().on_message(b"d5:added6:\x0a\x00").is_err());
+    }
+}
@@ -5,7 +5,8 @@ use std::path::{Path, PathBuf};
 use std::process::{exit, Command, Stdio};
 
 use torrent_client::messager::TerminalClientMessage;
-use torrent_client::torrent::TorrentState;
+use torrent_client::peer::PickingMode;
+use torrent_client::torrent::{FilePriority, MagnetLink, TorrentState};
 use torrent_client::utils::terminal::{TerminalClient, create_client_socket};
 
 fn check_file(path: &Path) -> bool {
@@ -36,7 +37,7 @@ fn calculate_percentage(pieces_count: usize, pieces_left: usize) -> f64 {
     (percentage * 100.0).round() / 100.0
 }
 
-fn print_torrent_infos(torrents: Vec<TorrentState>) {
+fn print_torrent_infos(torrents: Vec<TorrentState>, show_peers: bool) {
     print!("{esc}c", esc = 27 as char);
 
     if torrents.is_empty() {
@@ -44,19 +45,57 @@ fn print_torrent_infos(torrents: Vec<TorrentState>) {
         return;
     }
     println!(
-        "{0: <20} | {1: <20}  | {2: <20}   | {3: <20}   | {4: <20}",
-        "name", "progress", "downloaded", "uploaded", "peers"
+        "{0: <20} | {1: <20}  | {2: <20}   | {3: <20}   | {4: <20} | {5: <20} | {6: <20}",
+        "name", "progress", "downloaded", "uploaded", "peers", "seeders", "leechers"
     );
-    println!("{}", "-".repeat(109));
+    println!("{}", "-".repeat(155));
 
     for torrent in torrents {
-        let downloaded_percentage = calculate_percentage(torrent.torrent_info.pieces_count, torrent.needed.pieces.len());
-        let peers = torrent.peers.len();
+        let progress = match torrent.magnet_link {
+            Some(_) => "fetching metadata".to_string(),
+            None => format!("{}%", calculate_percentage(torrent.needed.wanted_pieces_count(), torrent.needed.pieces_left(&torrent.bitfield))),
+        };
+        let peers = match torrent.connected_peers.iter().filter(|peer| peer.snubbed).count() {
+            0 => torrent.peers.len().to_string(),
+            snubbed => format!("{} ({} snubbed)", torrent.peers.len(), snubbed),
+        };
 
         println!(
-            "{0: <20} | {1: <20}% | {2: <20}KB | {3: <20}KB | {4: <20}", 
-            torrent.torrent_name, downloaded_percentage, torrent.downloaded / 1000, torrent.uploaded / 1000, peers
+            "{0: <20} | {1: <21} | {2: <20}KB | {3: <20}KB | {4: <20} | {5: <20} | {6: <20}", 
+            torrent.torrent_name, progress, torrent.downloaded / 1000, torrent.uploaded / 1000, peers,
+            torrent.swarm_stats.complete, torrent.swarm_stats.incomplete
         );
+
+        if torrent.needed.picking_mode != PickingMode::RarestFirst {
+            println!("    downloading {}", torrent.needed.picking_mode);
+        }
+
+        for (index, priority) in torrent.file_priorities.iter().enumerate() {
+            if *priority != FilePriority::Normal {
+                println!("    file {}: {}", index, priority);
+            }
+        }
+
+        for path in &torrent.bad_md5sums {
+            println!("    {}: md5sum in the torrent file doesn't match the verified data", path);
+        }
+
+        for tracker in &torrent.trackers {
+            if let Some(error) = &tracker.last_error {
+                println!("    {}: {} (retrying in {}s)", tracker.announce, error, tracker.next_announce_in_secs());
+            } else if let Some(warning) = &tracker.last_warning {
+                println!("    {}: warning: {}", tracker.announce, warning);
+            }
+        }
+
+        if show_peers {
+            for peer in &torrent.connected_peers {
+                println!(
+                    "    {0: <45} | {1: >8}KB/s | queue {2: <4}{3}",
+                    peer.address, peer.download_rate / 1000, peer.queue_depth, if peer.snubbed { " | snubbed" } else { "" }
+                );
+            }
+        }
     }
 }
 
@@ -80,7 +119,7 @@ Commands:
 
             --block-size - sets the size of the blocks
 
-            --block-request-count - sets the max number of simultanious requests for blocks of data
+            --block-request-count - sets how many requests for blocks of data a new peer starts with, the count then adapts to its rate
 
             --sending-to-ui-interval - sets the time interval in seconds for sending information to the ui programs
 
@@ -98,30 +137,59 @@ Commands:
 
             --listening-port - sets the port on which the client listens for incoming connections
 
+            --hash-fail-ban-threshold - sets after how many failed pieces a contributing peer gets banned
+
+            --max-peers - sets how many peers a torrent connects to at most
+
+            --announce-ip - sets the ip address trackers give out to other peers
+
+            --readahead-pieces - sets how many pieces after the playback position sequential torrents download in order
+
 
         stop - Stop the client daemon
 
-        add <torrent_path> <dest_path> - add a torrent file
+        add <torrent_path | magnet_link> <dest_path> [--sequential] [--priority <file_index>=<priority>,...] - add a torrent file or a magnet link
+
+            --sequential - downloads the pieces in file order so media can be played while it downloads
+
+            --priority - sets the priority of files by their index in the torrent, it can be either \"skip\", \"low\", \"normal\", \"high\"
+
+        mode <info_hash> <rarest | sequential [position]> - pick rarest pieces first or download in order from a piece on
+
+        priority <info_hash> <file_index>=<priority>,... - change the priority of files, skipped files aren't downloaded
+
+        list [--peers] - List all torrents
+
+            --peers - also lists the connected peers with their download rate and how many requests they get at once
 
-        list - List all torrents
+        recheck <info_hash> - hash the downloaded data of a torrent again and rebuild its progress from it
 
 
 "
     );
 }
 
-async fn add(mut client: TerminalClient, src: &str, dest: &str) -> Result<()> {
-    let torrent_path = PathBuf::from(src);
+async fn add(mut client: TerminalClient, src: &str, dest: &str, picking_mode: PickingMode, file_priorities: Vec<(usize, FilePriority)>) -> Result<()> {
     let dest_path = PathBuf::from(dest);
 
-    check_add_arguments(&torrent_path, &dest_path)?;
-    let torrent_path = torrent_path.canonicalize()?;
-    let dest_path = dest_path.canonicalize()?;
+    let torrent_path = match MagnetLink::is_magnet_link(src) {
+        true => {
+            if !check_dir(&dest_path) {
+                return Err(anyhow!("Invalid destination path"));
+            }
+            src.to_string()
+        },
+        false => {
+            let torrent_path = PathBuf::from(src);
+            check_add_arguments(&torrent_path, &dest_path)?;
+            torrent_path.canonicalize()?.to_str().unwrap().to_string()
+        }
+    };
 
-    let torrent_path = torrent_path.to_str().unwrap().to_string();
+    let dest_path = dest_path.canonicalize()?;
     let dest_path = dest_path.to_str().unwrap().to_string();
     
-    client.send_message(&TerminalClientMessage::AddTorrent{src: torrent_path, dst: dest_path}).await?;
+    client.send_message(&TerminalClientMessage::AddTorrent{src: torrent_path, dst: dest_path, picking_mode, file_priorities}).await?;
 
     match client.recv_message().await? {
         TerminalClientMessage::Status{exit_code} => {
@@ -131,6 +199,40 @@ async fn add(mut client: TerminalClient, src: &str, dest: &str) -> Result<()> {
                 },
                 torrent_client::utils::ExitCode::InvalidSrcOrDst => {
                     return Err(anyhow!("Invalid src or dst"));
+                },
+                torrent_client::utils::ExitCode::UnsafeTorrentPath => {
+                    return Err(anyhow!("Torrent file contains unsafe file paths"));
+                },
+                _ => {
+                    return Err(anyhow!("Received invalid status from client"));
+                }
+            }
+        },
+        _ => {
+            return Err(anyhow!("Received invalid message from client"));
+        }
+    };
+
+    Ok(())
+}
+
+async fn recheck(mut client: TerminalClient, info_hash: &str) -> Result<()> {
+    client.send_message(&TerminalClientMessage::Recheck{info_hash: info_hash.to_string()}).await?;
+
+    match client.recv_message().await? {
+        TerminalClientMessage::Status{exit_code} => {
+            match exit_code {
+                torrent_client::utils::ExitCode::SUCCESS => {
+                    println!("Recheck started");
+                },
+                torrent_client::utils::ExitCode::InvalidInfoHash => {
+                    return Err(anyhow!("Invalid info hash"));
+                },
+                torrent_client::utils::ExitCode::TorrentNotFound => {
+                    return Err(anyhow!("No torrent with info hash {}", info_hash));
+                },
+                _ => {
+                    return Err(anyhow!("Received invalid status from client"));
                 }
             }
         },
@@ -142,14 +244,92 @@ async fn add(mut client: TerminalClient, src: &str, dest: &str) -> Result<()> {
     Ok(())
 }
 
-async fn list_torrents(mut torrent_client: TerminalClient) -> Result<()> {
+async fn set_picking_mode(mut client: TerminalClient, info_hash: &str, picking_mode: PickingMode) -> Result<()> {
+    client.send_message(&TerminalClientMessage::SetPickingMode{info_hash: info_hash.to_string(), picking_mode}).await?;
+
+    match client.recv_message().await? {
+        TerminalClientMessage::Status{exit_code} => {
+            match exit_code {
+                torrent_client::utils::ExitCode::SUCCESS => {
+                    println!("Downloading {}", picking_mode);
+                },
+                torrent_client::utils::ExitCode::InvalidInfoHash => {
+                    return Err(anyhow!("Invalid info hash"));
+                },
+                _ => {
+                    return Err(anyhow!("Received invalid status from client"));
+                }
+            }
+        },
+        _ => {
+            return Err(anyhow!("Received invalid message from client"));
+        }
+    };
+
+    Ok(())
+}
+
+async fn set_file_priorities(mut client: TerminalClient, info_hash: &str, file_priorities: Vec<(usize, FilePriority)>) -> Result<()> {
+    client.send_message(&TerminalClientMessage::SetFilePriorities{info_hash: info_hash.to_string(), file_priorities}).await?;
+
+    match client.recv_message().await? {
+        TerminalClientMessage::Status{exit_code} => {
+            match exit_code {
+                torrent_client::utils::ExitCode::SUCCESS => {
+                    println!("File priorities changed");
+                },
+                torrent_client::utils::ExitCode::InvalidInfoHash => {
+                    return Err(anyhow!("Invalid info hash"));
+                },
+                _ => {
+                    return Err(anyhow!("Received invalid status from client"));
+                }
+            }
+        },
+        _ => {
+            return Err(anyhow!("Received invalid message from client"));
+        }
+    };
+
+    Ok(())
+}
+
+/// Parses a comma separated list of `<file_index>=<priority>`.
+fn parse_file_priorities(arg: &str) -> Option<Vec<(usize, FilePriority)>> {
+    arg
+        .split(',')
+        .map(|file_priority| {
+            let (index, priority) = file_priority.split_once('=')?;
+            let priority = match priority {
+                "skip" => FilePriority::Skip,
+                "low" => FilePriority::Low,
+                "normal" => FilePriority::Normal,
+                "high" => FilePriority::High,
+                _ => return None
+            };
+
+            Some((index.parse().ok()?, priority))
+        })
+        .collect()
+}
+
+fn parse_picking_mode(args: &[String]) -> Option<PickingMode> {
+    match args {
+        [mode] if mode == "rarest" => Some(PickingMode::RarestFirst),
+        [mode] if mode == "sequential" => Some(PickingMode::Sequential { position: 0 }),
+        [mode, position] if mode == "sequential" => position.parse().ok().map(|position| PickingMode::Sequential { position }),
+        _ => None
+    }
+}
+
+async fn list_torrents(mut torrent_client: TerminalClient, show_peers: bool) -> Result<()> {
     println!("No torrent states...");
     loop {
         tokio::select! {
             message = torrent_client.recv_message() => {
                 match message? {
                     TerminalClientMessage::TorrentsInfo{torrents} => {
-                        print_torrent_infos(torrents);
+                        print_torrent_infos(torrents, show_peers);
                     },
                     _ => {
                         return Err(anyhow!("Received invalid message from client"));
@@ -203,32 +383,101 @@ async fn main() {
 
     match args[1].as_str() {
         "add" => {
-            if args.len() < 4 || args.len() > 4 {
+            if args.len() < 4 {
                 eprintln!("[Error] Invalid number of arguments provided");
-                println!("Usage: tttorrent add <torrent_path> <dest_path>");
+                println!("Usage: tttorrent add <torrent_path> <dest_path> [--sequential] [--priority <file_index>=<priority>,...]");
                 exit(1);
             }
 
-            if let Err(e) = add(terminal_client, &args[2], &args[3]).await {
+            let mut picking_mode = PickingMode::RarestFirst;
+            let mut file_priorities = Vec::new();
+            let mut options = args[4..].iter();
+            while let Some(option) = options.next() {
+                match option.as_str() {
+                    "--sequential" => picking_mode = PickingMode::Sequential { position: 0 },
+                    "--priority" => match options.next().and_then(|arg| parse_file_priorities(arg)) {
+                        Some(priorities) => file_priorities.extend(priorities),
+                        None => {
+                            eprintln!("[Error] Invalid file priorities provided");
+                            exit(1);
+                        }
+                    },
+                    _ => {
+                        eprintln!("[Error] Invalid option provided");
+                        exit(1);
+                    }
+                }
+            }
+
+            if let Err(e) = add(terminal_client, &args[2], &args[3], picking_mode, file_priorities).await {
                 eprintln!("Failed to add torrent: {}", e);
                 exit(1);
             }
         },
         "list" => {
-            if args.len() != 2 {
+            let show_peers = match args.get(2).map(|arg| arg.as_str()) {
+                None => false,
+                Some("--peers") if args.len() == 3 => true,
+                _ => {
+                    eprintln!("[Error] Invalid arguments provided");
+                    println!("Usage: tttorrent list [--peers]");
+
+                    exit(1);
+                }
+            };
+
+            if let Err(e) = terminal_client.send_message(&TerminalClientMessage::ListTorrents).await {
+                eprintln!("Failed to send list torrents message to client: {}", e);
+                exit(1);
+            }
+
+            if let Err(e) = list_torrents(terminal_client, show_peers).await {
+                eprintln!("Failed to list torrents: {}", e);
+                exit(1);
+            }
+        },
+        "recheck" => {
+            if args.len() != 3 {
                 eprintln!("[Error] Invalid number of arguments provided");
-                println!("Usage: tttorrent list");
+                println!("Usage: tttorrent recheck <info_hash>");
 
                 exit(1);
             }
 
-            if let Err(e) = terminal_client.send_message(&TerminalClientMessage::ListTorrents).await {
-                eprintln!("Failed to send list torrents message to client: {}", e);
+            if let Err(e) = recheck(terminal_client, &args[2]).await {
+                eprintln!("Failed to recheck torrent: {}", e);
+                exit(1);
+            }
+        },
+        "mode" => {
+            let picking_mode = match args.get(3..).and_then(parse_picking_mode) {
+                Some(picking_mode) => picking_mode,
+                None => {
+                    eprintln!("[Error] Invalid arguments provided");
+                    println!("Usage: tttorrent mode <info_hash> <rarest | sequential [position]>");
+
+                    exit(1);
+                }
+            };
+
+            if let Err(e) = set_picking_mode(terminal_client, &args[2], picking_mode).await {
+                eprintln!("Failed to change the picking mode: {}", e);
                 exit(1);
             }
+        },
+        "priority" => {
+            let file_priorities = match args.get(3).and_then(|arg| parse_file_priorities(arg)) {
+                Some(file_priorities) if args.len() == 4 => file_priorities,
+                _ => {
+                    eprintln!("[Error] Invalid arguments provided");
+                    println!("Usage: tttorrent priority <info_hash> <file_index>=<skip | low | normal | high>,...");
+
+                    exit(1);
+                }
+            };
 
-            if let Err(e) = list_torrents(terminal_client).await {
-                eprintln!("Failed to list torrents: {}", e);
+            if let Err(e) = set_file_priorities(terminal_client, &args[2], file_priorities).await {
+                eprintln!("Failed to change the file priorities: {}", e);
                 exit(1);
             }
         },
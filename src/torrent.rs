@@ -3,18 +3,19 @@ use tokio::sync::{mpsc, oneshot, Mutex, };
 use tokio::task::JoinHandle;
 use anyhow::{anyhow, Result, Context};
 
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::net::IpAddr;
 use std::sync::Arc;
 
 use crate::messager::ClientMessage;
-use crate::peer::block_picker::Piece;
-use crate::peer::{Block, BlockPicker, PeerAddress, PeerHandle, PeerSession, PeerTorrentContext};
-use crate::tracker::{Tracker, TrackerEvent};
+use crate::peer::{BlockPicker, PeerAddress, PeerHandle, PeerSession, PeerTorrentContext, PickingMode};
+use crate::peer::ut_pex::PEX_INTERVAL_SECS;
+use crate::dht::DHT_ANNOUNCE_INTERVAL_SECS;
+use crate::tracker::{SwarmStats, TrackerSet, TrackerEvent, ANNOUNCE_TIMEOUT_SECS, MIN_ANNOUNCE_GAP_SECS, SCRAPE_INTERVAL_SECS, STOPPED_ANNOUNCE_TIMEOUT_SECS};
 use crate::peer::peer_message::ConnectionType;
-use crate::disk_manager::{DiskManagerHandle, DiskTorrentContext};
+use crate::disk_manager::{DiskManagerHandle, DiskTorrentContext, DownloadableFile};
 use crate::utils::CommunicationPipe;
 use crate::utils::sha1hash::Sha1Hash;
-use crate::utils::bencode::BencodedValue;
 
 pub mod torrent_file;
 pub use torrent_file::TorrentFile;
@@ -26,18 +27,28 @@ pub mod torrent_state;
 pub use torrent_state::TorrentState;
 
 pub mod torrent_context;
-pub use torrent_context::TorrentContext;
+pub use torrent_context::{ConnectedPeer, PeerSource, TorrentContext, TorrentPeer};
+
+pub mod magnet;
+pub use magnet::MagnetLink;
+
+pub mod choker;
+use choker::{Choker, ChokerPeer, CHOKE_INTERVAL_SECS};
+
+pub mod file_priority;
+pub use file_priority::FilePriority;
 
 
 pub struct TorrentHandle {
     tx: mpsc::Sender<ClientMessage>,
     join_handle: JoinHandle<()>,
+    banned_peers: Arc<Mutex<Vec<PeerAddress>>>,
 
     pub torrent_info_hash: Sha1Hash,
 }
 
 impl TorrentHandle {
-    pub async fn new(client_id: [u8; 20], src: &str, dest: &str) -> Result<Self> {
+    pub async fn new(client_id: [u8; 20], src: &str, dest: &str, state_torrent_files_path: &str, dht_tx: Option<mpsc::Sender<ClientMessage>>) -> Result<Self> {
         // ---------------------- copy torrent file to state folder for redundancy ----------------------
         let src_path = std::path::Path::new(src);
         let torrent_name = src_path
@@ -46,8 +57,7 @@ impl TorrentHandle {
             .to_str()
             .unwrap();
 
-        let torrent_file_dest_path = unsafe { crate::CLIENT_OPTIONS.state_torrent_files_path.clone() };
-        let torrent_file_dest_path = std::path::Path::new(&torrent_file_dest_path).join(torrent_name);
+        let torrent_file_dest_path = std::path::Path::new(state_torrent_files_path).join(torrent_name);
         std::fs::create_dir_all(&torrent_file_dest_path.parent().unwrap())?;
         tokio::fs::copy(src_path, torrent_file_dest_path.clone()).await?;
 
@@ -60,7 +70,7 @@ impl TorrentHandle {
             rx: receiver,
         };
 
-        let torrent = match Torrent::new(client_id, pipe, src, dest).await {
+        let mut torrent = match Torrent::new(client_id, pipe, src, dest).await {
             Ok(torrent) => torrent,
             Err(e) => {
                 // remove torrent file from state folder
@@ -69,9 +79,55 @@ impl TorrentHandle {
             }
         };
 
+        torrent.dht_tx = dht_tx;
         let torrent_info_hash = torrent.torrent_context.info_hash.clone();
+        let banned_peers = Arc::clone(&torrent.torrent_context.banned_peers);
+
+        let join_handle = tokio::spawn(async move {
+            if let Err(e) = torrent.run().await {
+                eprintln!("Torrent error: {:?}", e);
+            }
+        });
+
+        Ok(Self {
+            tx: sender,
+            join_handle,
+            banned_peers,
+
+            torrent_info_hash,
+        })
+    }
+
+    pub async fn from_magnet(client_id: [u8; 20], magnet_link: &str, dest: &str, state_torrent_files_path: &str, dht_tx: Option<mpsc::Sender<ClientMessage>>) -> Result<Self> {
+        let magnet_link = MagnetLink::parse(magnet_link)?;
+        if magnet_link.trackers.is_empty() && dht_tx.is_none() {
+            return Err(anyhow!("Magnet link has no trackers to find peers with"));
+        }
 
+        let (sender, receiver) = mpsc::channel(unsafe { crate::CLIENT_OPTIONS.max_channel_size });
+        let pipe = CommunicationPipe {
+            tx: sender.clone(),
+            rx: receiver,
+        };
+
+        let torrent_info_hash = magnet_link.info_hash.clone();
+        let banned_peers = Arc::new(Mutex::new(Vec::new()));
+
+        let torrent_banned_peers = Arc::clone(&banned_peers);
+        let dest = dest.to_string();
+        let state_torrent_files_path = state_torrent_files_path.to_string();
         let join_handle = tokio::spawn(async move {
+            let mut torrent = match Torrent::from_magnet(client_id, pipe, magnet_link, &dest, &state_torrent_files_path, dht_tx.clone()).await {
+                Ok(Some(torrent)) => torrent,
+                Ok(None) => return,
+                Err(e) => {
+                    eprintln!("Torrent error: {:?}", e);
+                    return;
+                }
+            };
+            torrent.torrent_context.banned_peers = torrent_banned_peers;
+            torrent.dht_tx = dht_tx;
+
             if let Err(e) = torrent.run().await {
                 eprintln!("Torrent error: {:?}", e);
             }
@@ -80,21 +136,24 @@ impl TorrentHandle {
         Ok(Self {
             tx: sender,
             join_handle,
+            banned_peers,
 
             torrent_info_hash,
         })
     }
 
-    pub async fn from_state(client_id: [u8; 20], torrent_state: TorrentState, info_hash: Sha1Hash, connection_type: ConnectionType) -> Result<Self> {
+    pub async fn from_state(client_id: [u8; 20], torrent_state: TorrentState, info_hash: Sha1Hash, connection_type: ConnectionType, dht_tx: Option<mpsc::Sender<ClientMessage>>) -> Result<Self> {
         let (sender, receiver) = mpsc::channel(unsafe { crate::CLIENT_OPTIONS.max_channel_size });
         let pipe = CommunicationPipe {
             tx: sender.clone(),
             rx: receiver,
         };
 
-        let torrent = Torrent::from_state(client_id, pipe, torrent_state, info_hash, connection_type.clone()).await?;
+        let mut torrent = Torrent::from_state(client_id, pipe, torrent_state, info_hash, connection_type.clone()).await?;
 
+        torrent.dht_tx = dht_tx;
         let torrent_info_hash = torrent.torrent_context.info_hash.clone();
+        let banned_peers = Arc::clone(&torrent.torrent_context.banned_peers);
 
         let join_handle = tokio::spawn(async move {
             if let Err(e) = torrent.run().await {
@@ -105,6 +164,7 @@ impl TorrentHandle {
         Ok(Self {
             tx: sender,
             join_handle,
+            banned_peers,
 
             torrent_info_hash,
         })
@@ -125,6 +185,26 @@ impl TorrentHandle {
         Ok(())
     }
 
+    pub async fn recheck(&mut self) -> Result<()> {
+        self.tx.send(ClientMessage::Recheck{info_hash: self.torrent_info_hash.clone()}).await?;
+        Ok(())
+    }
+
+    pub async fn set_picking_mode(&mut self, picking_mode: PickingMode) -> Result<()> {
+        self.tx.send(ClientMessage::SetPickingMode{info_hash: self.torrent_info_hash.clone(), picking_mode}).await?;
+        Ok(())
+    }
+
+    /// Changes the priority of the files at the given indexes, the other files keep theirs.
+    pub async fn set_file_priorities(&mut self, file_priorities: Vec<(usize, FilePriority)>) -> Result<()> {
+        self.tx.send(ClientMessage::SetFilePriorities{info_hash: self.torrent_info_hash.clone(), file_priorities}).await?;
+        Ok(())
+    }
+
+    pub async fn is_banned(&self, peer_address: &PeerAddress) -> bool {
+        torrent_context::is_banned(&self.banned_peers, peer_address).await
+    }
+
     pub async fn add_peer_session(&mut self, peer_session: PeerSession) -> Result<()> {
         self.tx.send(ClientMessage::AddPeerSession{peer_session}).await?;
         Ok(())
@@ -137,8 +217,20 @@ struct Torrent {
     rx: mpsc::Receiver<ClientMessage>,
     peer_handles: Vec<PeerHandle>,
     disk_handle: DiskManagerHandle,
+    dht_tx: Option<mpsc::Sender<ClientMessage>>,
     
     torrent_context: TorrentContext,
+    files: Vec<DownloadableFile>,
+    /// Failed pieces every ip contributed to, unlike the bans they aren't saved and start over with the session.
+    hash_fail_strikes: HashMap<IpAddr, u32>,
+    choker: Choker,
+    has_existing_data: bool,
+    /// Whether every piece of the wanted files is downloaded.
+    finished: bool,
+    /// The trackers are away in an announce or scrape task.
+    trackers_busy: bool,
+    /// The torrent completed while the trackers were busy, they hear it once they're back.
+    completed_pending: bool,
     client_id: [u8; 20],
 }
 
@@ -157,29 +249,7 @@ impl Torrent {
 
         let info_hash = TorrentFile::get_info_hash(torrent_file.get_bencoded_dict_ref())?;
 
-        // not downloaded piece indexes
-        let pieces_left = {
-            let torrent_dict = torrent_file.get_bencoded_dict_ref().try_into_dict()?;
-            let info_dict = match torrent_dict.get(&b"info".to_vec()) {
-                Some(info_dict) => info_dict,
-                None => return Err(anyhow!("Could not get info dict from torrent file ref: {}", torrent_name))
-            };
-            let pieces = info_dict.get_from_dict(b"pieces")?;
-
-            let pieces = match pieces {
-                BencodedValue::ByteSha1Hashes(pieces) => pieces,
-                _ => return Err(anyhow!("Could not get pieces from info dict ref in torrent file: {}", torrent_name))
-            };
-
-            (0u32..pieces.len() as u32)
-                .map(|index| (index, Piece {
-                    index,
-                    block_count: torrent_info.get_specific_piece_block_count(index),
-                })).collect::<HashMap<u32, Piece>>()
-                .into_values().collect::<Vec<Piece>>()
-        };
-
-        let pieces_count = pieces_left.len();
+        let pieces_count = torrent_info.pieces_count;
 
         let downloaded = Arc::new(Mutex::new(0));
         let uploaded = Arc::new(Mutex::new(0));
@@ -193,9 +263,15 @@ impl Torrent {
             Arc::clone(&torrent_info),
         )?;
 
-        let disk_handle = DiskManagerHandle::new(torrent_context);
+        // files from a previous download or copied by hand have to be checked before using them
+        let has_existing_data = torrent_context.files
+            .iter()
+            .any(|file| std::path::Path::new(dest).join(&file.path).is_file());
+        let files = torrent_context.files.clone();
+
+        let disk_handle = DiskManagerHandle::new(torrent_context, &[], Vec::new());
 
-        let needed = BlockPicker::new(pieces_left, Arc::clone(&torrent_info));
+        let needed = BlockPicker::new(Arc::clone(&torrent_info));
 
         let torrent_context = TorrentContext {
             connection_type: ConnectionType::Outgoing,
@@ -206,13 +282,21 @@ impl Torrent {
             torrent_file,
             info_hash,
             needed: Arc::new(Mutex::new(needed)),
+            file_priorities: Vec::new(),
             bitfield: Arc::new(Mutex::new(vec![0; pieces_count.div_ceil(8)])),
             peers: Vec::new(),
+            piece_contributors: Arc::new(Mutex::new(HashMap::new())),
+            banned_peers: Arc::new(Mutex::new(Vec::new())),
 
             torrent_info,
 
             downloaded,
             uploaded,
+            hash_fails: 0,
+            md5_checks: BTreeMap::new(),
+            bad_md5sums: Vec::new(),
+            swarm_stats: SwarmStats::default(),
+            tracker_statuses: Vec::new(),
         };
 
         Ok(Self {
@@ -223,30 +307,94 @@ impl Torrent {
             disk_handle,
 
             torrent_context,
+            files,
+            hash_fail_strikes: HashMap::new(),
+            choker: Choker::new(),
+            has_existing_data,
+            finished: false,
+            trackers_busy: false,
+            completed_pending: false,
+            dht_tx: None,
             client_id,
         })
     }
 
 
+    /// Fetches the metadata of a magnet link from peers and creates the torrent from it.
+    /// Returns `None` if the torrent was shut down before the metadata arrived.
+    pub async fn from_magnet(client_id: [u8; 20], mut self_pipe: CommunicationPipe, magnet_link: MagnetLink, dest: &str, state_torrent_files_path: &str, dht_tx: Option<mpsc::Sender<ClientMessage>>) -> Result<Option<Self>> {
+        tracing::info!("Fetching metadata for magnet link with info hash {}", magnet_link.info_hash.to_hex());
+
+        let fetch_info_dict = magnet_link.fetch_info_dict(client_id, dht_tx);
+        tokio::pin!(fetch_info_dict);
+
+        // the picking mode and the file priorities can be chosen before the pieces are known
+        let mut picking_mode = PickingMode::default();
+        let mut file_priorities = Vec::new();
+
+        // saved so a restart fetches the metadata again
+        Torrent::write_state(&magnet_link.info_hash, TorrentState::from_magnet(&magnet_link, dest, picking_mode)).await?;
+
+        let info_dict = loop {
+            tokio::select! {
+                Some(msg) = self_pipe.rx.recv() => {
+                    match msg {
+                        ClientMessage::Shutdown => return Ok(None),
+                        ClientMessage::SetPickingMode { picking_mode: new_picking_mode, .. } => {
+                            picking_mode = new_picking_mode;
+                            Torrent::write_state(&magnet_link.info_hash, TorrentState::from_magnet(&magnet_link, dest, picking_mode)).await?;
+                        },
+                        ClientMessage::SetFilePriorities { file_priorities: new_file_priorities, .. } => file_priorities.extend(new_file_priorities),
+                        ClientMessage::SendTorrentInfo { tx } => {
+                            if tx.send(TorrentState::from_magnet(&magnet_link, dest, picking_mode)).is_err() {
+                                tracing::error!("Failed to send torrent state of magnet link to client");
+                            }
+                        },
+                        // peers and rechecks have to wait for the metadata
+                        _ => continue,
+                    }
+                },
+                info_dict = &mut fetch_info_dict => break info_dict?,
+            }
+        };
+
+        let src = magnet_link.write_torrent_file(&info_dict, state_torrent_files_path).await?;
+        tracing::info!("Fetched metadata for magnet link, saved it to '{}'", src);
+
+        let mut torrent = Torrent::new(client_id, self_pipe, &src, dest).await?;
+        torrent.torrent_context.needed.lock().await.picking_mode = picking_mode;
+        if !file_priorities.is_empty() {
+            torrent.set_file_priorities(file_priorities).await;
+        }
+
+        Ok(Some(torrent))
+    }
+
     pub async fn from_state(client_id: [u8; 20], self_pipe: CommunicationPipe, torrent_state: TorrentState, info_hash: Sha1Hash, connection_type: ConnectionType) -> Result<Self> {
         let torrent_file_path = format!("{}/{}.torrent", unsafe { crate::CLIENT_OPTIONS.state_torrent_files_path.clone() }, torrent_state.torrent_name);
         let path = std::path::Path::new(&torrent_file_path);
 
         let torrent_file = TorrentFile::new(path).await.context("couldn't create TorrentFile")?;
-        
+
+        let dest_path = torrent_state.dest_path.clone();
+        let torrent_name = torrent_state.torrent_name.clone();
+        let torrent_context = TorrentContext::from_state(torrent_state, info_hash, connection_type).await?;
+
         let disk_torrent_context = DiskTorrentContext::new(
             self_pipe.tx.clone(),
-            torrent_state.dest_path.clone(),
-            torrent_state.torrent_name.clone(),
+            dest_path,
+            torrent_name,
             Arc::new(torrent_file),
-            Arc::new(Mutex::new(torrent_state.downloaded)),
-            Arc::new(torrent_state.torrent_info.clone()),
+            Arc::clone(&torrent_context.downloaded),
+            Arc::clone(&torrent_context.torrent_info),
         )?;
+        let files = disk_torrent_context.files.clone();
         
-        let disk_handle = DiskManagerHandle::new(disk_torrent_context);
+        let bitfield = torrent_context.bitfield.lock().await.clone();
+        let written_pieces = torrent_context.needed.lock().await.written_pieces();
+        let disk_handle = DiskManagerHandle::new(disk_torrent_context, &bitfield, written_pieces);
         
-        let torrent_context = TorrentContext::from_state(torrent_state, info_hash, connection_type).await?;
-        Ok(Self {
+        let mut torrent = Self {
             self_tx: self_pipe.tx,
 
             rx: self_pipe.rx,
@@ -254,11 +402,34 @@ impl Torrent {
             disk_handle,
 
             torrent_context,
+            files,
+            hash_fail_strikes: HashMap::new(),
+            choker: Choker::new(),
+            has_existing_data: false,
+            finished: false,
+            trackers_busy: false,
+            completed_pending: false,
+            dht_tx: None,
             client_id,
-        })
+        };
+
+        // the priorities of the pieces aren't saved, they follow from the files
+        if !torrent.torrent_context.file_priorities.is_empty() {
+            torrent.set_file_priorities(Vec::new()).await;
+        }
+
+        Ok(torrent)
     }   
 
     async fn save_state(torrent_context: TorrentContext) -> Result<()> {
+        let torrent_info_hash = torrent_context.info_hash.clone();
+        let torrent_state = TorrentState::new(torrent_context).await;
+
+        Torrent::write_state(&torrent_info_hash, torrent_state).await
+    }
+
+    /// Replaces the entry of the torrent in the client state file.
+    async fn write_state(torrent_info_hash: &Sha1Hash, torrent_state: TorrentState) -> Result<()> {
         let state_file = unsafe { crate::CLIENT_OPTIONS.state_file_path.clone() };
         let state_file = std::path::Path::new(&state_file);
         let client_state = match tokio::fs::read_to_string(state_file).await {
@@ -270,12 +441,9 @@ impl Torrent {
             _ => serde_json::from_str(&client_state).unwrap(), // client state is always valid json
         };
         
-        let torrent_info_hash = torrent_context.info_hash.clone();
-        let torrent_context = TorrentState::new(torrent_context).await;
+        let torrent_state = serde_json::to_value(torrent_state).unwrap(); // torrent state is always valid json
         
-        let torrent_context = serde_json::to_value(torrent_context).unwrap(); // torrent context is always valid json
-        
-        client_state[torrent_info_hash.to_hex()] = torrent_context;
+        client_state[torrent_info_hash.to_hex()] = torrent_state;
 
         let client_state = serde_json::to_string_pretty(&client_state).unwrap(); // client state is always valid json
 
@@ -284,32 +452,43 @@ impl Torrent {
         Ok(())
     }
 
-    async fn add_new_peers(&mut self, peer_addresses: Vec<PeerAddress>, connection_type: ConnectionType) -> Result<()> {
+    fn peer_torrent_context(&self) -> PeerTorrentContext {
+        PeerTorrentContext::new(
+            self.self_tx.clone(),
+            Arc::clone(&self.torrent_context.torrent_info),
+            self.torrent_context.info_hash.clone(),
+            Arc::clone(&self.torrent_context.needed),
+            Arc::clone(&self.torrent_context.bitfield),
+            Arc::clone(&self.torrent_context.piece_contributors),
+            Arc::clone(&self.torrent_context.uploaded),
+        )
+    }
+
+    async fn add_new_peers(&mut self, peer_addresses: Vec<PeerAddress>, connection_type: ConnectionType, source: PeerSource) -> Result<()> {
         let old_peer_addresses = self.peer_handles
             .iter()
             .map(|peer_handle| peer_handle.peer_address.clone())
             .collect::<Vec<PeerAddress>>();
 
-        let peer_addresses = peer_addresses
-            .iter()
-            .filter(|peer_address| !old_peer_addresses.contains(peer_address))
-            .cloned()
-            .collect::<Vec<PeerAddress>>();
+        let mut new_peer_addresses = Vec::new();
+        for peer_address in peer_addresses {
+            if old_peer_addresses.contains(&peer_address) || self.torrent_context.is_banned(&peer_address).await {
+                continue;
+            }
+            new_peer_addresses.push(peer_address);
+        }
+        let peer_addresses = new_peer_addresses;
 
         let mut peer_addresses_iter = tokio_stream::iter(peer_addresses);
         while let Some(peer_address) = peer_addresses_iter.next().await {
-            if !self.torrent_context.peers.contains(&peer_address) {
-                self.torrent_context.peers.push(peer_address.clone());
+            if !self.torrent_context.has_peer(&peer_address) {
+                self.torrent_context.peers.push(TorrentPeer {
+                    address: peer_address.clone(),
+                    source,
+                });
             }
 
-            let torrent_context = PeerTorrentContext::new(
-                self.self_tx.clone(),
-                Arc::clone(&self.torrent_context.torrent_info),
-                self.torrent_context.info_hash.clone(),
-                Arc::clone(&self.torrent_context.needed),
-                Arc::clone(&self.torrent_context.bitfield),
-                Arc::clone(&self.torrent_context.uploaded)
-            );
+            let torrent_context = self.peer_torrent_context();
 
             let peer_handle = PeerHandle::new(
                 self.client_id,
@@ -325,6 +504,171 @@ impl Torrent {
         Ok(())
     }
 
+    /// Replaces the bitfield and the state of the block picker with the verified `pieces`.
+    /// Returns the pieces that weren't in the old bitfield.
+    async fn rebuild_from_pieces(&mut self, pieces: &[u32]) -> Vec<u32> {
+        let torrent_info = &self.torrent_context.torrent_info;
+
+        let mut bitfield = vec![0u8; torrent_info.pieces_count.div_ceil(8)];
+        for piece in pieces {
+            bitfield[*piece as usize / 8] |= 1 << (7 - piece % 8);
+        }
+
+        self.torrent_context.needed.lock().await.rebuild(&bitfield);
+        self.torrent_context.piece_contributors.lock().await.clear();
+
+        let mut bitfield_guard = self.torrent_context.bitfield.lock().await;
+        let new_pieces = pieces
+            .iter()
+            .filter(|piece| bitfield_guard[**piece as usize / 8] & 1 << (7 - *piece % 8) == 0)
+            .copied()
+            .collect();
+        *bitfield_guard = bitfield;
+
+        new_pieces
+    }
+
+    /// Changes the priority of the listed files and hands the resulting piece priorities to the block picker.
+    async fn set_file_priorities(&mut self, file_priorities: Vec<(usize, FilePriority)>) {
+        self.torrent_context.file_priorities.resize(self.files.len(), FilePriority::default());
+        for (index, priority) in file_priorities {
+            match self.torrent_context.file_priorities.get_mut(index) {
+                Some(file_priority) => *file_priority = priority,
+                None => tracing::warn!("Torrent '{}' has no file {}", self.torrent_context.torrent_name, index),
+            }
+        }
+
+        let torrent_info = &self.torrent_context.torrent_info;
+        let piece_priorities = file_priority::piece_priorities(&self.files, &self.torrent_context.file_priorities, torrent_info.pieces_count, torrent_info.piece_length);
+        self.torrent_context.needed.lock().await.set_piece_priorities(piece_priorities);
+    }
+
+    /// Whether every piece with data of a wanted file is downloaded.
+    async fn is_finished(&self) -> bool {
+        let needed_guard = self.torrent_context.needed.lock().await;
+
+        (0..self.torrent_context.torrent_info.pieces_count as u32)
+            .all(|index| !needed_guard.is_wanted(index) || needed_guard.is_verified(index))
+    }
+
+    /// Tells the trackers the torrent is complete when the last wanted piece arrives or the files still missing get skipped.
+    async fn check_finished(&mut self, trackers: &Option<TrackerSet>) {
+        let finished = self.is_finished().await;
+        if finished && !self.finished {
+            tracing::info!("Finished downloading torrent '{}'", self.torrent_context.torrent_name);
+            self.announce(trackers, true).await;
+        }
+        self.finished = finished;
+    }
+
+    /// Hashes the data that is already at the destination so the verified pieces don't get downloaded again.
+    /// Returns the messages to handle once the torrent runs, or None if it was shut down during the check.
+    async fn check_existing_data(&mut self) -> Result<Option<VecDeque<ClientMessage>>> {
+        tracing::info!("Found existing data for torrent '{}', checking it", self.torrent_context.torrent_name);
+        self.disk_handle.recheck(self.torrent_context.info_hash.clone()).await?;
+
+        // the client still gets answers while checking, everything else waits for the check to finish
+        let mut postponed_messages = VecDeque::new();
+        while let Some(msg) = self.rx.recv().await {
+            match msg {
+                ClientMessage::Rechecked { pieces } => {
+                    tracing::info!("Found {} of {} pieces at the destination", pieces.len(), self.torrent_context.torrent_info.pieces_count);
+                    self.rebuild_from_pieces(&pieces).await;
+                    break;
+                },
+                ClientMessage::SendTorrentInfo { tx } => self.send_torrent_info(tx).await,
+                ClientMessage::PeerDisconnected { peer_address } => self.remove_peer_handle(&peer_address).await,
+                ClientMessage::Shutdown => {
+                    tracing::info!("Shutting down torrent '{}' while checking its data", self.torrent_context.torrent_name);
+                    self.disk_handle.shutdown().await?;
+                    return Ok(None);
+                },
+                msg => postponed_messages.push_back(msg),
+            }
+        }
+
+        Ok(Some(postponed_messages))
+    }
+
+    /// The messages postponed during the existing data check come before the new ones.
+    async fn next_message(postponed_messages: &mut VecDeque<ClientMessage>, rx: &mut mpsc::Receiver<ClientMessage>) -> Option<ClientMessage> {
+        match postponed_messages.pop_front() {
+            Some(msg) => Some(msg),
+            None => rx.recv().await,
+        }
+    }
+
+    async fn send_torrent_info(&self, tx: oneshot::Sender<TorrentState>) {
+        let mut torrent_state = TorrentState::new(self.torrent_context.clone()).await;
+        torrent_state.connected_peers = self.connected_peers().await;
+        if let Err(e) = tx.send(torrent_state) {
+            tracing::error!("Failed to send torrent context to client: {:?}", e);
+        }
+    }
+
+    async fn remove_peer_handle(&mut self, peer_address: &PeerAddress) {
+        self.torrent_context.remove_peer(peer_address);
+        self.choker.remove_peer(peer_address);
+
+        let handle_index = self.peer_handles.iter().position(|peer_handle| &peer_handle.peer_address == peer_address);
+        if let Some(handle_index) = handle_index {
+            if let Err(e) = self.peer_handles.remove(handle_index).join().await {
+                tracing::debug!("Peer '{}' disconnected with an error: {}", peer_address, e);
+            }
+        }
+    }
+
+    async fn blame_piece_contributors(&mut self, piece: u32) -> Result<()> {
+        let contributors = self.torrent_context.piece_contributors.lock().await.remove(&piece).unwrap_or_default();
+
+        for peer_address in contributors {
+            let strikes = self.hash_fail_strikes.entry(peer_address.ip()).or_insert(0);
+            *strikes += 1;
+
+            tracing::debug!("Peer '{}' contributed to a failed piece, strikes: {}", peer_address, strikes);
+            if *strikes >= unsafe { crate::CLIENT_OPTIONS.hash_fail_ban_threshold } {
+                self.ban_peer(peer_address).await?;
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn ban_peer(&mut self, peer_address: PeerAddress) -> Result<()> {
+        if self.torrent_context.is_banned(&peer_address).await {
+            return Ok(());
+        }
+
+        tracing::warn!("Banning peer '{}' for sending corrupt data", peer_address);
+        self.torrent_context.banned_peers.lock().await.push(peer_address.clone());
+        self.torrent_context.peers.retain(|peer| peer.address.ip() != peer_address.ip());
+
+        // disconnect every connection coming from the banned ip
+        let (banned_handles, peer_handles) = std::mem::take(&mut self.peer_handles)
+            .into_iter()
+            .partition::<Vec<PeerHandle>, _>(|peer_handle| peer_handle.peer_address.ip() == peer_address.ip());
+        self.peer_handles = peer_handles;
+        for peer_handle in &banned_handles {
+            self.choker.remove_peer(&peer_handle.peer_address);
+        }
+
+        // a peer can be waiting on the torrent channel, waiting on it here would never end
+        tokio::spawn(async move {
+            for mut peer_handle in banned_handles {
+                let peer_address = peer_handle.peer_address.clone();
+                if let Err(e) = peer_handle.shutdown().await {
+                    tracing::warn!("Failed to send shutdown message to peer {}: {}", peer_address, e);
+                }
+
+                if let Err(e) = peer_handle.join().await {
+                    tracing::debug!("Banned peer '{}' exited with: {}", peer_address, e);
+                }
+            }
+        });
+
+        Ok(())
+    }
+
     pub fn load_state(&mut self) {
 
         // I could try to connect to the peers from the previous session but I don't think it's worth it
@@ -335,42 +679,229 @@ impl Torrent {
         self.torrent_context.peers.clear();
     }
 
-    async fn connect_to_peers(&mut self, tracker: &mut Tracker) -> Result<()> {
-        let peer_addresses = match unsafe { crate::CLIENT_OPTIONS.debug_mode } {
-            true => {
-                vec![PeerAddress{address: "127.0.0.1".to_string(), port: "51413".to_string()}, PeerAddress{address: "192.168.0.24".to_string(), port: "51413".to_string()}]
-            },
-            false => {
-                let tracker_response = match self.torrent_context.needed.lock().await.pieces.len() == self.torrent_context.torrent_info.pieces_count {
-                    true => tracker.response(self.client_id, &self.torrent_context, TrackerEvent::Started).await?,
-                    false => tracker.response(self.client_id, &self.torrent_context, TrackerEvent::None).await?,
-                };
-                PeerAddress::from_tracker_response(tracker_response).await?
-            }
+    /// Announces to the trackers that are due, or that the torrent is completed, in a task of its own so slow trackers don't hold up the torrent.
+    /// The trackers come back with the peers they returned in `ClientMessage::TrackersUpdated`, a completed event meanwhile is sent after that.
+    async fn announce(&mut self, trackers: &Option<TrackerSet>, completed: bool) {
+        let debug_mode = unsafe { crate::CLIENT_OPTIONS.debug_mode };
+        let mut trackers = match trackers {
+            Some(trackers) if !(debug_mode && completed) => trackers.clone(),
+            _ => return
         };
 
-        let peer_addresses = peer_addresses.into_iter().rev().take(10).collect();
+        if self.trackers_busy {
+            self.completed_pending |= completed;
+            return;
+        }
+        self.trackers_busy = true;
 
-        self.add_new_peers(peer_addresses, self.torrent_context.connection_type.clone()).await?;
+        // seeds keep announcing so trackers hand them out to leechers
+        let tracker_event = match completed {
+            true => TrackerEvent::Completed,
+            false if crate::utils::is_zero_aligned(&self.torrent_context.bitfield.lock().await) => TrackerEvent::Started,
+            false => TrackerEvent::None,
+        };
+        let client_id = self.client_id;
+        let torrent_context = self.torrent_context.clone();
+        let numwant = self.wanted_peers() as u32;
+        let self_tx = self.self_tx.clone();
+
+        tokio::spawn(async move {
+            let peer_addresses = match debug_mode {
+                true => {
+                    vec![PeerAddress(([127, 0, 0, 1], 51413).into()), PeerAddress(([192, 168, 0, 24], 51413).into())]
+                },
+                false => {
+                    let timeout = std::time::Duration::from_secs(ANNOUNCE_TIMEOUT_SECS);
+                    let tracker_responses = match completed {
+                        true => trackers.response(client_id, &torrent_context, tracker_event, numwant, timeout).await,
+                        false => trackers.due_response(client_id, &torrent_context, tracker_event, numwant, timeout).await,
+                    };
+
+                    // merge the peers of every tier, the same peer is often known by several trackers
+                    let mut peer_addresses = Vec::new();
+                    let tracker_responses = match tracker_responses {
+                        Ok(tracker_responses) => tracker_responses,
+                        Err(e) => {
+                            tracing::error!("Failed to announce to the trackers: {}", e);
+                            Vec::new()
+                        }
+                    };
+                    for tracker_response in tracker_responses {
+                        match PeerAddress::from_tracker_response(tracker_response).await {
+                            Ok(tier_peer_addresses) => {
+                                for peer_address in tier_peer_addresses {
+                                    if !peer_addresses.contains(&peer_address) {
+                                        peer_addresses.push(peer_address);
+                                    }
+                                }
+                            },
+                            Err(e) => tracing::warn!("Invalid tracker response: {}", e),
+                        }
+                    }
+                    peer_addresses
+                }
+            };
 
-        Ok(())
+            if let Err(e) = self_tx.send(ClientMessage::TrackersUpdated { trackers, peer_addresses }).await {
+                tracing::debug!("Torrent stopped before the trackers answered: {}", e);
+            }
+        });
     }
 
-    pub async fn tracker_stopped(&mut self, tracker: &mut Tracker) -> Result<()> {
-        if !unsafe { crate::CLIENT_OPTIONS.debug_mode } {
-            tracker.response(self.client_id, &self.torrent_context, TrackerEvent::Stopped).await.context("couldn't get tracker response")?;
+    /// Connects to the peers the trackers returned, a seed doesn't need them.
+    async fn connect_to_peers(&mut self, peer_addresses: Vec<PeerAddress>) -> Result<()> {
+        if self.torrent_context.needed.lock().await.is_empty() {
+            return Ok(());
         }
 
+        // trackers don't have to respect numwant
+        let peer_addresses = peer_addresses.into_iter().take(self.wanted_peers()).collect();
+
+        self.add_new_peers(peer_addresses, self.torrent_context.connection_type.clone(), PeerSource::Tracker).await?;
+
         Ok(())
     }
 
-    async fn tracker_completed(&mut self, tracker: &mut Tracker) -> Result<()> {
+    /// How many more peers the torrent can connect to.
+    fn wanted_peers(&self) -> usize {
+        unsafe { crate::CLIENT_OPTIONS.max_peers_per_torrent }.saturating_sub(self.peer_handles.len())
+    }
+
+    /// Copies the swarm stats and the state of every tracker into the context for the UI.
+    fn update_tracker_statuses(&mut self, trackers: &TrackerSet) {
+        if let Some(swarm_stats) = trackers.get_swarm_stats() {
+            self.torrent_context.swarm_stats = swarm_stats;
+        }
+        self.torrent_context.tracker_statuses = trackers.get_statuses();
+    }
+
+    /// When the trackers are announced to next, never sooner than `MIN_ANNOUNCE_GAP_SECS` from now.
+    fn next_announce(trackers: &Option<TrackerSet>) -> tokio::time::Instant {
+        let earliest = std::time::Instant::now() + std::time::Duration::from_secs(MIN_ANNOUNCE_GAP_SECS);
+        let next_announce = match trackers {
+            Some(trackers) => trackers.get_next_announce().max(earliest),
+            None => earliest
+        };
+
+        tokio::time::Instant::from_std(next_announce)
+    }
+
+    /// Asks the DHT for peers and announces that we are downloading or seeding the torrent.
+    async fn find_dht_peers(&mut self) {
+        // private torrents must only get their peers from the tracker
+        if self.torrent_context.torrent_info.private {
+            return;
+        }
+
+        if let Some(dht_tx) = &self.dht_tx {
+            let message = ClientMessage::FindDhtPeers {
+                info_hash: self.torrent_context.info_hash.clone(),
+                port: Some(unsafe { crate::CLIENT_OPTIONS.listening_port }),
+                tx: self.self_tx.clone(),
+            };
+
+            if let Err(e) = dht_tx.send(message).await {
+                tracing::warn!("Failed to send find peers message to the DHT: {}", e);
+            }
+        }
+    }
+
+    /// Tells the peers the choker picked this round to choke or unchoke.
+    async fn rechoke(&mut self) {
+        let mut peers = Vec::new();
+        for peer_handle in &self.peer_handles {
+            let stats = *peer_handle.stats.lock().await;
+            peers.push(ChokerPeer {
+                address: peer_handle.peer_address.clone(),
+                interested: stats.interested,
+                downloaded: stats.downloaded,
+                uploaded: stats.uploaded,
+            });
+        }
+
+        let seeding = self.torrent_context.needed.lock().await.is_empty();
+        let (to_choke, to_unchoke) = self.choker.rechoke(&peers, seeding);
+
+        for peer_handle in &mut self.peer_handles {
+            let result = match (to_choke.contains(&peer_handle.peer_address), to_unchoke.contains(&peer_handle.peer_address)) {
+                (true, _) => peer_handle.choke().await,
+                (_, true) => peer_handle.unchoke().await,
+                _ => continue
+            };
+
+            if let Err(e) = result {
+                tracing::warn!("Failed to send choke decision to peer {}: {}", peer_handle.peer_address, e);
+            }
+        }
+    }
+
+    async fn connected_peers(&self) -> Vec<ConnectedPeer> {
+        let mut connected_peers = Vec::new();
+        for peer_handle in &self.peer_handles {
+            let stats = *peer_handle.stats.lock().await;
+            connected_peers.push(ConnectedPeer {
+                address: peer_handle.peer_address.clone(),
+                snubbed: stats.snubbed,
+                queue_depth: stats.queue_depth,
+                download_rate: stats.download_rate,
+            });
+        }
+
+        connected_peers
+    }
+
+    /// Sends the peers we connected to ourselves to every peer, ut_pex only forwards what changed.
+    async fn advertise_peers(&mut self) {
+        let connectable_peers = self.torrent_context.peers
+            .iter()
+            .filter(|peer| peer.source != PeerSource::Incoming)
+            .map(|peer| peer.address.clone())
+            .collect::<Vec<PeerAddress>>();
+
+        for peer_handle in &mut self.peer_handles {
+            let peers = connectable_peers
+                .iter()
+                .filter(|peer| **peer != peer_handle.peer_address)
+                .cloned()
+                .collect();
+
+            if let Err(e) = peer_handle.advertise_peers(peers).await {
+                tracing::warn!("Failed to send peers to advertise to peer {}: {}", peer_handle.peer_address, e);
+            }
+        }
+    }
+
+    /// Scrapes the trackers in a task of its own, they come back in `ClientMessage::TrackersUpdated`.
+    fn scrape_trackers(&mut self, trackers: &Option<TrackerSet>) {
+        if unsafe { crate::CLIENT_OPTIONS.debug_mode } || self.trackers_busy {
+            return;
+        }
+
+        let mut trackers = match trackers {
+            Some(trackers) => trackers.clone(),
+            None => return
+        };
+        self.trackers_busy = true;
+
+        let info_hash = self.torrent_context.info_hash.clone();
+        let self_tx = self.self_tx.clone();
+        tokio::spawn(async move {
+            trackers.scrape(&info_hash, std::time::Duration::from_secs(ANNOUNCE_TIMEOUT_SECS)).await;
+
+            if let Err(e) = self_tx.send(ClientMessage::TrackersUpdated { trackers, peer_addresses: Vec::new() }).await {
+                tracing::debug!("Torrent stopped before the trackers answered: {}", e);
+            }
+        });
+    }
+
+    pub async fn tracker_stopped(&mut self, trackers: &mut TrackerSet) -> Result<()> {
         if !unsafe { crate::CLIENT_OPTIONS.debug_mode } {
-            tracker.response(self.client_id, &self.torrent_context, TrackerEvent::Completed).await.context("couldn't get tracker response")?;
+            let timeout = std::time::Duration::from_secs(STOPPED_ANNOUNCE_TIMEOUT_SECS);
+            trackers.response(self.client_id, &self.torrent_context, TrackerEvent::Stopped, 0, timeout).await.context("couldn't get tracker response")?;
         }
 
         Ok(())
-    }   
+    }
 
     #[tracing::instrument(
         name = "Torrent::run",
@@ -384,41 +915,60 @@ impl Torrent {
     pub async fn run(mut self) -> Result<()> {
         self.load_state();
 
+        let mut postponed_messages = VecDeque::new();
+        if self.has_existing_data {
+            match self.check_existing_data().await {
+                Ok(Some(messages)) => postponed_messages = messages,
+                Ok(None) => {
+                    self.disk_handle.join().await?;
+                    Torrent::save_state(self.torrent_context).await?;
+                    return Ok(());
+                },
+                Err(e) => tracing::error!("Failed to check existing data: {}", e),
+            }
+
+            // the existing data can be the whole torrent, the trackers hear it's completed after the first announce
+            if self.is_finished().await {
+                postponed_messages.push_front(ClientMessage::FinishedDownloading);
+            }
+        } else {
+            self.finished = self.is_finished().await;
+        }
+
         let mut save_state_interval = tokio::time::interval(std::time::Duration::from_secs(unsafe { crate::CLIENT_OPTIONS.save_state_interval_secs }));
 
-        // ------------------------------ create tracker --------------------------------
-        let mut tracker = match Tracker::from_torrent_file(&self.torrent_context.torrent_file) {
-            Ok(tracker) => Some(tracker),
+        // ------------------------------ create trackers --------------------------------
+        let mut trackers = match TrackerSet::from_torrent_file(&self.torrent_context.torrent_file) {
+            Ok(trackers) => Some(trackers),
             Err(e) => {
-                tracing::error!("Failed to create tracker: {}", e);
+                tracing::error!("Failed to create trackers: {}", e);
                 None
             }
         };
         
         // ------------------------------ connect to peers --------------------------------
-        if let Some(ref mut tracker) = tracker {
-            // connect to peers from tracker response
-            if let Err(e) = self.connect_to_peers(tracker).await {
-                tracing::error!("Failed to connect to peers: {}", e);
-            }
-        }
-
-        let interval = tracker.as_ref().map(|tracker| tracker.get_interval()).unwrap_or(unsafe { crate::CLIENT_OPTIONS.tracker_regular_request_interval_secs });
-        let mut find_new_peers_interval = tokio::time::interval(std::time::Duration::from_secs(interval));
+        // the peers from the tracker responses arrive in a message
+        self.announce(&trackers, false).await;
+
+        let next_announce = tokio::time::sleep_until(Torrent::next_announce(&trackers));
+        tokio::pin!(next_announce);
+        let mut pex_interval = tokio::time::interval(std::time::Duration::from_secs(PEX_INTERVAL_SECS));
+        let mut dht_interval = tokio::time::interval(std::time::Duration::from_secs(DHT_ANNOUNCE_INTERVAL_SECS));
+        let mut scrape_interval = tokio::time::interval(std::time::Duration::from_secs(SCRAPE_INTERVAL_SECS));
+        let mut choke_interval = tokio::time::interval(std::time::Duration::from_secs(CHOKE_INTERVAL_SECS));
         
         // ------------------------------ main loop --------------------------------
-        let mut end_game_blocks: Vec<Block> = Vec::new();
         loop {
             tokio::select! {
                 biased;
 
-                Some(msg) = self.rx.recv() => {
+                Some(msg) = Torrent::next_message(&mut postponed_messages, &mut self.rx) => {
                     match msg {
                         ClientMessage::Shutdown => {
                             tracing::info!("Shutting down torrent '{}'", self.torrent_context.torrent_name);
 
-                            if let Some(ref mut tracker) = tracker {
-                                if let Err(e) = self.tracker_stopped(tracker).await {
+                            if let Some(ref mut trackers) = trackers {
+                                if let Err(e) = self.tracker_stopped(trackers).await {
                                     tracing::warn!("Failed to send stopped message to tracker: {}", e);
                                 }
                             }
@@ -437,102 +987,129 @@ impl Torrent {
                         ClientMessage::Have { piece } => {
                             tracing::debug!("Have piece: {}", piece);                     
                             self.torrent_context.bitfield.lock().await[piece as usize / 8] |= 1 << (7 - piece % 8);  
+                            self.torrent_context.needed.lock().await.verify_piece(piece);
+                            self.torrent_context.piece_contributors.lock().await.remove(&piece);
 
                             for peer_handle in &mut self.peer_handles {
                                 let _ = peer_handle.have(piece).await;
                             }   
+
+                            self.check_finished(&trackers).await;
                         },
-                        ClientMessage::Cancel { block } => {
-                            tracing::debug!("Cancel block: {} {} {}", block.index, block.begin, block.length);
-                            if !end_game_blocks.iter().any(|b| b.index == block.index && b.begin == block.begin && b.length == block.length){
-                                
-                                let block_copy = Block {
-                                    index: block.index,
-                                    begin: block.begin,
-                                    length: block.length,
-
-                                    number: 0, // doesn't matter
-                                    data: None, // None is simpler to copy
-                                };
-
-                                {
-                                    // removing piece from block picker when all blocks are downloaded
-                                    let end_game_current_piece_blocks_count = end_game_blocks.iter().filter(|b| b.index == block.index).count() + 1;
-                                    tracing::debug!("End game current piece blocks count: {}", end_game_current_piece_blocks_count);
-                                    let mut needed_guard = self.torrent_context.needed.lock().await;
-                                    let position = match needed_guard.pieces.iter().position(|p| p.index == block.index) {
-                                        Some(position) => position,
-                                        None => {
-                                            tracing::error!("Block picker already removed end game piece {}.", block.index);
-                                            continue;
-                                        }
-                                    };
-
-                                    if end_game_current_piece_blocks_count == needed_guard.pieces[position].block_count {
-                                        needed_guard.pieces.remove(position);
-                                    }   
-                                }
+                        ClientMessage::Recheck { info_hash } => {
+                            tracing::info!("Rechecking the data of torrent '{}'", self.torrent_context.torrent_name);
+                            if let Err(e) = self.disk_handle.recheck(info_hash).await {
+                                tracing::error!("Failed to send recheck message to disk handle: {}", e);
+                            }
+                        },
+                        ClientMessage::SetPickingMode { picking_mode, .. } => {
+                            tracing::info!("Downloading torrent '{}' {}", self.torrent_context.torrent_name, picking_mode);
+                            self.torrent_context.needed.lock().await.picking_mode = picking_mode;
+                        },
+                        ClientMessage::SetFilePriorities { file_priorities, .. } => {
+                            tracing::info!("Changing the priority of {} files of torrent '{}'", file_priorities.len(), self.torrent_context.torrent_name);
+
+                            self.set_file_priorities(file_priorities).await;
+                            self.check_finished(&trackers).await;
+                        },
+                        ClientMessage::Rechecked { pieces } => {
+                            tracing::info!("Recheck found {} of {} pieces on disk", pieces.len(), self.torrent_context.torrent_info.pieces_count);
 
-                                self.disk_handle.write_block(block).await?;
-                                
+                            let new_pieces = self.rebuild_from_pieces(&pieces).await;
+                            for piece in new_pieces {
                                 for peer_handle in &mut self.peer_handles {
-                                    if let Err(e) = peer_handle.cancel(block_copy.clone()).await {
-                                        tracing::warn!("Failed to send cancel message to peer {}: {}", peer_handle.peer_address, e);
-                                    }
+                                    let _ = peer_handle.have(piece).await;
                                 }
-                                end_game_blocks.push(block_copy);
                             }
+                            self.finished = self.is_finished().await;
                         },
-                        ClientMessage::FinishedDownloading => {
-                            tracing::info!("Finished downloading torrent '{}'", self.torrent_context.torrent_name);
+                        ClientMessage::HashFailed { piece } => {
+                            tracing::warn!("Piece {} failed the hash check, downloading it again", piece);
+                            self.torrent_context.hash_fails += 1;
 
-                            if let Some(ref mut tracker) = tracker {
-                                if let Err(e) = self.tracker_completed(tracker).await {
-                                    tracing::error!("Failed to send completed message to tracker: {}", e);
+                            if let Err(e) = self.blame_piece_contributors(piece).await {
+                                tracing::error!("Failed to blame the contributors of piece {}: {}", piece, e);
+                            }
+
+                            self.torrent_context.needed.lock().await.reset_piece(piece);
+                        },
+                        ClientMessage::FileChecked { path, valid, bad_md5sum, pieces } => {
+                            self.torrent_context.md5_checks.insert(path.clone(), valid);
+                            if valid {
+                                tracing::debug!("File '{}' passed the md5sum check", path);
+                                continue;
+                            }
+
+                            if bad_md5sum {
+                                tracing::warn!("The md5sum of file '{}' in the torrent file is probably wrong, keeping its data", path);
+                                if !self.torrent_context.bad_md5sums.contains(&path) {
+                                    self.torrent_context.bad_md5sums.push(path);
                                 }
-                            } 
+                                continue;
+                            }
+
+                            // the pieces inside the file weren't announced yet, they only have to be downloaded again
+                            tracing::warn!("File '{}' failed the md5sum check, downloading its pieces again", path);
+                            let mut needed_guard = self.torrent_context.needed.lock().await;
+                            for piece in pieces {
+                                needed_guard.reset_piece(piece);
+                            }
                         },
-                        ClientMessage::SendTorrentInfo { tx } => {
-                            let torrent_state = TorrentState::new(self.torrent_context.clone()).await;
-                            if let Err(e) = tx.send(torrent_state) {
-                                tracing::error!("Failed to send torrent context to client: {:?}", e);
+                        ClientMessage::Cancel { block } => {
+                            // a peer received an end game block, the others it was requested from don't have to send it
+                            tracing::debug!("Cancel block: {} {} {}", block.index, block.begin, block.length);
+                            for peer_handle in &mut self.peer_handles {
+                                if let Err(e) = peer_handle.cancel(block.clone()).await {
+                                    tracing::warn!("Failed to send cancel message to peer {}: {}", peer_handle.peer_address, e);
+                                }
                             }
                         },
-                        ClientMessage::PeerDisconnected { peer_address } => {
-                            self.torrent_context.peers.retain(|peer| peer != &peer_address);
+                        ClientMessage::BlockWritten { number } => {
+                            self.torrent_context.needed.lock().await.write_block(number);
+                        },
+                        ClientMessage::TrackersUpdated { trackers: updated_trackers, peer_addresses } => {
+                            self.update_tracker_statuses(&updated_trackers);
+                            trackers = Some(updated_trackers);
+                            self.trackers_busy = false;
+
+                            if self.completed_pending {
+                                self.completed_pending = false;
+                                self.announce(&trackers, true).await;
+                            }
+                            next_announce.as_mut().reset(Torrent::next_announce(&trackers));
 
-                            let handle_index = self.peer_handles.iter().position(|peer_handle| peer_handle.peer_address == peer_address);
-                            if let Some(handle_index) = handle_index {
-                                if let Err(e) = self.peer_handles.remove(handle_index).join().await {
-                                    tracing::error!("Failed to join peer handle: {}", e);
-                                }
+                            if let Err(e) = self.connect_to_peers(peer_addresses).await {
+                                tracing::error!("Failed to connect to peers: {}", e);
                             }
                         },
+                        ClientMessage::FinishedDownloading => {
+                            self.check_finished(&trackers).await;
+                        },
+                        ClientMessage::SendTorrentInfo { tx } => {
+                            self.send_torrent_info(tx).await;
+                        },
+                        ClientMessage::PeerDisconnected { peer_address } => {
+                            self.remove_peer_handle(&peer_address).await;
+                        },
                         ClientMessage::AddPeerSession { peer_session } => {
                             let peer_address = match peer_session.stream.peer_addr() {
-                                Ok(peer_address) => peer_address,
+                                Ok(peer_address) => PeerAddress::from(peer_address),
                                 Err(e) => {
                                     tracing::error!("Failed to get peer address: {}", e);
                                     continue;
                                 }
                             };
-                            let peer_address = PeerAddress {
-                                address: peer_address.ip().to_string(),
-                                port: peer_address.port().to_string(),
-                            };
                             
-                            if self.torrent_context.peers.contains(&peer_address) {
+                            if self.torrent_context.has_peer(&peer_address) {
+                                continue;
+                            }
+
+                            if self.torrent_context.is_banned(&peer_address).await {
+                                tracing::debug!("Rejecting banned peer '{}'", peer_address);
                                 continue;
                             }
 
-                            let torrent_context = PeerTorrentContext::new(
-                                self.self_tx.clone(),
-                                Arc::clone(&self.torrent_context.torrent_info),
-                                self.torrent_context.info_hash.clone(),
-                                Arc::clone(&self.torrent_context.needed),
-                                Arc::clone(&self.torrent_context.bitfield),
-                                Arc::clone(&self.torrent_context.uploaded),
-                            );
+                            let torrent_context = self.peer_torrent_context();
 
                             let peer_handle = match PeerHandle::from_session(
                                 self.client_id,
@@ -547,20 +1124,64 @@ impl Torrent {
                                 }
                             };
 
+                            self.torrent_context.peers.push(TorrentPeer {
+                                address: peer_address,
+                                source: PeerSource::Incoming,
+                            });
                             self.peer_handles.push(peer_handle);
-                        }
+                        },
+                        ClientMessage::DhtPeers { peers } => {
+                            if self.torrent_context.needed.lock().await.is_empty() {
+                                continue;
+                            }
+
+                            tracing::debug!("Found {} peers through the DHT", peers.len());
+                            if let Err(e) = self.add_new_peers(peers, ConnectionType::Outgoing, PeerSource::Dht).await {
+                                tracing::error!("Failed to connect to peers from the DHT: {}", e);
+                            }
+                        },
+                        ClientMessage::DhtNode { address } => {
+                            if let Some(dht_tx) = &self.dht_tx {
+                                let _ = dht_tx.send(ClientMessage::DhtNode{address}).await;
+                            }
+                        },
+                        ClientMessage::PexPeers { peers } => {
+                            if self.torrent_context.torrent_info.private {
+                                continue;
+                            }
+
+                            // seeds are of no use once everything is downloaded
+                            let seeding = self.torrent_context.needed.lock().await.is_empty();
+                            let peer_addresses = peers
+                                .into_iter()
+                                .filter(|peer| !(seeding && peer.is_seed()))
+                                .map(|peer| peer.address)
+                                .collect::<Vec<PeerAddress>>();
+
+                            tracing::debug!("Learned {} peers through peer exchange", peer_addresses.len());
+                            if let Err(e) = self.add_new_peers(peer_addresses, ConnectionType::Outgoing, PeerSource::Pex).await {
+                                tracing::error!("Failed to connect to peers from peer exchange: {}", e);
+                            }
+                        },
                         _ => {}
                     }
                 },
-                _ = find_new_peers_interval.tick() => {
-                    // connect to more peers with better tracker request
-                    if !self.torrent_context.needed.lock().await.is_empty() {
-                        if let Some(ref mut tracker) = tracker {
-                            // connect to peers from tracker response
-                            if let Err(e) = self.connect_to_peers(tracker).await {
-                                tracing::error!("Failed to connect to peers: {}", e);
-                            }
-                        }
+                // rescheduled once the trackers are back
+                _ = &mut next_announce, if trackers.is_some() && !self.trackers_busy => {
+                    self.announce(&trackers, false).await;
+                },
+                _ = dht_interval.tick() => {
+                    self.find_dht_peers().await;
+                },
+                _ = scrape_interval.tick() => {
+                    self.scrape_trackers(&trackers);
+                },
+                _ = choke_interval.tick() => {
+                    self.rechoke().await;
+                },
+                _ = pex_interval.tick() => {
+                    if !self.torrent_context.torrent_info.private {
+                        self.advertise_peers().await;
                     }
                 },
                 _ = save_state_interval.tick() => {
@@ -571,21 +1192,24 @@ impl Torrent {
             }
         }
 
+        // peers tell the torrent when they exit, nobody listens anymore so they mustn't wait on a full channel
+        self.rx.close();
+
         for peer_handle in self.peer_handles {
             let peer_addr = peer_handle.peer_address.clone();
             if let Err(err) = peer_handle.join().await {
                 if let Some(err) = err.downcast_ref::<tokio::io::Error>() {
                     match err.kind() {
                         tokio::io::ErrorKind::UnexpectedEof => {
-                            self.torrent_context.peers.retain(|peer| peer != &peer_addr);
+                            self.torrent_context.remove_peer(&peer_addr);
                             tracing::debug!("Peer '{}' UnexpectedEof", peer_addr);
                         }
                         tokio::io::ErrorKind::ConnectionReset => {
-                            self.torrent_context.peers.retain(|peer| peer != &peer_addr);
+                            self.torrent_context.remove_peer(&peer_addr);
                             tracing::debug!("Peer '{}' ConnectionReset", peer_addr);
                         }
                         tokio::io::ErrorKind::ConnectionAborted => {
-                            self.torrent_context.peers.retain(|peer| peer != &peer_addr);
+                            self.torrent_context.remove_peer(&peer_addr);
                             tracing::debug!("Peer '{}' ConnectionAborted", peer_addr);
                         }
                         _ => {
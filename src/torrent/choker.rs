@@ -0,0 +1,203 @@
+use std::collections::HashMap;
+
+use crate::peer::PeerAddress;
+
+/// How often the peers we upload to are chosen again.
+pub const CHOKE_INTERVAL_SECS: u64 = 10;
+/// The optimistic unchoke moves to another peer every 3 rounds, so every 30 seconds.
+const OPTIMISTIC_UNCHOKE_ROUNDS: u32 = 3;
+/// Peers unchoked for their rate, the optimistic unchoke comes on top of them.
+const UNCHOKE_SLOTS: usize = 4;
+
+/// What the choker needs to know about a connected peer.
+#[derive(Debug, Clone)]
+pub struct ChokerPeer {
+    pub address: PeerAddress,
+    pub interested: bool,
+    /// Bytes downloaded from the peer since it connected.
+    pub downloaded: u64,
+    /// Bytes uploaded to the peer since it connected.
+    pub uploaded: u64,
+}
+
+/// Tit-for-tat choker, unchokes the peers we download the most from, or upload the most to while seeding,
+/// plus one optimistic unchoke that gives other peers the chance to show their rate.
+#[derive(Debug, Default)]
+pub struct Choker {
+    round: u32,
+    optimistic: Option<PeerAddress>,
+    unchoked: Vec<PeerAddress>,
+    /// The byte counts of every peer at the last round, the rates are measured against them.
+    last_totals: HashMap<PeerAddress, (u64, u64)>,
+}
+
+impl Choker {
+    pub fn new() -> Choker {
+        Choker::default()
+    }
+
+    /// Picks the peers to upload to until the next round.
+    /// Returns the peers that have to be choked and the ones that have to be unchoked.
+    pub fn rechoke(&mut self, peers: &[ChokerPeer], seeding: bool) -> (Vec<PeerAddress>, Vec<PeerAddress>) {
+        let mut rates = peers
+            .iter()
+            .filter(|peer| peer.interested)
+            .map(|peer| {
+                let (downloaded, uploaded) = self.last_totals.get(&peer.address).copied().unwrap_or_default();
+                let rate = match seeding {
+                    true => peer.uploaded.saturating_sub(uploaded),
+                    false => peer.downloaded.saturating_sub(downloaded),
+                };
+                (peer.address.clone(), rate)
+            })
+            .collect::<Vec<(PeerAddress, u64)>>();
+        rates.sort_by_key(|(_, rate)| std::cmp::Reverse(*rate));
+
+        let mut unchoked = rates
+            .iter()
+            .take(UNCHOKE_SLOTS)
+            .map(|(address, _)| address.clone())
+            .collect::<Vec<PeerAddress>>();
+
+        // the optimistic unchoke is replaced early if it left, lost interest or earned a regular slot
+        let candidates = rates
+            .iter()
+            .map(|(address, _)| address)
+            .filter(|address| !unchoked.contains(address))
+            .collect::<Vec<&PeerAddress>>();
+        let optimistic_valid = self.optimistic.as_ref().is_some_and(|optimistic| candidates.contains(&optimistic));
+        if !optimistic_valid || self.round.is_multiple_of(OPTIMISTIC_UNCHOKE_ROUNDS) {
+            let others = candidates
+                .iter()
+                .filter(|address| Some(**address) != self.optimistic.as_ref())
+                .copied()
+                .collect::<Vec<&PeerAddress>>();
+
+            self.optimistic = match others.is_empty() {
+                true => candidates.first().map(|address| (*address).clone()),
+                false => Some(others[crate::utils::random_index(others.len())].clone()),
+            };
+        }
+        if let Some(optimistic) = &self.optimistic {
+            unchoked.push(optimistic.clone());
+        }
+
+        let connected = |address: &PeerAddress| peers.iter().any(|peer| &peer.address == address);
+        let to_choke = self.unchoked
+            .iter()
+            .filter(|address| !unchoked.contains(address) && connected(address))
+            .cloned()
+            .collect();
+        let to_unchoke = unchoked
+            .iter()
+            .filter(|address| !self.unchoked.contains(address))
+            .cloned()
+            .collect();
+
+        self.unchoked = unchoked;
+        self.last_totals = peers
+            .iter()
+            .map(|peer| (peer.address.clone(), (peer.downloaded, peer.uploaded)))
+            .collect();
+        self.round = self.round.wrapping_add(1);
+
+        (to_choke, to_unchoke)
+    }
+
+    /// Forgets a peer that disconnected, it starts choked if it connects again.
+    pub fn remove_peer(&mut self, peer_address: &PeerAddress) {
+        self.unchoked.retain(|address| address != peer_address);
+        self.last_totals.remove(peer_address);
+        if self.optimistic.as_ref() == Some(peer_address) {
+            self.optimistic = None;
+        }
+    }
+}
+
+#[cfg(test)]
+mod choker_tests {
+    use super::*;
+
+    fn peer(port: u16, interested: bool, downloaded: u64, uploaded: u64) -> ChokerPeer {
+        ChokerPeer {
+            address: PeerAddress(([127, 0, 0, 1], port).into()),
+            interested,
+            downloaded,
+            uploaded,
+        }
+    }
+
+    #[test]
+    fn test_unchokes_fastest_peers_and_one_optimistic() {
+        let mut choker = Choker::new();
+        let peers = (0..8).map(|port| peer(port, port != 7, port as u64 * 1000, 0)).collect::<Vec<ChokerPeer>>();
+
+        let (to_choke, to_unchoke) = choker.rechoke(&peers, false);
+        assert!(to_choke.is_empty());
+        assert_eq!(to_unchoke.len(), UNCHOKE_SLOTS + 1);
+        for peer in &peers[3..7] {
+            assert!(to_unchoke.contains(&peer.address));
+        }
+        // the uninterested peer is never unchoked
+        assert!(!to_unchoke.contains(&peers[7].address));
+    }
+
+    #[test]
+    fn test_seeding_ranks_by_upload_rate() {
+        let mut choker = Choker::new();
+        let peers = (0..6).map(|port| peer(port, true, 0, (6 - port) as u64 * 1000)).collect::<Vec<ChokerPeer>>();
+
+        let (_, to_unchoke) = choker.rechoke(&peers, true);
+        for peer in &peers[..4] {
+            assert!(to_unchoke.contains(&peer.address));
+        }
+    }
+
+    #[test]
+    fn test_rates_are_measured_per_round() {
+        let mut choker = Choker::new();
+        let mut peers = (0..6).map(|port| peer(port, true, 0, 0)).collect::<Vec<ChokerPeer>>();
+        peers[0].downloaded = 100_000;
+        choker.rechoke(&peers, false);
+
+        // peer 0 downloaded a lot before but nothing since the last round, peer 5 is the fastest now
+        for (index, peer) in peers.iter_mut().enumerate().skip(1) {
+            peer.downloaded = index as u64 * 1000;
+        }
+        choker.rechoke(&peers, false);
+
+        assert!(choker.unchoked[..UNCHOKE_SLOTS].contains(&peers[5].address));
+        assert!(!choker.unchoked[..UNCHOKE_SLOTS].contains(&peers[0].address));
+    }
+
+    #[test]
+    fn test_reconnected_peer_is_unchoked_again() {
+        let mut choker = Choker::new();
+        let peers = vec![peer(0, true, 1000, 0)];
+
+        let (_, to_unchoke) = choker.rechoke(&peers, false);
+        assert_eq!(to_unchoke, vec![peers[0].address.clone()]);
+
+        // a new connection from the same address starts choked and its byte counts start over
+        choker.remove_peer(&peers[0].address);
+        let peers = vec![peer(0, true, 500, 0)];
+        let (to_choke, to_unchoke) = choker.rechoke(&peers, false);
+        assert!(to_choke.is_empty());
+        assert_eq!(to_unchoke, vec![peers[0].address.clone()]);
+    }
+
+    #[test]
+    fn test_optimistic_unchoke_rotates() {
+        let mut choker = Choker::new();
+        let peers = (0..6).map(|port| peer(port, true, 0, 0)).collect::<Vec<ChokerPeer>>();
+
+        choker.rechoke(&peers, false);
+        let optimistic = choker.optimistic.clone();
+        choker.rechoke(&peers, false);
+        choker.rechoke(&peers, false);
+        assert_eq!(choker.optimistic, optimistic);
+
+        choker.rechoke(&peers, false);
+        assert_ne!(choker.optimistic, optimistic);
+    }
+}
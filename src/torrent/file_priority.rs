@@ -0,0 +1,73 @@
+use serde::{Serialize, Deserialize};
+
+use crate::disk_manager::DownloadableFile;
+
+/// How much a file of a multi-file torrent is wanted, higher priorities are downloaded first.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub enum FilePriority {
+    /// Not downloaded, except for the pieces it shares with wanted files.
+    Skip,
+    Low,
+    #[default]
+    Normal,
+    High,
+}
+
+impl std::fmt::Display for FilePriority {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            FilePriority::Skip => write!(f, "skip"),
+            FilePriority::Low => write!(f, "low"),
+            FilePriority::Normal => write!(f, "normal"),
+            FilePriority::High => write!(f, "high"),
+        }
+    }
+}
+
+/// The priority of every piece, which is the highest priority of the files it has data of.
+/// Files without a priority in `file_priorities` are normal.
+pub fn piece_priorities(files: &[DownloadableFile], file_priorities: &[FilePriority], pieces_count: usize, piece_length: usize) -> Vec<FilePriority> {
+    let mut piece_priorities = vec![FilePriority::Skip; pieces_count];
+
+    for (index, file) in files.iter().enumerate() {
+        let priority = file_priorities.get(index).copied().unwrap_or_default();
+        let range = match file.piece_range(piece_length) {
+            Some(range) => range,
+            None => continue
+        };
+
+        for piece in range {
+            if let Some(piece_priority) = piece_priorities.get_mut(piece as usize) {
+                *piece_priority = (*piece_priority).max(priority);
+            }
+        }
+    }
+
+    piece_priorities
+}
+
+#[cfg(test)]
+mod file_priority_tests {
+    use super::*;
+
+    fn file(start: u64, size: u64) -> DownloadableFile {
+        DownloadableFile {
+            start,
+            size,
+            path: String::new(),
+            md5sum: None,
+        }
+    }
+
+    #[test]
+    fn test_boundary_pieces_take_the_highest_priority() {
+        // pieces of 10 bytes, the second file starts in the middle of piece 1 and the third one in piece 3
+        let files = vec![file(0, 15), file(15, 20), file(35, 15)];
+
+        let priorities = piece_priorities(&files, &[FilePriority::Skip, FilePriority::High, FilePriority::Skip], 5, 10);
+        assert_eq!(priorities, vec![FilePriority::Skip, FilePriority::High, FilePriority::High, FilePriority::High, FilePriority::Skip]);
+
+        let priorities = piece_priorities(&files, &[FilePriority::Low], 5, 10);
+        assert_eq!(priorities, vec![FilePriority::Low, FilePriority::Normal, FilePriority::Normal, FilePriority::Normal, FilePriority::Normal]);
+    }
+}
@@ -0,0 +1,275 @@
+use anyhow::{anyhow, Result, Context};
+use tokio::task::JoinSet;
+use tokio::sync::mpsc;
+
+use std::collections::BTreeMap;
+
+use crate::disk_manager::torrent_context::sanitize_path_component;
+use crate::messager::ClientMessage;
+use crate::peer::PeerAddress;
+use crate::peer::ut_metadata;
+use crate::tracker::Tracker;
+use crate::utils::bencode::BencodedValue;
+use crate::utils::sha1hash::Sha1Hash;
+
+use super::TorrentFile;
+
+/// A parsed `magnet:?xt=urn:btih:` URI.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MagnetLink {
+    pub info_hash: Sha1Hash,
+    pub display_name: Option<String>,
+    pub trackers: Vec<String>,
+}
+
+impl MagnetLink {
+    pub fn is_magnet_link(src: &str) -> bool {
+        src.starts_with("magnet:?")
+    }
+
+    pub fn parse(uri: &str) -> Result<MagnetLink> {
+        let query = match uri.strip_prefix("magnet:?") {
+            Some(query) => query,
+            None => return Err(anyhow!("Invalid magnet link: missing 'magnet:?' prefix"))
+        };
+
+        let mut info_hash = None;
+        let mut display_name = None;
+        let mut trackers = Vec::new();
+
+        for pair in query.split('&') {
+            let (key, value) = match pair.split_once('=') {
+                Some(pair) => pair,
+                None => continue
+            };
+            let value = percent_encoding::percent_decode_str(value).decode_utf8().context("invalid utf8 in magnet link")?.to_string();
+
+            match key {
+                "xt" => {
+                    if let Some(hash) = value.strip_prefix("urn:btih:") {
+                        info_hash = Some(parse_info_hash(hash)?);
+                    }
+                },
+                "dn" => display_name = Some(value),
+                "tr" if !trackers.contains(&value) => trackers.push(value),
+                _ => {}
+            }
+        }
+
+        let info_hash = match info_hash {
+            Some(info_hash) => info_hash,
+            None => return Err(anyhow!("Invalid magnet link: missing 'xt=urn:btih:' parameter"))
+        };
+
+        Ok(MagnetLink {
+            info_hash,
+            display_name,
+            trackers,
+        })
+    }
+
+    /// Builds the link back into a URI, which is what gets saved until the metadata arrives.
+    pub fn to_uri(&self) -> String {
+        let mut uri = format!("magnet:?xt=urn:btih:{}", self.info_hash.to_hex());
+        if let Some(display_name) = &self.display_name {
+            uri += &format!("&dn={}", percent_encoding::utf8_percent_encode(display_name, percent_encoding::NON_ALPHANUMERIC));
+        }
+        for announce in &self.trackers {
+            uri += &format!("&tr={}", percent_encoding::utf8_percent_encode(announce, percent_encoding::NON_ALPHANUMERIC));
+        }
+
+        uri
+    }
+
+    /// Finds peers through the magnet trackers and the DHT and downloads the info dictionary from them.
+    /// Keeps retrying until one of the peers sends valid metadata.
+    pub async fn fetch_info_dict(&self, client_id: [u8; 20], dht_tx: Option<mpsc::Sender<ClientMessage>>) -> Result<Vec<u8>> {
+        if self.trackers.is_empty() && dht_tx.is_none() {
+            return Err(anyhow!("Magnet link has no trackers to find peers with"));
+        }
+
+        loop {
+            let mut peer_addresses: Vec<PeerAddress> = Vec::new();
+            for announce in &self.trackers {
+                let mut tracker = Tracker::new(announce.clone());
+
+                let tracker_response = match tracker.metadata_response(client_id, self.info_hash.clone()).await {
+                    Ok(tracker_response) => tracker_response,
+                    Err(e) => {
+                        tracing::warn!("Failed to get peers from tracker '{}': {}", announce, e);
+                        continue;
+                    }
+                };
+
+                match PeerAddress::from_tracker_response(tracker_response).await {
+                    Ok(addresses) => {
+                        for peer_address in addresses {
+                            if !peer_addresses.contains(&peer_address) {
+                                peer_addresses.push(peer_address);
+                            }
+                        }
+                    },
+                    Err(e) => tracing::warn!("Invalid response from tracker '{}': {}", announce, e),
+                }
+            }
+
+            if let Some(dht_tx) = &dht_tx {
+                for peer_address in MagnetLink::dht_peers(dht_tx, &self.info_hash).await {
+                    if !peer_addresses.contains(&peer_address) {
+                        peer_addresses.push(peer_address);
+                    }
+                }
+            }
+
+            tracing::debug!("Fetching metadata from {} peers", peer_addresses.len());
+
+            let mut fetches = JoinSet::new();
+            for peer_address in peer_addresses {
+                let info_hash = self.info_hash.clone();
+                fetches.spawn(async move {
+                    let result = ut_metadata::fetch_metadata(client_id, info_hash, &peer_address).await;
+                    (peer_address, result)
+                });
+            }
+
+            while let Some(fetch) = fetches.join_next().await {
+                match fetch {
+                    Ok((_, Ok(info_dict))) => return Ok(info_dict),
+                    Ok((peer_address, Err(e))) => tracing::debug!("Failed to fetch metadata from peer '{}': {}", peer_address, e),
+                    Err(e) => tracing::debug!("Metadata fetch task failed: {}", e),
+                }
+            }
+
+            tokio::time::sleep(std::time::Duration::from_secs(unsafe { crate::CLIENT_OPTIONS.tracker_regular_request_interval_secs })).await;
+        }
+    }
+
+    async fn dht_peers(dht_tx: &mpsc::Sender<ClientMessage>, info_hash: &Sha1Hash) -> Vec<PeerAddress> {
+        let (tx, mut rx) = mpsc::channel(1);
+        if let Err(e) = dht_tx.send(ClientMessage::FindDhtPeers{info_hash: info_hash.clone(), port: None, tx}).await {
+            tracing::warn!("Failed to ask the DHT for peers: {}", e);
+            return Vec::new();
+        }
+
+        match rx.recv().await {
+            Some(ClientMessage::DhtPeers{peers}) => peers,
+            _ => Vec::new()
+        }
+    }
+
+    /// Wraps a verified info dictionary into a torrent file in the state folder so it can be reloaded on restart.
+    /// Returns the path of the written file.
+    pub async fn write_torrent_file(&self, info_dict: &[u8], state_torrent_files_path: &str) -> Result<String> {
+        let info = BencodedValue::from_bytes(info_dict).context("parsing the fetched info dictionary")?;
+
+        let torrent_name = match info.get_from_dict(b"name") {
+            Ok(BencodedValue::ByteString(name)) => Some(name),
+            _ => None
+        };
+        // names that aren't safe as a file name fall back to the next one
+        let torrent_name = torrent_name
+            .into_iter()
+            .chain(self.display_name.clone().map(String::into_bytes))
+            .find_map(|name| sanitize_path_component(&name).ok())
+            .unwrap_or(self.info_hash.to_hex());
+
+        let mut torrent_dict = BTreeMap::new();
+        if let Some(announce) = self.trackers.first() {
+            torrent_dict.insert(b"announce".to_vec(), BencodedValue::ByteString(announce.clone().into_bytes()));
+        }
+        if self.trackers.len() > 1 {
+            let announce_list = self.trackers
+                .iter()
+                .map(|announce| BencodedValue::List(vec![BencodedValue::ByteString(announce.clone().into_bytes())]))
+                .collect();
+            torrent_dict.insert(b"announce-list".to_vec(), BencodedValue::List(announce_list));
+        }
+        torrent_dict.insert(b"info".to_vec(), info);
+        let torrent_dict = BencodedValue::Dict(torrent_dict);
+
+        // the info dictionary is encoded again so make sure it still hashes to the same value
+        if TorrentFile::get_info_hash(&torrent_dict)? != self.info_hash {
+            return Err(anyhow!("Fetched info dictionary doesn't encode back to the magnet info hash"));
+        }
+
+        let torrent_file_dest_path = std::path::Path::new(state_torrent_files_path).join(format!("{}.torrent", torrent_name));
+        tokio::fs::create_dir_all(torrent_file_dest_path.parent().unwrap()).await?; // always has the state folder as parent
+        tokio::fs::write(&torrent_file_dest_path, torrent_dict.as_bytes()?).await.context("couldn't write torrent file")?;
+
+        Ok(torrent_file_dest_path.to_str().unwrap().to_string()) // built from utf8 strings
+    }
+}
+
+fn parse_info_hash(hash: &str) -> Result<Sha1Hash> {
+    match hash.len() {
+        40 => Sha1Hash::from_hex(hash),
+        32 => {
+            let bytes = base32_decode(hash)?;
+            Ok(Sha1Hash(bytes.try_into().unwrap())) // 32 base32 characters are always 20 bytes
+        },
+        _ => Err(anyhow!("Invalid info hash length in magnet link: {}", hash.len()))
+    }
+}
+
+/// Decodes unpadded RFC 4648 base32, which older magnet links use for the info hash.
+fn base32_decode(input: &str) -> Result<Vec<u8>> {
+    let mut bytes = Vec::with_capacity(input.len() * 5 / 8);
+    let mut buffer: u32 = 0;
+    let mut bits = 0;
+
+    for c in input.chars() {
+        let value = match c.to_ascii_uppercase() {
+            c @ 'A'..='Z' => c as u32 - 'A' as u32,
+            c @ '2'..='7' => c as u32 - '2' as u32 + 26,
+            _ => return Err(anyhow!("Invalid base32 character: {}", c))
+        };
+
+        buffer = buffer << 5 | value;
+        bits += 5;
+
+        if bits >= 8 {
+            bits -= 8;
+            bytes.push((buffer >> bits) as u8);
+            buffer &= (1 << bits) - 1;
+        }
+    }
+
+    Ok(bytes)
+}
+
+#[cfg(test)]
+mod magnet_tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_hex_magnet_link() {
+        let magnet = MagnetLink::parse("magnet:?xt=urn:btih:c12fe1c06bba254a9dc9f519b335aa7c1367a88a&dn=Some%20Name&tr=http%3A%2F%2Ftracker.example%2Fannounce&tr=udp%3A%2F%2Fother.example%3A80").unwrap();
+
+        assert_eq!(magnet.info_hash.to_hex(), "c12fe1c06bba254a9dc9f519b335aa7c1367a88a");
+        assert_eq!(magnet.display_name, Some("Some Name".to_string()));
+        assert_eq!(magnet.trackers, vec!["http://tracker.example/announce".to_string(), "udp://other.example:80".to_string()]);
+    }
+
+    #[test]
+    fn test_parse_base32_magnet_link() {
+        let magnet = MagnetLink::parse("magnet:?xt=urn:btih:YEX6DQDLXISUVHOJ6UM3GNNKPQJWPKEK").unwrap();
+
+        assert_eq!(magnet.info_hash.to_hex(), "c12fe1c06bba254a9dc9f519b335aa7c1367a88a");
+        assert!(magnet.trackers.is_empty());
+    }
+
+    #[test]
+    fn test_magnet_link_to_uri() {
+        let magnet = MagnetLink::parse("magnet:?xt=urn:btih:YEX6DQDLXISUVHOJ6UM3GNNKPQJWPKEK&dn=Some%20Name&tr=udp%3A%2F%2Fother.example%3A80").unwrap();
+
+        assert_eq!(magnet.to_uri(), "magnet:?xt=urn:btih:c12fe1c06bba254a9dc9f519b335aa7c1367a88a&dn=Some%20Name&tr=udp%3A%2F%2Fother%2Eexample%3A80");
+        assert_eq!(MagnetLink::parse(&magnet.to_uri()).unwrap(), magnet);
+    }
+
+    #[test]
+    fn test_parse_invalid_magnet_link() {
+        assert!(MagnetLink::parse("magnet:?dn=name").is_err());
+        assert!(MagnetLink::parse("magnet:?xt=urn:btih:1234").is_err());
+        assert!(MagnetLink::parse("http://example.com").is_err());
+    }
+}
@@ -1,16 +1,71 @@
 
 use tokio::sync::Mutex;
 use anyhow::{Result, Context};
+use serde::{Serialize, Deserialize};
 
+use std::collections::{BTreeMap, HashMap};
+use std::net::SocketAddr;
 use std::sync::Arc;
 
 use crate::utils::sha1hash::Sha1Hash;
 use crate::peer::{BlockPicker, PeerAddress};
 use crate::peer::peer_message::ConnectionType;
+use crate::tracker::{SwarmStats, TrackerStatus};
 
-use super::{TorrentFile, TorrentInfo, TorrentState};
+use super::{FilePriority, TorrentFile, TorrentInfo, TorrentState};
 
 
+/// Where the address of a peer came from.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum PeerSource {
+    #[default]
+    Tracker,
+    Incoming,
+    Pex,
+    Dht,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "SerializedTorrentPeer")]
+pub struct TorrentPeer {
+    pub address: PeerAddress,
+    pub source: PeerSource,
+}
+
+/// Peers in state files saved before addresses were socket addresses have the ip and port in separate keys.
+#[derive(Deserialize)]
+struct SerializedTorrentPeer {
+    address: String,
+    port: Option<String>,
+    #[serde(default)]
+    source: PeerSource,
+}
+
+impl TryFrom<SerializedTorrentPeer> for TorrentPeer {
+    type Error = anyhow::Error;
+
+    fn try_from(serialized: SerializedTorrentPeer) -> Result<TorrentPeer> {
+        let address = match serialized.port {
+            Some(port) => PeerAddress::new(serialized.address.parse()?, port.parse()?),
+            None => PeerAddress::from(serialized.address.parse::<SocketAddr>()?),
+        };
+
+        Ok(TorrentPeer { address, source: serialized.source })
+    }
+}
+
+/// A peer the torrent is connected to, as the UI shows it.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ConnectedPeer {
+    pub address: PeerAddress,
+    /// it stopped sending the blocks we requested
+    pub snubbed: bool,
+    /// requests kept outstanding with it
+    pub queue_depth: usize,
+    /// bytes per second downloaded from it
+    pub download_rate: u64,
+}
+
 #[derive(Debug, Clone)]
 pub struct TorrentContext {
     pub connection_type: ConnectionType,
@@ -21,18 +76,26 @@ pub struct TorrentContext {
     pub torrent_file: Arc<TorrentFile>,
     pub info_hash: Sha1Hash,
     pub needed: Arc<Mutex<BlockPicker>>,
+    pub file_priorities: Vec<FilePriority>,
     pub bitfield: Arc<Mutex<Vec<u8>>>,
-    pub peers: Vec<PeerAddress>,
+    pub peers: Vec<TorrentPeer>,
+    pub piece_contributors: Arc<Mutex<HashMap<u32, Vec<PeerAddress>>>>,
+    pub banned_peers: Arc<Mutex<Vec<PeerAddress>>>,
 
     pub torrent_info: Arc<TorrentInfo>,
 
     pub downloaded: Arc<Mutex<u64>>,
     pub uploaded: Arc<Mutex<u64>>,
+    pub hash_fails: u64,
+    pub md5_checks: BTreeMap<String, bool>,
+    pub bad_md5sums: Vec<String>,
+    pub swarm_stats: SwarmStats,
+    pub tracker_statuses: Vec<TrackerStatus>,
 }
 
 impl TorrentContext {
     pub async fn from_state(torrent_state: TorrentState, info_hash: Sha1Hash, connection_type: ConnectionType) -> Result<Self> {
-        let needed = BlockPicker::from_state(torrent_state.needed);
+        let needed = BlockPicker::from_state(torrent_state.needed, &torrent_state.bitfield);
 
         let torrent_file_path = format!("{}/{}.torrent", unsafe { crate::CLIENT_OPTIONS.state_torrent_files_path.clone() }, torrent_state.torrent_name);
         let path = std::path::Path::new(&torrent_file_path);
@@ -48,13 +111,57 @@ impl TorrentContext {
             torrent_file: Arc::new(torrent_file),
             info_hash,
             needed: Arc::new(Mutex::new(needed)),
+            file_priorities: torrent_state.file_priorities,
             bitfield: Arc::new(Mutex::new(torrent_state.bitfield)),
             peers: torrent_state.peers,
+            piece_contributors: Arc::new(Mutex::new(HashMap::new())),
+            banned_peers: Arc::new(Mutex::new(torrent_state.banned_peers)),
 
             torrent_info: Arc::new(torrent_state.torrent_info),
 
             downloaded: Arc::new(Mutex::new(torrent_state.downloaded)),
             uploaded: Arc::new(Mutex::new(torrent_state.uploaded)),
+            hash_fails: torrent_state.hash_fails,
+            md5_checks: torrent_state.md5_checks,
+            bad_md5sums: torrent_state.bad_md5sums,
+            swarm_stats: torrent_state.swarm_stats,
+            tracker_statuses: torrent_state.trackers,
         })
     }
-}
\ No newline at end of file
+
+    pub fn has_peer(&self, peer_address: &PeerAddress) -> bool {
+        self.peers.iter().any(|peer| &peer.address == peer_address)
+    }
+
+    pub fn remove_peer(&mut self, peer_address: &PeerAddress) {
+        self.peers.retain(|peer| &peer.address != peer_address);
+    }
+
+    pub async fn is_banned(&self, peer_address: &PeerAddress) -> bool {
+        is_banned(&self.banned_peers, peer_address).await
+    }
+}
+
+/// Bans are per ip since incoming connections come from random ports.
+pub async fn is_banned(banned_peers: &Mutex<Vec<PeerAddress>>, peer_address: &PeerAddress) -> bool {
+    banned_peers.lock().await.iter().any(|banned| banned.ip() == peer_address.ip())
+}
+
+#[cfg(test)]
+mod torrent_context_tests {
+    use super::*;
+
+    #[test]
+    fn test_torrent_peer_serialization() {
+        let peer = TorrentPeer {
+            address: PeerAddress("[2001:db8::1]:6881".parse().unwrap()),
+            source: PeerSource::Pex,
+        };
+
+        let serialized = serde_json::to_string(&peer).unwrap();
+        assert_eq!(serde_json::from_str::<TorrentPeer>(&serialized).unwrap(), peer);
+
+        let legacy = serde_json::from_str::<TorrentPeer>(r#"{"address":"10.0.0.1","port":"6881"}"#).unwrap();
+        assert_eq!(legacy, TorrentPeer { address: PeerAddress("10.0.0.1:6881".parse().unwrap()), source: PeerSource::Tracker });
+    }
+}
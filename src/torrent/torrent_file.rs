@@ -44,11 +44,25 @@ impl TorrentFile {
     }
 
     pub fn get_pieces_count(&self) -> Result<usize> {
-        let info_dict = self.bencoded_dict.get_from_dict(b"info")?;
-        let pieces = info_dict.get_from_dict(b"pieces")?;
-        let pieces = pieces.try_into_byte_sha1_hashes()?;
+        Ok(self.get_pieces()?.len() / 20)
+    }
 
-        Ok(pieces.len())
+    /// The concatenated SHA-1 hashes of the pieces, borrowed so that looking up one hash doesn't copy all of them.
+    fn get_pieces(&self) -> Result<&[u8]> {
+        let info_dict = match self.bencoded_dict.try_into_dict()?.get(b"info".as_slice()) {
+            Some(info_dict) => info_dict,
+            None => return Err(anyhow!("Could not get info dict from torrent file"))
+        };
+        let pieces = match info_dict.try_into_dict()?.get(b"pieces".as_slice()) {
+            Some(pieces) => pieces.try_into_byte_string()?,
+            None => return Err(anyhow!("Could not get pieces from info dict in torrent file"))
+        };
+
+        if !pieces.len().is_multiple_of(20) {
+            return Err(anyhow!("Invalid number of bytes in sha1 hashes"));
+        }
+
+        Ok(pieces)
     }
 
     pub fn get_blocks_in_piece(&self) -> Result<usize> {
@@ -83,12 +97,10 @@ impl TorrentFile {
     }
 
     pub fn get_piece_hash(&self, piece_index: usize) -> Result<Sha1Hash> {
-        let info_dict = self.bencoded_dict.get_from_dict(b"info")?;
-        let pieces = info_dict.get_from_dict(b"pieces")?;
-        let pieces = pieces.try_into_byte_sha1_hashes()?;
+        let pieces = self.get_pieces()?;
 
-        match pieces.get(piece_index) {
-            Some(piece_hash) => Ok(piece_hash.clone()),
+        match pieces.get(20 * piece_index..20 * piece_index + 20) {
+            Some(piece_hash) => Ok(Sha1Hash(piece_hash.try_into()?)),
             None => Err(anyhow!("Invalid piece index in info dict ref in torrent file: {:?}", self.bencoded_dict))
         }
     }
@@ -101,6 +113,15 @@ impl TorrentFile {
         Ok(piece_length as usize)
     }
 
+    pub fn is_private(&self) -> bool {
+        let info_dict = match self.bencoded_dict.get_from_dict(b"info") {
+            Ok(info_dict) => info_dict,
+            Err(_) => return false
+        };
+
+        matches!(info_dict.get_from_dict(b"private"), Ok(BencodedValue::Integer(1)))
+    }
+
     pub fn get_torrent_length(&self) -> Result<u64> {
         let torrent_dict = self.get_bencoded_dict_ref().try_into_dict()?;
         let info_dict = match torrent_dict.get(&b"info".to_vec()) {
@@ -3,7 +3,7 @@ use serde::{Serialize, Deserialize};
 
 use super::TorrentFile;
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
 pub struct TorrentInfo {
     pub pieces_count: usize,
     pub blocks_count: usize,
@@ -11,6 +11,9 @@ pub struct TorrentInfo {
     pub piece_length: usize,
     pub block_length: usize,
     pub blocks_in_piece: usize,
+    /// Private torrents (BEP 27) only get peers from their trackers.
+    #[serde(default)]
+    pub private: bool,
 }
 
 impl TorrentInfo {
@@ -21,6 +24,7 @@ impl TorrentInfo {
         let piece_length = torrent_file.get_piece_length()?;
         let block_length = torrent_file.get_block_length()?;
         let blocks_in_piece = torrent_file.get_blocks_in_piece()?;
+        let private = torrent_file.is_private();
 
         Ok(TorrentInfo {
             pieces_count,
@@ -29,6 +33,7 @@ impl TorrentInfo {
             piece_length, 
             block_length,
             blocks_in_piece,
+            private,
         })
     }
 
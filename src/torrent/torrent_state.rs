@@ -1,8 +1,11 @@
 use serde::{Serialize, Deserialize};
 
-use crate::peer::{PeerAddress, BlockPickerState};
+use std::collections::BTreeMap;
 
-use super::{TorrentInfo, TorrentContext};
+use crate::peer::{PeerAddress, BlockPickerState, PickingMode};
+use crate::tracker::{SwarmStats, TrackerStatus};
+
+use super::{ConnectedPeer, FilePriority, MagnetLink, TorrentInfo, TorrentContext, TorrentPeer};
 
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -11,13 +14,38 @@ pub struct TorrentState {
     pub dest_path: String,
     pub torrent_name: String,
     pub needed: BlockPickerState,
+    /// priority of every file in the order of the torrent file, files past the end are normal
+    #[serde(default)]
+    pub file_priorities: Vec<FilePriority>,
     pub bitfield: Vec<u8>,
-    pub peers: Vec<PeerAddress>,
+    pub peers: Vec<TorrentPeer>,
+    #[serde(default)]
+    pub banned_peers: Vec<PeerAddress>,
+    /// peers the torrent is connected to right now
+    #[serde(default)]
+    pub connected_peers: Vec<ConnectedPeer>,
 
     pub torrent_info: TorrentInfo,
 
     pub downloaded: u64,
     pub uploaded: u64,
+    #[serde(default)]
+    pub hash_fails: u64,
+    /// md5sum check result of every completed file that has one
+    #[serde(default)]
+    pub md5_checks: BTreeMap<String, bool>,
+    /// files that kept failing the md5sum check although their pieces passed the hash check
+    #[serde(default)]
+    pub bad_md5sums: Vec<String>,
+    /// seeders and leechers the trackers last reported
+    #[serde(default)]
+    pub swarm_stats: SwarmStats,
+    /// errors, warnings and schedule of every tracker
+    #[serde(default)]
+    pub trackers: Vec<TrackerStatus>,
+    /// the magnet link the torrent was added from while its metadata is still being fetched
+    #[serde(default)]
+    pub magnet_link: Option<String>,
 }
 
 impl TorrentState {
@@ -28,12 +56,54 @@ impl TorrentState {
             dest_path: torrent_context.dest_path,
             torrent_name: torrent_context.torrent_name,
             needed,
+            file_priorities: torrent_context.file_priorities,
             bitfield: torrent_context.bitfield.lock().await.clone(),
             peers: torrent_context.peers,
+            banned_peers: torrent_context.banned_peers.lock().await.clone(),
+            connected_peers: Vec::new(),
 
             torrent_info: (*torrent_context.torrent_info).clone(),
             downloaded: *torrent_context.downloaded.lock().await,
             uploaded: *torrent_context.uploaded.lock().await,
+            hash_fails: torrent_context.hash_fails,
+            md5_checks: torrent_context.md5_checks,
+            bad_md5sums: torrent_context.bad_md5sums,
+            swarm_stats: torrent_context.swarm_stats,
+            trackers: torrent_context.tracker_statuses,
+            magnet_link: None,
+        }
+    }
+
+    /// Stands in for a magnet link whose metadata didn't arrive yet, it has no pieces until then.
+    pub fn from_magnet(magnet_link: &MagnetLink, dest_path: &str, picking_mode: PickingMode) -> Self {
+        let torrent_name = magnet_link.display_name.clone().unwrap_or(magnet_link.info_hash.to_hex());
+        let needed = BlockPickerState {
+            torrent_info: TorrentInfo::default(),
+            picking_mode,
+            written: Default::default(),
+            wanted: Default::default(),
+        };
+
+        Self {
+            src_path: magnet_link.to_uri(),
+            dest_path: dest_path.to_string(),
+            torrent_name,
+            needed,
+            file_priorities: Vec::new(),
+            bitfield: Vec::new(),
+            peers: Vec::new(),
+            banned_peers: Vec::new(),
+            connected_peers: Vec::new(),
+
+            torrent_info: TorrentInfo::default(),
+            downloaded: 0,
+            uploaded: 0,
+            hash_fails: 0,
+            md5_checks: BTreeMap::new(),
+            bad_md5sums: Vec::new(),
+            swarm_stats: SwarmStats::default(),
+            trackers: Vec::new(),
+            magnet_link: Some(magnet_link.to_uri()),
         }
     }
 }
\ No newline at end of file
@@ -1,9 +1,12 @@
 use anyhow::{anyhow, Context, Result};
 
+use std::time::{Duration, Instant, SystemTime};
+
 use crate::torrent::torrent_file::TorrentFile;
 use crate::torrent::TorrentContext;
 
 use crate::utils::bencode::BencodedValue;
+use crate::utils::sha1hash::Sha1Hash;
 use crate::utils::UrlEncodable;
 
 pub mod tracker_event;
@@ -12,22 +15,107 @@ pub use tracker_event::TrackerEvent;
 mod tracker_request;
 use tracker_request::TrackerRequest;
 
+pub mod tracker_set;
+pub use tracker_set::TrackerSet;
+
+pub mod udp_tracker;
+use udp_tracker::UdpTracker;
+
+pub mod swarm_stats;
+pub use swarm_stats::SwarmStats;
+
+pub mod tracker_status;
+pub use tracker_status::TrackerStatus;
+
+/// How often trackers are scraped for the size of the swarm.
+pub const SCRAPE_INTERVAL_SECS: u64 = 10 * 60;
+/// Announces are spread out by at least this much, whatever the trackers ask for.
+pub const MIN_ANNOUNCE_GAP_SECS: u64 = 30;
+/// Announces that take longer than this count as failed, the trackers of a torrent share the deadline.
+pub const ANNOUNCE_TIMEOUT_SECS: u64 = 30;
+/// The stopped announce is sent while shutting down, which shouldn't wait on dead trackers.
+pub const STOPPED_ANNOUNCE_TIMEOUT_SECS: u64 = 5;
+/// Wait after the first failed announce, doubled with every failure after it.
+const BACKOFF_BASE_SECS: u64 = 60;
+const BACKOFF_MAX_SECS: u64 = 60 * 60;
+
 #[derive(Debug, Clone)]
 pub struct Tracker {
     announce: String,
     last_response: Option<BencodedValue>,
+    udp_tracker: Option<UdpTracker>,
+    swarm_stats: Option<SwarmStats>,
+
+    last_error: Option<String>,
+    last_warning: Option<String>,
+    failures: u32,
+    next_announce: Instant,
 }
 
 impl Tracker {
+    /// The `interval` of the last response, never shorter than its `min interval`.
     pub fn get_interval(&self) -> u64 {
-        match self.last_response.as_ref().and_then(|last_response| {
-            match last_response.get_from_dict(b"interval") {
-                Ok(BencodedValue::Integer(interval)) => Some(interval),
+        let read = |key: &[u8]| self.last_response.as_ref().and_then(|last_response| {
+            match last_response.get_from_dict(key) {
+                Ok(BencodedValue::Integer(interval)) if interval >= 0 => Some(interval as u64),
                 _ => None
             }
-        }) {
-            Some(interval) => interval as u64,
-            None => unsafe { crate::CLIENT_OPTIONS.tracker_regular_request_interval_secs }
+        });
+
+        let interval = read(b"interval").unwrap_or(unsafe { crate::CLIENT_OPTIONS.tracker_regular_request_interval_secs });
+        match read(b"min interval") {
+            Some(min_interval) => interval.max(min_interval),
+            None => interval
+        }
+    }
+
+    pub fn get_next_announce(&self) -> Instant {
+        self.next_announce
+    }
+
+    /// Trackers that failed are left alone until their backoff runs out.
+    pub fn is_backing_off(&self, now: Instant) -> bool {
+        self.failures > 0 && self.next_announce > now
+    }
+
+    pub fn has_failed(&self) -> bool {
+        self.failures > 0
+    }
+
+    pub fn get_status(&self) -> TrackerStatus {
+        let next_announce = SystemTime::now() + self.next_announce.saturating_duration_since(Instant::now());
+
+        TrackerStatus {
+            announce: self.announce.clone(),
+            last_error: self.last_error.clone(),
+            last_warning: self.last_warning.clone(),
+            failures: self.failures,
+            next_announce: next_announce.duration_since(SystemTime::UNIX_EPOCH).unwrap_or_default().as_secs(),
+        }
+    }
+
+    pub fn new(announce: String) -> Tracker {
+        let udp_tracker = match UdpTracker::is_udp_announce(&announce) {
+            true => match UdpTracker::new(&announce) {
+                Ok(udp_tracker) => Some(udp_tracker),
+                Err(e) => {
+                    tracing::warn!("Invalid UDP tracker url: {}", e);
+                    None
+                }
+            },
+            false => None
+        };
+
+        Tracker {
+            announce,
+            last_response: None,
+            udp_tracker,
+            swarm_stats: None,
+
+            last_error: None,
+            last_warning: None,
+            failures: 0,
+            next_announce: Instant::now(),
         }
     }
 
@@ -41,22 +129,78 @@ impl Tracker {
     
         let announce = String::from_utf8(tracker_announce.clone())?;
     
-        Ok(Tracker {
-            announce,
-            last_response: None,
-        })
+        Ok(Tracker::new(announce))
     }
 
-    pub async fn response(&mut self, client_id: [u8; 20], torrent_context: &TorrentContext, tracker_event: TrackerEvent) -> Result<BencodedValue> {
-        let request = TrackerRequest::new(self, client_id, torrent_context, tracker_event).await.context("creating tracker request")?.as_url()?;
-        tracing::debug!("request: {}", request);
-        
-        let response = reqwest::get(request).await.context("invalid tracker url")?;
-        let response_bytes = response.bytes().await.context("error getting response bytes")?; 
-        tracing::debug!("response: {:?}", response_bytes.to_vec().as_url_encoded()); 
-        
-        let bencoded_response = BencodedValue::from_bytes(&response_bytes).context("creating bencoded response")?;
+    /// Announces `tracker_event` asking for `numwant` peers.
+    pub async fn response(&mut self, client_id: [u8; 20], torrent_context: &TorrentContext, tracker_event: TrackerEvent, numwant: u32) -> Result<BencodedValue> {
+        let request = TrackerRequest::new(self, client_id, torrent_context, tracker_event, numwant).await.context("creating tracker request")?;
+        self.send_request(request).await
+    }
+
+    /// Announces a torrent whose metadata isn't known yet, only to get peers for it.
+    pub async fn metadata_response(&mut self, client_id: [u8; 20], info_hash: Sha1Hash) -> Result<BencodedValue> {
+        // the size is unknown before the metadata is fetched,
+        // anything above 0 makes the tracker treat us as a leecher
+        let left = unsafe { crate::CLIENT_OPTIONS.block_size } as u64;
+
+        let request = TrackerRequest::from_parts(self, client_id, info_hash, 0, 0, left, TrackerEvent::Started);
+        self.send_request(request).await
+    }
+
+    /// Sends the announce and schedules the next one, after the tracker's interval or with backoff if it failed.
+    async fn send_request(&mut self, request: TrackerRequest) -> Result<BencodedValue> {
+        let bencoded_response = match self.udp_tracker.as_mut() {
+            Some(udp_tracker) => udp_tracker.announce(&request).await,
+            None => Tracker::send_http_request(request).await
+        };
+
+        let bencoded_response = bencoded_response.and_then(|bencoded_response| {
+            match bencoded_response.get_from_dict(b"failure reason") {
+                Ok(BencodedValue::ByteString(failure)) => Err(anyhow!("Tracker failure: {}", String::from_utf8_lossy(&failure))),
+                _ => Ok(bencoded_response)
+            }
+        });
+
+        match bencoded_response {
+            Ok(bencoded_response) => {
+                self.update_last_response(&bencoded_response);
 
+                self.last_error = None;
+                self.failures = 0;
+                self.last_warning = match bencoded_response.get_from_dict(b"warning message") {
+                    Ok(BencodedValue::ByteString(warning)) => Some(String::from_utf8_lossy(&warning).to_string()),
+                    _ => None
+                };
+                if let Some(warning) = &self.last_warning {
+                    tracing::warn!("Tracker '{}' warning: {}", self.announce, warning);
+                }
+                self.next_announce = Instant::now() + Duration::from_secs(self.get_interval());
+
+                Ok(bencoded_response)
+            },
+            Err(e) => {
+                self.fail(&e);
+                Err(e)
+            }
+        }
+    }
+
+    /// Records an announce that didn't get an answer in time and returns the error for it.
+    pub fn time_out(&mut self) -> anyhow::Error {
+        let e = anyhow!("Tracker didn't respond in time");
+        self.fail(&e);
+        e
+    }
+
+    fn fail(&mut self, e: &anyhow::Error) {
+        self.last_error = Some(e.to_string());
+        self.failures = self.failures.saturating_add(1);
+
+        self.next_announce = Instant::now() + Duration::from_secs(backoff_secs(self.failures));
+    }
+
+    fn update_last_response(&mut self, bencoded_response: &BencodedValue) {
         let last_tracker_id = self.last_response.as_ref().and_then(|last_response| {
             match last_response.get_from_dict(b"tracker id") {
                 Ok(BencodedValue::ByteString(tracker_id)) => Some(tracker_id),
@@ -66,6 +210,8 @@ impl Tracker {
 
         self.last_response = Some(bencoded_response.clone());
 
+        self.swarm_stats.get_or_insert_with(SwarmStats::default).update_from_announce(bencoded_response);
+
         if bencoded_response.get_from_dict(b"tracker id").is_err() {
             if let Some(last_response) = self.last_response.as_mut() {
                 if let Some(last_tracker_id) = last_tracker_id {
@@ -73,7 +219,102 @@ impl Tracker {
                 }
             }
         }
+    }
+
+    /// The swarm stats from the last announce or scrape.
+    pub fn get_swarm_stats(&self) -> Option<SwarmStats> {
+        self.swarm_stats
+    }
+
+    /// HTTP trackers support scraping if the last part of the announce url starts with `announce`,
+    /// the scrape url replaces it with `scrape`.
+    pub fn scrape_url(&self) -> Option<String> {
+        let (base, last_part) = self.announce.rsplit_once('/')?;
+
+        last_part
+            .strip_prefix("announce")
+            .map(|rest| format!("{}/scrape{}", base, rest))
+    }
+
+    pub async fn scrape(&mut self, info_hash: &Sha1Hash) -> Result<SwarmStats> {
+        let stats = match self.udp_tracker.as_mut() {
+            Some(udp_tracker) => match udp_tracker.scrape(std::slice::from_ref(info_hash)).await?.first() {
+                Some(stats) => *stats,
+                None => return Err(anyhow!("UDP scrape response has no stats for the torrent"))
+            },
+            None => {
+                let scrape_url = match self.scrape_url() {
+                    Some(scrape_url) => scrape_url,
+                    None => return Err(anyhow!("Tracker '{}' doesn't support scraping", self.announce))
+                };
+                // the announce url can already have a query
+                let separator = if scrape_url.contains('?') { '&' } else { '?' };
+                let request = format!("{}{}info_hash={}", scrape_url, separator, info_hash.as_url_encoded());
+                tracing::debug!("scrape request: {}", request);
+
+                let response = reqwest::get(request).await.context("invalid tracker scrape url")?;
+                let response_bytes = response.bytes().await.context("error getting scrape response bytes")?;
+                let bencoded_response = BencodedValue::from_bytes(&response_bytes).context("creating bencoded scrape response")?;
+
+                SwarmStats::from_scrape_response(&bencoded_response, info_hash)?
+            }
+        };
 
-        Ok(bencoded_response)
+        self.swarm_stats = Some(stats);
+
+        Ok(stats)
+    }
+
+    async fn send_http_request(request: TrackerRequest) -> Result<BencodedValue> {
+        let request = request.as_url()?;
+        tracing::debug!("request: {}", request);
+        
+        let response = reqwest::get(request).await.context("invalid tracker url")?;
+        let response_bytes = response.bytes().await.context("error getting response bytes")?; 
+        tracing::debug!("response: {:?}", response_bytes.to_vec().as_url_encoded()); 
+        
+        BencodedValue::from_bytes(&response_bytes).context("creating bencoded response")
     }
-}
\ No newline at end of file
+}
+
+/// Exponential backoff after `failures` failed announces in a row.
+fn backoff_secs(failures: u32) -> u64 {
+    BACKOFF_BASE_SECS.saturating_mul(1 << failures.saturating_sub(1).min(16)).min(BACKOFF_MAX_SECS)
+}
+
+#[cfg(test)]
+mod tracker_tests {
+    use super::*;
+
+    #[test]
+    fn test_scrape_url() {
+        let scrape_url = |announce: &str| Tracker::new(announce.to_string()).scrape_url();
+
+        assert_eq!(scrape_url("http://example.com/announce"), Some("http://example.com/scrape".to_string()));
+        assert_eq!(scrape_url("http://example.com/x/announce"), Some("http://example.com/x/scrape".to_string()));
+        assert_eq!(scrape_url("http://example.com/announce.php"), Some("http://example.com/scrape.php".to_string()));
+        assert_eq!(scrape_url("http://example.com/announce?x2%0644"), Some("http://example.com/scrape?x2%0644".to_string()));
+        assert_eq!(scrape_url("http://example.com/a"), None);
+        assert_eq!(scrape_url("http://example.com/x%064announce"), None);
+    }
+
+    #[test]
+    fn test_interval_respects_min_interval() {
+        let mut tracker = Tracker::new("http://example.com/announce".to_string());
+
+        tracker.update_last_response(&BencodedValue::from_bytes(b"d8:intervali1800e12:min intervali900ee").unwrap());
+        assert_eq!(tracker.get_interval(), 1800);
+
+        tracker.update_last_response(&BencodedValue::from_bytes(b"d8:intervali60e12:min intervali900ee").unwrap());
+        assert_eq!(tracker.get_interval(), 900);
+    }
+
+    #[test]
+    fn test_backoff_secs() {
+        assert_eq!(backoff_secs(1), 60);
+        assert_eq!(backoff_secs(2), 120);
+        assert_eq!(backoff_secs(4), 480);
+        assert_eq!(backoff_secs(7), 3600);
+        assert_eq!(backoff_secs(u32::MAX), 3600);
+    }
+}
@@ -0,0 +1,81 @@
+use anyhow::{anyhow, Result};
+use serde::{Serialize, Deserialize};
+
+use crate::utils::bencode::BencodedValue;
+use crate::utils::sha1hash::Sha1Hash;
+
+/// Size of a torrent's swarm as reported by its trackers.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SwarmStats {
+    /// Peers that have the whole torrent.
+    pub complete: u32,
+    /// Times the torrent was completed, only scrapes report it.
+    pub downloaded: u32,
+    /// Peers that are still downloading.
+    pub incomplete: u32,
+}
+
+impl SwarmStats {
+    pub fn peers(&self) -> u32 {
+        self.complete.saturating_add(self.incomplete)
+    }
+
+    /// Takes `complete` and `incomplete` from an announce response, keeping `downloaded` from the last scrape.
+    pub fn update_from_announce(&mut self, response: &BencodedValue) {
+        if let Ok(BencodedValue::Integer(complete)) = response.get_from_dict(b"complete") {
+            self.complete = complete.clamp(0, u32::MAX as i64) as u32;
+        }
+
+        if let Ok(BencodedValue::Integer(incomplete)) = response.get_from_dict(b"incomplete") {
+            self.incomplete = incomplete.clamp(0, u32::MAX as i64) as u32;
+        }
+    }
+
+    /// Reads the stats of `info_hash` from the `files` dictionary of an HTTP scrape response.
+    pub fn from_scrape_response(response: &BencodedValue, info_hash: &Sha1Hash) -> Result<SwarmStats> {
+        if let Ok(BencodedValue::ByteString(failure)) = response.get_from_dict(b"failure reason") {
+            return Err(anyhow!("Failure reason: {}", String::from_utf8_lossy(&failure)));
+        }
+
+        let files = response.get_from_dict(b"files")?;
+        let stats = match files.try_into_dict()?.get(info_hash.0.as_slice()) {
+            Some(stats) => stats,
+            None => return Err(anyhow!("Scrape response has no stats for the torrent"))
+        };
+
+        let read = |key: &[u8]| match stats.get_from_dict(key) {
+            Ok(BencodedValue::Integer(value)) => value.clamp(0, u32::MAX as i64) as u32,
+            _ => 0
+        };
+
+        Ok(SwarmStats {
+            complete: read(b"complete"),
+            downloaded: read(b"downloaded"),
+            incomplete: read(b"incomplete"),
+        })
+    }
+}
+
+#[cfg(test)]
+mod swarm_stats_tests {
+    use super::*;
+
+    #[test]
+    fn test_from_scrape_response() {
+        let info_hash = Sha1Hash([b'a'; 20]);
+        let response = BencodedValue::from_bytes(b"d5:filesd20:aaaaaaaaaaaaaaaaaaaad8:completei5e10:downloadedi50e10:incompletei10eeee").unwrap();
+
+        let stats = SwarmStats::from_scrape_response(&response, &info_hash).unwrap();
+        assert_eq!(stats, SwarmStats{complete: 5, downloaded: 50, incomplete: 10});
+        assert!(SwarmStats::from_scrape_response(&response, &Sha1Hash([b'b'; 20])).is_err());
+    }
+
+    #[test]
+    fn test_update_from_announce_keeps_downloaded() {
+        let mut stats = SwarmStats{complete: 5, downloaded: 50, incomplete: 10};
+        let response = BencodedValue::from_bytes(b"d8:completei7e10:incompletei3e8:intervali1800ee").unwrap();
+
+        stats.update_from_announce(&response);
+        assert_eq!(stats, SwarmStats{complete: 7, downloaded: 50, incomplete: 3});
+    }
+}
@@ -17,4 +17,16 @@ impl UrlEncodable for TrackerEvent {
             TrackerEvent::None => "",
         }.to_string()
     }
-}
\ No newline at end of file
+}
+
+impl TrackerEvent {
+    /// The event id sent in UDP tracker announces (BEP 15).
+    pub fn as_udp_event(&self) -> u32 {
+        match self {
+            TrackerEvent::None => 0,
+            TrackerEvent::Completed => 1,
+            TrackerEvent::Started => 2,
+            TrackerEvent::Stopped => 3,
+        }
+    }
+}
@@ -1,4 +1,7 @@
 use anyhow::Result;
+use once_cell::sync::Lazy;
+
+use std::net::{IpAddr, Ipv6Addr};
 
 use crate::utils::sha1hash::Sha1Hash;
 use crate::utils::bencode::BencodedValue;
@@ -7,6 +10,13 @@ use crate::torrent::torrent_context::TorrentContext;
 
 use super::{Tracker, TrackerEvent};
 
+/// Sent with every announce of the session so trackers recognise us even if our ip changes.
+static TRACKER_KEY: Lazy<u32> = Lazy::new(|| {
+    let mut key = [0; 4];
+    getrandom::getrandom(&mut key).expect("getrandom failed");
+    u32::from_be_bytes(key)
+});
+
 #[derive(Debug, Clone)]
 pub struct TrackerRequest {
     announce: String,
@@ -19,27 +29,39 @@ pub struct TrackerRequest {
     compact: u8,
     no_peer_id: u8,
     event: TrackerEvent,
-    ip: Option<String>,
+    ip: Option<IpAddr>,
+    /// Our ipv6 address so ipv4 announces also get us into the tracker's ipv6 peer list (BEP 7).
+    ipv6: Option<Ipv6Addr>,
     numwant: Option<u32>,
     key: Option<String>,
     tracker_id: Option<String>,
 }
 
 impl TrackerRequest {
-    pub async fn new(tracker: &Tracker, client_id: [u8; 20], torrent_context: &TorrentContext, tracker_event: TrackerEvent) -> Result<TrackerRequest> {
+    pub async fn new(tracker: &Tracker, client_id: [u8; 20], torrent_context: &TorrentContext, tracker_event: TrackerEvent, numwant: u32) -> Result<TrackerRequest> {
+        let uploaded = *torrent_context.uploaded.lock().await;
+        let downloaded = *torrent_context.downloaded.lock().await;
+        let left = torrent_context.torrent_file.get_torrent_length()?.saturating_sub(downloaded);
+
+        let mut request = TrackerRequest::from_parts(tracker, client_id, torrent_context.info_hash.clone(), uploaded, downloaded, left, tracker_event);
+        request.numwant = Some(numwant);
+
+        Ok(request)
+    }
+
+    /// Builds the announce of a torrent from its transfer counts, `new` fills them in from the torrent context.
+    pub fn from_parts(tracker: &Tracker, client_id: [u8; 20], info_hash: Sha1Hash, uploaded: u64, downloaded: u64, left: u64, tracker_event: TrackerEvent) -> TrackerRequest {
         let announce = tracker.announce.clone();
-        let info_hash = torrent_context.info_hash.clone();
         let peer_id = client_id;
         let port = unsafe { crate::CLIENT_OPTIONS.listening_port };
-        let uploaded = *torrent_context.uploaded.lock().await;
-        let downloaded = *torrent_context.downloaded.lock().await;
-        let left = torrent_context.torrent_file.get_torrent_length()? - downloaded;
         let compact = 1;
         let no_peer_id = 0;
         let event = tracker_event;
-        let ip = None;
-        let numwant = None;
-        let key = None;
+        let ip = unsafe { crate::CLIENT_OPTIONS.announce_ip };
+        let ipv6 = crate::utils::local_ipv6_address();
+        // as many peers as a torrent connects to at most
+        let numwant = Some(unsafe { crate::CLIENT_OPTIONS.max_peers_per_torrent } as u32);
+        let key = Some(format!("{:08x}", *TRACKER_KEY));
         let tracker_id = tracker.last_response.as_ref().and_then(|last_response| {
             match last_response.get_from_dict(b"tracker id") {
                 Ok(BencodedValue::ByteString(tracker_id)) => Some(tracker_id.as_url_encoded()),
@@ -47,7 +69,7 @@ impl TrackerRequest {
             }
         });
 
-        Ok(TrackerRequest {
+        TrackerRequest {
             announce,
             info_hash,
             peer_id,
@@ -59,10 +81,11 @@ impl TrackerRequest {
             no_peer_id,
             event,
             ip,
+            ipv6,
             numwant,
             key,
             tracker_id,
-        })
+        }
     }
 
     pub fn as_url(&self) -> Result<String> {
@@ -89,7 +112,11 @@ impl TrackerRequest {
         };
 
         if let Some(ip) = &self.ip {
-            url.push_str(&format!("&ip={}", ip));
+            url.push_str(&format!("&ip={}", ip.as_url_encoded()));
+        }
+
+        if let Some(ipv6) = self.ipv6 {
+            url.push_str(&format!("&ipv6={}", IpAddr::V6(ipv6).as_url_encoded()));
         }
 
         if let Some(numwant) = self.numwant {
@@ -100,10 +127,59 @@ impl TrackerRequest {
             url.push_str(&format!("&key={}", key));
         }
 
+        // `trackerid` is the query key trackers read the `tracker id` of their responses from
         if let Some(tracker_id) = &self.tracker_id {
             url.push_str(&format!("&trackerid={}", tracker_id));
         }
 
         Ok(url)
     }
+
+    /// Builds the body of a UDP announce request (BEP 15), the tracker id only exists in the HTTP protocol.
+    pub fn as_udp_announce(&self, connection_id: u64, transaction_id: u32) -> Vec<u8> {
+        // the UDP announce only has room for an ipv4 address
+        let ip = match self.ip {
+            Some(IpAddr::V4(ip)) => u32::from(ip),
+            _ => 0
+        };
+        let key = self.key
+            .as_ref()
+            .and_then(|key| u32::from_str_radix(key, 16).ok())
+            .unwrap_or(0);
+        let numwant = self.numwant.map(|numwant| numwant as i32).unwrap_or(-1);
+
+        let mut bytes = Vec::with_capacity(98);
+        bytes.extend_from_slice(&connection_id.to_be_bytes());
+        bytes.extend_from_slice(&super::udp_tracker::ACTION_ANNOUNCE.to_be_bytes());
+        bytes.extend_from_slice(&transaction_id.to_be_bytes());
+        bytes.extend_from_slice(&self.info_hash.0);
+        bytes.extend_from_slice(&self.peer_id);
+        bytes.extend_from_slice(&self.downloaded.to_be_bytes());
+        bytes.extend_from_slice(&self.left.to_be_bytes());
+        bytes.extend_from_slice(&self.uploaded.to_be_bytes());
+        bytes.extend_from_slice(&self.event.as_udp_event().to_be_bytes());
+        bytes.extend_from_slice(&ip.to_be_bytes());
+        bytes.extend_from_slice(&key.to_be_bytes());
+        bytes.extend_from_slice(&numwant.to_be_bytes());
+        bytes.extend_from_slice(&self.port.to_be_bytes());
+
+        bytes
+    }
+}
+
+#[cfg(test)]
+mod tracker_request_tests {
+    use super::*;
+
+    #[test]
+    fn test_session_key_and_numwant() {
+        let tracker = Tracker::new("http://example.com/announce".to_string());
+        let request = TrackerRequest::from_parts(&tracker, [1; 20], Sha1Hash([2; 20]), 0, 0, 10, TrackerEvent::Started);
+        let other_request = TrackerRequest::from_parts(&tracker, [1; 20], Sha1Hash([3; 20]), 0, 0, 10, TrackerEvent::None);
+
+        let url = request.as_url().unwrap();
+        assert!(url.contains(&format!("&numwant={}", unsafe { crate::CLIENT_OPTIONS.max_peers_per_torrent })));
+        assert!(url.contains(&format!("&key={:08x}", *TRACKER_KEY)));
+        assert_eq!(request.key, other_request.key);
+    }
 }
@@ -0,0 +1,251 @@
+use anyhow::{anyhow, Result};
+
+use std::time::{Duration, Instant};
+
+use crate::torrent::torrent_file::TorrentFile;
+use crate::torrent::TorrentContext;
+use crate::utils::bencode::BencodedValue;
+use crate::utils::sha1hash::Sha1Hash;
+
+use super::{SwarmStats, Tracker, TrackerEvent, TrackerStatus};
+
+/// The trackers of a torrent grouped in tiers (BEP 12).
+#[derive(Debug, Clone)]
+pub struct TrackerSet {
+    tiers: Vec<Vec<Tracker>>,
+}
+
+impl TrackerSet {
+    /// Builds the tiers from `announce-list` and shuffles each one, torrents without it get a single tier with `announce`.
+    pub fn from_torrent_file(torrent_file: &TorrentFile) -> Result<TrackerSet> {
+        let torrent_dict = torrent_file.get_bencoded_dict_ref();
+
+        let mut tiers = match torrent_dict.get_from_dict(b"announce-list") {
+            Ok(BencodedValue::List(announce_list)) => TrackerSet::parse_announce_list(&announce_list),
+            _ => Vec::new()
+        };
+
+        if tiers.is_empty() {
+            tiers.push(vec![Tracker::from_torrent_file(torrent_file)?]);
+        }
+
+        for tier in &mut tiers {
+            shuffle(tier);
+        }
+
+        Ok(TrackerSet { tiers })
+    }
+
+    /// Skips invalid announce urls and tiers that end up empty.
+    fn parse_announce_list(announce_list: &[BencodedValue]) -> Vec<Vec<Tracker>> {
+        announce_list
+            .iter()
+            .filter_map(|tier| match tier {
+                BencodedValue::List(tier) => Some(tier),
+                _ => None
+            })
+            .map(|tier| {
+                let mut announces: Vec<String> = Vec::new();
+                for announce in tier {
+                    if let BencodedValue::ByteString(announce) = announce {
+                        match String::from_utf8(announce.clone()) {
+                            Ok(announce) if !announces.contains(&announce) => announces.push(announce),
+                            _ => {}
+                        }
+                    }
+                }
+
+                announces.into_iter().map(Tracker::new).collect::<Vec<Tracker>>()
+            })
+            .filter(|tier| !tier.is_empty())
+            .collect()
+    }
+
+    /// When the next tier is due for an announce.
+    pub fn get_next_announce(&self) -> Instant {
+        self.tiers
+            .iter()
+            .map(|tier| TrackerSet::tier_next_announce(tier))
+            .min()
+            .unwrap_or_else(Instant::now) // there is always at least one tier
+    }
+
+    /// A tier whose front tracker works is announced on that tracker's schedule,
+    /// otherwise as soon as any of its trackers is out of backoff.
+    fn tier_next_announce(tier: &[Tracker]) -> Instant {
+        match tier.first() {
+            Some(tracker) if !tracker.has_failed() => tracker.get_next_announce(),
+            _ => tier.iter().map(|tracker| tracker.get_next_announce()).min().unwrap_or_else(Instant::now)
+        }
+    }
+
+    pub fn get_statuses(&self) -> Vec<TrackerStatus> {
+        self.tiers
+            .iter()
+            .flatten()
+            .map(|tracker| tracker.get_status())
+            .collect()
+    }
+
+    /// Announces `tracker_event` to every tier no matter their schedule, used for events the trackers must hear about.
+    pub async fn response(&mut self, client_id: [u8; 20], torrent_context: &TorrentContext, tracker_event: TrackerEvent, numwant: u32, timeout: Duration) -> Result<Vec<BencodedValue>> {
+        self.announce(client_id, torrent_context, tracker_event, numwant, timeout, false).await
+    }
+
+    /// Announces only to the tiers that are due and skips trackers that are backing off.
+    pub async fn due_response(&mut self, client_id: [u8; 20], torrent_context: &TorrentContext, tracker_event: TrackerEvent, numwant: u32, timeout: Duration) -> Result<Vec<BencodedValue>> {
+        self.announce(client_id, torrent_context, tracker_event, numwant, timeout, true).await
+    }
+
+    /// Announces to the tiers at the same time, each one tries its trackers in order until one responds and moves that one to the front.
+    /// Trackers still working on an answer after `timeout` count as failed. Returns the responses of all tiers that had a working tracker.
+    async fn announce(&mut self, client_id: [u8; 20], torrent_context: &TorrentContext, tracker_event: TrackerEvent, numwant: u32, timeout: Duration, only_due: bool) -> Result<Vec<BencodedValue>> {
+        let now = Instant::now();
+        let deadline = tokio::time::Instant::from_std(now + timeout);
+
+        let tier_announces = self.tiers
+            .iter_mut()
+            .filter(|tier| !only_due || TrackerSet::tier_next_announce(tier) <= now)
+            .map(|tier| {
+                let tracker_event = tracker_event.clone();
+                async move {
+                    let mut attempted = false;
+                    for index in 0..tier.len() {
+                        let tracker = &mut tier[index];
+                        if only_due && tracker.is_backing_off(now) {
+                            continue;
+                        }
+                        attempted = true;
+
+                        let response = match tokio::time::timeout_at(deadline, tracker.response(client_id, torrent_context, tracker_event.clone(), numwant)).await {
+                            Ok(response) => response,
+                            Err(_) => Err(tracker.time_out()),
+                        };
+                        let response = match response {
+                            Ok(response) => response,
+                            Err(e) => {
+                                tracing::warn!("Failed to get a response from tracker '{}': {}", tracker.announce, e);
+                                continue;
+                            }
+                        };
+
+                        let tracker = tier.remove(index);
+                        tier.insert(0, tracker);
+                        return (true, Some(response));
+                    }
+
+                    (attempted, None)
+                }
+            });
+
+        let results = futures::future::join_all(tier_announces).await;
+        let attempted = results.iter().any(|(attempted, _)| *attempted);
+        let responses = results.into_iter().filter_map(|(_, response)| response).collect::<Vec<BencodedValue>>();
+
+        if attempted && responses.is_empty() {
+            return Err(anyhow!("No tracker responded"));
+        }
+
+        Ok(responses)
+    }
+
+    /// Scrapes the tracker at the front of every tier at the same time, which is the one that answered the last announce.
+    pub async fn scrape(&mut self, info_hash: &Sha1Hash, timeout: Duration) {
+        let scrapes = self.tiers
+            .iter_mut()
+            .filter_map(|tier| tier.first_mut())
+            .map(|tracker| async move {
+                match tokio::time::timeout(timeout, tracker.scrape(info_hash)).await {
+                    Ok(Err(e)) => tracing::debug!("Failed to scrape tracker '{}': {}", tracker.announce, e),
+                    Err(_) => tracing::debug!("Tracker '{}' didn't answer the scrape in time", tracker.announce),
+                    Ok(Ok(_)) => {}
+                }
+            });
+
+        futures::future::join_all(scrapes).await;
+    }
+
+    /// The tiers usually share most of their peers so their stats aren't added up,
+    /// the tracker that knows the biggest swarm is trusted instead.
+    pub fn get_swarm_stats(&self) -> Option<SwarmStats> {
+        self.tiers
+            .iter()
+            .filter_map(|tier| tier.first())
+            .filter_map(|tracker| tracker.get_swarm_stats())
+            .max_by_key(|stats| stats.peers())
+    }
+}
+
+/// Fisher-Yates shuffle, BEP 12 wants the order inside a tier to be random.
+fn shuffle<T>(items: &mut [T]) {
+    for i in (1..items.len()).rev() {
+        let mut random = [0; 8];
+        getrandom::getrandom(&mut random).expect("getrandom failed");
+
+        let j = (u64::from_be_bytes(random) % (i as u64 + 1)) as usize;
+        items.swap(i, j);
+    }
+}
+
+#[cfg(test)]
+mod tracker_set_tests {
+    use super::*;
+
+    fn announces(tracker_set: &TrackerSet) -> Vec<Vec<String>> {
+        tracker_set.tiers
+            .iter()
+            .map(|tier| tier.iter().map(|tracker| tracker.announce.clone()).collect())
+            .collect()
+    }
+
+    #[test]
+    fn test_parse_announce_list() {
+        let byte_string = |announce: &str| BencodedValue::ByteString(announce.as_bytes().to_vec());
+        let announce_list = vec![
+            BencodedValue::List(vec![byte_string("http://a.example/announce"), byte_string("http://a.example/announce"), BencodedValue::Integer(1)]),
+            BencodedValue::List(Vec::new()),
+            byte_string("http://not.a.tier/announce"),
+            BencodedValue::List(vec![byte_string("udp://b.example:80"), byte_string("udp://c.example:80")]),
+        ];
+
+        let tracker_set = TrackerSet { tiers: TrackerSet::parse_announce_list(&announce_list) };
+        let mut tiers = announces(&tracker_set);
+        tiers[1].sort();
+        assert_eq!(tiers, vec![
+            vec!["http://a.example/announce".to_string()],
+            vec!["udp://b.example:80".to_string(), "udp://c.example:80".to_string()],
+        ]);
+    }
+
+    #[test]
+    fn test_shuffle_keeps_items() {
+        let mut items = (0..32).collect::<Vec<u32>>();
+        shuffle(&mut items);
+        items.sort();
+        assert_eq!(items, (0..32).collect::<Vec<u32>>());
+    }
+
+    #[tokio::test]
+    async fn test_silent_trackers_share_the_deadline() {
+        // the sockets are bound but never answer, like a dead tracker
+        let mut sockets = Vec::new();
+        let mut tiers = Vec::new();
+        for _ in 0..3 {
+            let socket = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+            tiers.push(vec![Tracker::new(format!("udp://{}/announce", socket.local_addr().unwrap()))]);
+            sockets.push(socket);
+        }
+        let mut tracker_set = TrackerSet { tiers };
+
+        let start = Instant::now();
+        tracker_set.scrape(&Sha1Hash([1; 20]), Duration::from_millis(200)).await;
+        // one after the other they would take 600ms
+        assert!(start.elapsed() < Duration::from_millis(500));
+
+        let tracker = &mut tracker_set.tiers[0][0];
+        assert!(!tracker.has_failed());
+        tracker.time_out();
+        assert!(tracker.has_failed());
+        assert!(tracker.is_backing_off(Instant::now()));
+    }
+}
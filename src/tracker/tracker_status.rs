@@ -0,0 +1,27 @@
+use serde::{Serialize, Deserialize};
+
+/// What a torrent knows about one of its trackers, shown by the UI.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TrackerStatus {
+    pub announce: String,
+    /// Why the last announce failed, `None` once an announce succeeds again.
+    pub last_error: Option<String>,
+    /// The `warning message` of the last successful announce.
+    pub last_warning: Option<String>,
+    /// Announces that failed in a row.
+    pub failures: u32,
+    /// When the tracker is announced to next, in seconds since the unix epoch.
+    pub next_announce: u64,
+}
+
+impl TrackerStatus {
+    /// Seconds left until the next announce, 0 if it's already due.
+    pub fn next_announce_in_secs(&self) -> u64 {
+        let now = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+
+        self.next_announce.saturating_sub(now)
+    }
+}
@@ -0,0 +1,363 @@
+use anyhow::{anyhow, Context, Result};
+use tokio::net::UdpSocket;
+
+use std::collections::BTreeMap;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+use crate::utils::bencode::BencodedValue;
+use crate::utils::sha1hash::Sha1Hash;
+
+use super::tracker_request::TrackerRequest;
+use super::SwarmStats;
+
+const PROTOCOL_ID: u64 = 0x41727101980;
+
+pub const ACTION_CONNECT: u32 = 0;
+pub const ACTION_ANNOUNCE: u32 = 1;
+pub const ACTION_SCRAPE: u32 = 2;
+pub const ACTION_ERROR: u32 = 3;
+
+/// A connection id can be used for this long after it was received.
+const CONNECTION_ID_VALID_SECS: u64 = 60;
+/// A request is sent again after 15 * 2 ^ n seconds without a response.
+const RETRANSMISSION_TIMEOUT_SECS: u64 = 15;
+/// BEP 15 allows up to 8 retransmissions which takes over an hour, the torrent stops waiting long before that.
+const MAX_RETRANSMISSIONS: u32 = 2;
+/// Most info hashes that fit in a single scrape request.
+pub const MAX_SCRAPE_INFO_HASHES: usize = 74;
+
+/// Client side of the UDP tracker protocol (BEP 15).
+#[derive(Debug, Clone)]
+pub struct UdpTracker {
+    address: String,
+    connection: Option<(u64, Instant)>,
+}
+
+impl UdpTracker {
+    pub fn is_udp_announce(announce: &str) -> bool {
+        announce.starts_with("udp://")
+    }
+
+    pub fn new(announce: &str) -> Result<UdpTracker> {
+        let address = match announce.strip_prefix("udp://") {
+            Some(address) => address,
+            None => return Err(anyhow!("Not a UDP tracker url: {}", announce))
+        };
+        // the path is meaningless in the UDP protocol
+        let address = address.split('/').next().unwrap_or_default(); // split always yields at least one item
+
+        if address.rsplit_once(':').is_none() {
+            return Err(anyhow!("UDP tracker url has no port: {}", announce));
+        }
+
+        Ok(UdpTracker {
+            address: address.to_string(),
+            connection: None,
+        })
+    }
+
+    /// Announces to the tracker and returns the response as the equivalent HTTP tracker dictionary,
+    /// error responses become a `failure reason`.
+    pub async fn announce(&mut self, request: &TrackerRequest) -> Result<BencodedValue> {
+        let socket = self.socket().await?;
+        let connection_id = self.connection_id(&socket).await?;
+
+        let transaction_id = random_transaction_id();
+        let response = match exchange(&socket, &request.as_udp_announce(connection_id, transaction_id), ACTION_ANNOUNCE, transaction_id).await {
+            Ok(response) => response,
+            Err(e) => return self.failure(e)
+        };
+
+        parse_announce_response(&response, socket.peer_addr()?.is_ipv6())
+    }
+
+    /// Scrapes the swarm statistics of up to `MAX_SCRAPE_INFO_HASHES` torrents, in the order they were given.
+    pub async fn scrape(&mut self, info_hashes: &[Sha1Hash]) -> Result<Vec<SwarmStats>> {
+        if info_hashes.len() > MAX_SCRAPE_INFO_HASHES {
+            return Err(anyhow!("Can't scrape more than {} torrents at once", MAX_SCRAPE_INFO_HASHES));
+        }
+
+        let socket = self.socket().await?;
+        let connection_id = self.connection_id(&socket).await?;
+
+        let transaction_id = random_transaction_id();
+        let mut request = Vec::with_capacity(16 + info_hashes.len() * 20);
+        request.extend_from_slice(&connection_id.to_be_bytes());
+        request.extend_from_slice(&ACTION_SCRAPE.to_be_bytes());
+        request.extend_from_slice(&transaction_id.to_be_bytes());
+        for info_hash in info_hashes {
+            request.extend_from_slice(&info_hash.0);
+        }
+
+        let response = match exchange(&socket, &request, ACTION_SCRAPE, transaction_id).await {
+            Ok(response) => response,
+            Err(e) => {
+                self.connection = None;
+                return Err(e);
+            }
+        };
+
+        let stats = parse_scrape_response(&response)?;
+        if stats.len() != info_hashes.len() {
+            return Err(anyhow!("Scrape response has {} torrents, expected {}", stats.len(), info_hashes.len()));
+        }
+
+        Ok(stats)
+    }
+
+    /// Errors sent by the tracker are reported like HTTP failures, the connection id might be the reason so it is dropped.
+    fn failure(&mut self, e: anyhow::Error) -> Result<BencodedValue> {
+        self.connection = None;
+
+        match e.downcast::<TrackerError>() {
+            Ok(TrackerError(message)) => {
+                let mut response = BTreeMap::new();
+                response.insert(b"failure reason".to_vec(), BencodedValue::ByteString(message.into_bytes()));
+                Ok(BencodedValue::Dict(response))
+            },
+            Err(e) => Err(e)
+        }
+    }
+
+    async fn socket(&self) -> Result<UdpSocket> {
+        let address = tokio::net::lookup_host(&self.address)
+            .await
+            .context("resolving UDP tracker address")?
+            // ipv4 is preferred because most peers are still only reachable over ipv4
+            .min_by_key(|address| address.is_ipv6())
+            .ok_or(anyhow!("UDP tracker address {} doesn't resolve", self.address))?;
+
+        let bind_address: SocketAddr = match address {
+            SocketAddr::V4(_) => ([0, 0, 0, 0], 0).into(),
+            SocketAddr::V6(_) => ([0; 16], 0).into(),
+        };
+        let socket = UdpSocket::bind(bind_address).await?;
+        socket.connect(address).await?;
+
+        Ok(socket)
+    }
+
+    /// Returns the cached connection id or asks the tracker for a new one.
+    async fn connection_id(&mut self, socket: &UdpSocket) -> Result<u64> {
+        if let Some((connection_id, received_at)) = self.connection {
+            if received_at.elapsed() < Duration::from_secs(CONNECTION_ID_VALID_SECS) {
+                return Ok(connection_id);
+            }
+        }
+
+        let transaction_id = random_transaction_id();
+        let mut request = Vec::with_capacity(16);
+        request.extend_from_slice(&PROTOCOL_ID.to_be_bytes());
+        request.extend_from_slice(&ACTION_CONNECT.to_be_bytes());
+        request.extend_from_slice(&transaction_id.to_be_bytes());
+
+        let response = exchange(socket, &request, ACTION_CONNECT, transaction_id).await.context("connecting to UDP tracker")?;
+        if response.len() < 16 {
+            return Err(anyhow!("UDP tracker connect response is too short: {} bytes", response.len()));
+        }
+
+        let connection_id = u64::from_be_bytes(response[8..16].try_into().unwrap()); // length checked above
+        self.connection = Some((connection_id, Instant::now()));
+
+        Ok(connection_id)
+    }
+}
+
+/// Error message sent by the tracker in an error action response.
+#[derive(Debug)]
+struct TrackerError(String);
+
+impl std::fmt::Display for TrackerError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "UDP tracker error: {}", self.0)
+    }
+}
+
+impl std::error::Error for TrackerError {}
+
+/// Sends `request` until a response with the same transaction id arrives, retransmitting with exponential timeouts.
+async fn exchange(socket: &UdpSocket, request: &[u8], action: u32, transaction_id: u32) -> Result<Vec<u8>> {
+    let mut buf = vec![0; 65535];
+
+    for n in 0..=MAX_RETRANSMISSIONS {
+        socket.send(request).await?;
+
+        let timeout = tokio::time::sleep(Duration::from_secs(RETRANSMISSION_TIMEOUT_SECS * 2u64.pow(n)));
+        tokio::pin!(timeout);
+
+        loop {
+            tokio::select! {
+                _ = &mut timeout => break,
+                received = socket.recv(&mut buf) => {
+                    let response = &buf[..received?];
+                    if let Some(response) = check_response(response, action, transaction_id)? {
+                        return Ok(response.to_vec());
+                    }
+                }
+            }
+        }
+
+        tracing::debug!("UDP tracker didn't answer within {} seconds", RETRANSMISSION_TIMEOUT_SECS * 2u64.pow(n));
+    }
+
+    Err(anyhow!("UDP tracker didn't answer after {} retransmissions", MAX_RETRANSMISSIONS))
+}
+
+/// Returns None for responses to other transactions, which are late answers to earlier requests.
+fn check_response(response: &[u8], action: u32, transaction_id: u32) -> Result<Option<&[u8]>> {
+    if response.len() < 8 {
+        return Ok(None);
+    }
+
+    let response_action = u32::from_be_bytes(response[..4].try_into().unwrap()); // length checked above
+    let response_transaction_id = u32::from_be_bytes(response[4..8].try_into().unwrap());
+
+    if response_transaction_id != transaction_id {
+        return Ok(None);
+    }
+
+    if response_action == ACTION_ERROR {
+        return Err(TrackerError(String::from_utf8_lossy(&response[8..]).to_string()).into());
+    }
+
+    if response_action != action {
+        return Err(anyhow!("UDP tracker answered action {} with action {}", action, response_action));
+    }
+
+    Ok(Some(response))
+}
+
+/// Trackers answer announces sent over ipv6 with 18 byte ipv6 peers, those go in `peers6` like in HTTP responses (BEP 7).
+fn parse_announce_response(response: &[u8], ipv6: bool) -> Result<BencodedValue> {
+    if response.len() < 20 {
+        return Err(anyhow!("UDP tracker announce response is too short: {} bytes", response.len()));
+    }
+
+    let read_u32 = |offset: usize| u32::from_be_bytes(response[offset..offset + 4].try_into().unwrap()) as i64;
+    let peers_key = match ipv6 {
+        true => b"peers6".to_vec(),
+        false => b"peers".to_vec(),
+    };
+
+    let mut dict = BTreeMap::new();
+    dict.insert(b"interval".to_vec(), BencodedValue::Integer(read_u32(8)));
+    dict.insert(b"incomplete".to_vec(), BencodedValue::Integer(read_u32(12)));
+    dict.insert(b"complete".to_vec(), BencodedValue::Integer(read_u32(16)));
+    dict.insert(peers_key, BencodedValue::ByteString(response[20..].to_vec()));
+
+    Ok(BencodedValue::Dict(dict))
+}
+
+fn parse_scrape_response(response: &[u8]) -> Result<Vec<SwarmStats>> {
+    Ok(response[8..]
+        .chunks_exact(12)
+        .map(|stats| SwarmStats {
+            complete: u32::from_be_bytes(stats[..4].try_into().unwrap()), // chunks are always 12 bytes
+            downloaded: u32::from_be_bytes(stats[4..8].try_into().unwrap()),
+            incomplete: u32::from_be_bytes(stats[8..].try_into().unwrap()),
+        })
+        .collect())
+}
+
+fn random_transaction_id() -> u32 {
+    let mut transaction_id = [0; 4];
+    getrandom::getrandom(&mut transaction_id).expect("getrandom failed");
+
+    u32::from_be_bytes(transaction_id)
+}
+
+#[cfg(test)]
+mod udp_tracker_tests {
+    use super::*;
+    use crate::peer::PeerAddress;
+    use crate::tracker::{Tracker, TrackerEvent};
+
+    /// Answers connect, announce and scrape requests like a tracker with a single peer and rejects everything else.
+    async fn fake_tracker(socket: UdpSocket) {
+        let connection_id: u64 = 0x1234;
+        let mut buf = vec![0; 65535];
+
+        loop {
+            let (len, from) = socket.recv_from(&mut buf).await.unwrap();
+            let request = &buf[..len];
+            let action = u32::from_be_bytes(request[8..12].try_into().unwrap());
+            let transaction_id = &request[12..16];
+
+            let mut response = Vec::new();
+            if action == ACTION_CONNECT && request[..8] == PROTOCOL_ID.to_be_bytes() {
+                response.extend_from_slice(&ACTION_CONNECT.to_be_bytes());
+                response.extend_from_slice(transaction_id);
+                response.extend_from_slice(&connection_id.to_be_bytes());
+            }
+            else if action == ACTION_ANNOUNCE && request[..8] == connection_id.to_be_bytes() {
+                assert_eq!(request.len(), 98);
+                assert_eq!(u32::from_be_bytes(request[80..84].try_into().unwrap()), TrackerEvent::Started.as_udp_event());
+
+                response.extend_from_slice(&ACTION_ANNOUNCE.to_be_bytes());
+                response.extend_from_slice(transaction_id);
+                response.extend_from_slice(&1800u32.to_be_bytes());
+                response.extend_from_slice(&3u32.to_be_bytes());
+                response.extend_from_slice(&5u32.to_be_bytes());
+                response.extend_from_slice(&[10, 0, 0, 1, 0x1a, 0xe1]);
+            }
+            else if action == ACTION_SCRAPE && request[..8] == connection_id.to_be_bytes() {
+                response.extend_from_slice(&ACTION_SCRAPE.to_be_bytes());
+                response.extend_from_slice(transaction_id);
+                for _ in request[16..].chunks_exact(20) {
+                    response.extend_from_slice(&[0, 0, 0, 5, 0, 0, 0, 9, 0, 0, 0, 3]);
+                }
+            }
+            else {
+                response.extend_from_slice(&ACTION_ERROR.to_be_bytes());
+                response.extend_from_slice(transaction_id);
+                response.extend_from_slice(b"invalid connection id");
+            }
+
+            socket.send_to(&response, from).await.unwrap();
+        }
+    }
+
+    #[test]
+    fn test_announce_url_parsing() {
+        assert!(UdpTracker::is_udp_announce("udp://tracker.example:80/announce"));
+        assert!(!UdpTracker::is_udp_announce("http://tracker.example/announce"));
+        assert_eq!(UdpTracker::new("udp://tracker.example:80/announce").unwrap().address, "tracker.example:80");
+        assert!(UdpTracker::new("udp://tracker.example/announce").is_err());
+    }
+
+    #[test]
+    fn test_error_action_becomes_failure_reason() {
+        let mut response = ACTION_ERROR.to_be_bytes().to_vec();
+        response.extend_from_slice(&7u32.to_be_bytes());
+        response.extend_from_slice(b"torrent not registered");
+
+        let e = check_response(&response, ACTION_ANNOUNCE, 7).unwrap_err();
+        let mut udp_tracker = UdpTracker::new("udp://127.0.0.1:1").unwrap();
+        udp_tracker.connection = Some((1, Instant::now()));
+
+        let failure = udp_tracker.failure(e).unwrap();
+        assert_eq!(failure.get_from_dict(b"failure reason").unwrap(), BencodedValue::ByteString(b"torrent not registered".to_vec()));
+        assert!(udp_tracker.connection.is_none());
+
+        // answers to other transactions are skipped
+        assert!(check_response(&response, ACTION_ANNOUNCE, 8).unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_announce_and_scrape_on_localhost() {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let announce = format!("udp://{}/announce", socket.local_addr().unwrap());
+        tokio::spawn(fake_tracker(socket));
+
+        let mut tracker = Tracker::new(announce.clone());
+        let response = tracker.metadata_response([1; 20], Sha1Hash([2; 20])).await.unwrap();
+        assert_eq!(tracker.get_interval(), 1800);
+        assert_eq!(response.get_from_dict(b"complete").unwrap(), BencodedValue::Integer(5));
+        assert_eq!(PeerAddress::from_tracker_response(response).await.unwrap(), vec![PeerAddress("10.0.0.1:6881".parse().unwrap())]);
+
+        let mut udp_tracker = UdpTracker::new(&announce).unwrap();
+        let stats = udp_tracker.scrape(&[Sha1Hash([2; 20]), Sha1Hash([3; 20])]).await.unwrap();
+        assert_eq!(stats, vec![SwarmStats{complete: 5, downloaded: 9, incomplete: 3}; 2]);
+    }
+}
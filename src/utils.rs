@@ -11,10 +11,16 @@ pub use bencode::BencodedValue;
 
 pub mod terminal;
 
+pub mod bitset;
+pub use bitset::Bitset;
+
 #[derive(Debug, Serialize, Deserialize)]
 pub enum ExitCode {
     SUCCESS,
     InvalidSrcOrDst,
+    InvalidInfoHash,
+    UnsafeTorrentPath,
+    TorrentNotFound,
 }
 
 pub struct CommunicationPipe {
@@ -38,6 +44,13 @@ impl UrlEncodable for Vec<u8> {
     }
 }
 
+impl UrlEncodable for std::net::IpAddr {
+    fn as_url_encoded(&self) -> String {
+        // the colons of ipv6 addresses have to be escaped
+        percent_encoding::utf8_percent_encode(&self.to_string(), percent_encoding::NON_ALPHANUMERIC).to_string()
+    }
+}
+
 pub async fn read_file_as_bytes(path: &std::path::Path) -> Result<Vec<u8>> {
     let mut buf = Vec::new();
     let mut file = tokio::fs::File::open(path).await.context("couldn't open file")?;
@@ -51,18 +64,20 @@ pub fn print_as_string(char_vec: &[u8]) {
     println!("{}", char_vec.iter().map(|&c| c as char).collect::<String>());
 }
 
+/// Checks the source and destination of a new torrent without touching the filesystem,
+/// the destination is created once the torrent gets added.
 pub fn valid_src_and_dst(src: &str, dst: &str) -> bool {
     let torrent_file = std::path::Path::new(src);
     let directory = std::path::Path::new(dst);
-    if  !torrent_file.exists()                              || 
-        !torrent_file.is_file()                             || 
-        torrent_file.extension().is_none()                  ||
-        "torrent" != torrent_file.extension().unwrap()
-    {
+    if directory.exists() && !directory.is_dir() {
         return false;
     }
 
-    std::fs::create_dir_all(directory).is_ok()
+    if crate::torrent::MagnetLink::is_magnet_link(src) {
+        return crate::torrent::MagnetLink::parse(src).is_ok();
+    }
+
+    torrent_file.is_file() && torrent_file.extension().is_some_and(|extension| extension == "torrent")
 }
 
 pub fn is_zero_aligned(buf: &[u8]) -> bool {
@@ -73,6 +88,19 @@ pub fn is_zero_aligned(buf: &[u8]) -> bool {
         && aligned.iter().all(|&x| x == 0)
 }
 
+/// The global ipv6 address other hosts reach us on, if there is one.
+/// Connecting a UDP socket only picks the outgoing address, nothing is sent.
+pub fn local_ipv6_address() -> Option<std::net::Ipv6Addr> {
+    let socket = std::net::UdpSocket::bind("[::]:0").ok()?;
+    socket.connect("[2001:4860:4860::8888]:80").ok()?;
+
+    match socket.local_addr().ok()?.ip() {
+        // link local (fe80::/10) and unique local (fc00::/7) addresses aren't reachable from the internet
+        std::net::IpAddr::V6(ip) if !ip.is_loopback() && (ip.segments()[0] & 0xffc0) != 0xfe80 && (ip.segments()[0] & 0xfe00) != 0xfc00 => Some(ip),
+        _ => None
+    }
+}
+
 pub fn generate_random_client_id() -> [u8; 20] {
     let mut client_id = [0u8; 20];
     client_id[0..10].copy_from_slice(b"TtT-1-0-0-");
@@ -83,6 +111,14 @@ pub fn generate_random_client_id() -> [u8; 20] {
     client_id
 }
 
+/// A random index into a collection of `len` items, `len` must not be 0.
+pub fn random_index(len: usize) -> usize {
+    let mut random = [0u8; 8];
+    getrandom(&mut random).expect("Failed to generate random index");
+
+    (u64::from_be_bytes(random) % len as u64) as usize
+}
+
 pub fn print_bencoded_value(bencoded_value: &BencodedValue) {
     match bencoded_value {
         BencodedValue::ByteString(byte_string) => {
@@ -107,15 +143,5 @@ pub fn print_bencoded_value(bencoded_value: &BencodedValue) {
                 print_bencoded_value(value);
             }
         },
-        BencodedValue::ByteSha1Hashes(byte_sha1_hashes) => {
-            println!("Byte Sha1 Hashes: ");
-            for byte_sha1_hash in byte_sha1_hashes {
-                print!("Byte Sha1 Hash: ");
-                print_as_string(byte_sha1_hash.as_bytes().as_ref());
-            }
-        },
-        _ => {
-            println!("Invalid bencoded value");
-        }
     }
 }
\ No newline at end of file
@@ -3,10 +3,6 @@ use anyhow::{anyhow, Result};
 
 use std::collections::BTreeMap;
 
-use crate::peer::PeerAddress;
-
-use super::Sha1Hash;
-
 mod parsing;
 
 /// Represents a value in the Bencode format.
@@ -16,8 +12,6 @@ pub enum BencodedValue {
     List(Vec<BencodedValue>),
     Integer(i64),
     ByteString(Vec<u8>),
-    ByteSha1Hashes(Vec<Sha1Hash>),
-    ByteAddresses(Vec<PeerAddress>),
 }
 
 impl BencodedValue {
@@ -25,6 +19,11 @@ impl BencodedValue {
         parsing::encode(bytes)
     }
 
+    /// Like `from_bytes` but also returns where the bencoded value ends.
+    pub fn from_bytes_prefix(bytes: &[u8]) -> Result<(BencodedValue, usize)> {
+        parsing::encode_prefix(bytes)
+    }
+
     pub fn as_bytes(&self) -> Result<Vec<u8>> {
         parsing::decode(self)
     }
@@ -57,13 +56,6 @@ impl BencodedValue {
         }
     }
 
-    pub fn try_into_byte_sha1_hashes(&self) -> Result<&Vec<Sha1Hash>> {
-        match self {
-            BencodedValue::ByteSha1Hashes(b) => Ok(b),
-            _ => Err(anyhow!("Trying to convert a non-bencoded byte string"))
-        }
-    }
-
     pub fn insert_into_dict(&mut self, key: Vec<u8>, value: BencodedValue) {
         if let BencodedValue::Dict(d) = self {
             d.insert(key, value);
@@ -100,7 +92,8 @@ impl BencodedValue {
             Err(_) => return false
         };
 
-        if [b"announce".to_vec(), b"info".to_vec()].iter().any(|key| !dict.contains_key(key)) {
+        // trackerless torrents have no announce key and find their peers through the DHT
+        if !dict.contains_key(&b"info".to_vec()) {
             return false;
         }
 
@@ -8,6 +8,10 @@ pub fn encode(bytes: &[u8]) -> Result<BencodedValue> {
     parsing_utils::parse_to_bencoded_value(bytes)
 }
 
+pub fn encode_prefix(bytes: &[u8]) -> Result<(BencodedValue, usize)> {
+    parsing_utils::parse_prefix_to_bencoded_value(bytes)
+}
+
 pub fn decode(bencoded_value: &BencodedValue) -> Result<Vec<u8>> {
     parsing_utils::parse_from_bencoded_value(bencoded_value)
 }
@@ -265,6 +269,23 @@ mod torrent_parser_tests {
         assert!(true);
     }
 
+    #[test]
+    fn test_create_int_negative_int_length() {
+        let mut cur_index = 0;
+        create_int(b"i-123e", &mut cur_index).unwrap();
+        assert_eq!(cur_index, 6);
+    }
+
+    #[test]
+    fn test_truncated_input_is_an_error() {
+        let inputs: [&[u8]; 9] = [b"", b"5:ab", b"i", b"i-", b"i12", b"d", b"d3:key", b"l4:spa", b"99999999999999999999:a"];
+        for input in inputs {
+            assert!(encode(input).is_err(), "{:?}", input);
+        }
+
+        assert_eq!(encode(b"5:abcde").unwrap(), BencodedValue::ByteString(b"abcde".to_vec()));
+    }
+
     #[test]
     fn test_to_bencoded_list() {
         let torrent_file = "l4:spami90elee".as_bytes();
@@ -1,9 +1,6 @@
 use anyhow::{anyhow, Result};
 use std::collections::BTreeMap;
 
-use crate::peer::PeerAddress;
-use crate::utils::sha1hash::Sha1Hash;
-
 use super::BencodedValue;
 
 pub fn parse_from_bencoded_value(bencoded_value: &BencodedValue) -> Result<Vec<u8>> {
@@ -12,33 +9,32 @@ pub fn parse_from_bencoded_value(bencoded_value: &BencodedValue) -> Result<Vec<u
         BencodedValue::List(_) => to_bencoded_list(bencoded_value),
         BencodedValue::Integer(i) => Ok(("i".to_owned() + &i.to_string() + "e").as_bytes().to_vec()),
         BencodedValue::ByteString(byte_string) => Ok(byte_string.clone()),
-        BencodedValue::ByteSha1Hashes(sha1hashes) => Ok(
-            sha1hashes.iter()
-                .flat_map(|sha1hash| sha1hash.0)
-                .collect::<Vec<u8>>()
-        ),
-        BencodedValue::ByteAddresses(byte_addresses) => Ok(
-            byte_addresses.iter()
-            .flat_map(|peer_address| peer_address.to_vec())
-            .collect::<Vec<u8>>()
-        ),
     }
 }
 
 pub fn parse_to_bencoded_value(bytes: &[u8]) -> Result<BencodedValue> {
-    match bytes[0] {
-        b'd' => create_dict(bytes, &mut 0),
-        b'l' => create_list(bytes, &mut 0),
-        b'i' => create_int(bytes, &mut 0),
-        _ => {
-            let word_len = parse_integer(bytes)? as usize;
-            let word_len_chars_len = (word_len.checked_ilog10().unwrap_or(0) + 1) as usize;
+    match bytes.first() {
+        Some(b'd') => create_dict(bytes, &mut 0),
+        Some(b'l') => create_list(bytes, &mut 0),
+        Some(b'i') => create_int(bytes, &mut 0),
+        Some(_) => Ok(BencodedValue::ByteString(create_byte_string(bytes, &mut 0)?)),
+        None => Err(anyhow!("Invalid bencoded value: empty input"))
+    }
+}
 
-            let word = bytes[word_len_chars_len + 1..word_len_chars_len + 1 + word_len].to_vec();
+/// Parses a bencoded dictionary, list or integer at the start of `bytes` and returns it
+/// together with the number of bytes it took. Used for messages that carry raw data after the bencoded part.
+pub fn parse_prefix_to_bencoded_value(bytes: &[u8]) -> Result<(BencodedValue, usize)> {
+    let mut cur_index = 0;
 
-            Ok(BencodedValue::ByteString(word))
-        }
-    }
+    let value = match bytes.first() {
+        Some(b'd') => create_dict(bytes, &mut cur_index)?,
+        Some(b'l') => create_list(bytes, &mut cur_index)?,
+        Some(b'i') => create_int(bytes, &mut cur_index)?,
+        _ => return Err(anyhow!("Invalid bencoded prefix: expected a dictionary, list or integer"))
+    };
+
+    Ok((value, cur_index))
 }
 
 pub fn to_bencoded_dict(bencoded_dict: &BencodedValue) -> Result<Vec<u8>> {
@@ -94,42 +90,6 @@ pub fn to_bencoded_dict(bencoded_dict: &BencodedValue) -> Result<Vec<u8>> {
                 bencoded_dict.push(b':');
                 bencoded_dict.append(&mut bytes.clone());
             }
-            BencodedValue::ByteSha1Hashes(byte_string) => {
-                let mut word_len = (byte_string.len() * 20) // 20 bytes per sha1hash
-                    .to_string()
-                    .as_bytes()
-                    .to_vec(); 
-
-                // byte count:concatenated sha1 hashes
-                bencoded_dict.append(&mut word_len);
-                bencoded_dict.push(b':');
-
-                for sha1hash in byte_string {
-                    let mut sha1hash = sha1hash
-                        .0
-                        .clone()
-                        .to_vec();
-
-                        bencoded_dict.append(&mut sha1hash);
-                }
-            }
-            BencodedValue::ByteAddresses(byte_addresses) => {
-                let mut word_len = (byte_addresses.len() * 6) // 6 bytes per peer address
-                    .to_string()
-                    .as_bytes()
-                    .to_vec(); 
-
-                // byte count:concatenated byte addresses
-                bencoded_dict.append(&mut word_len);
-                bencoded_dict.push(b':');
-
-                for peer_address in byte_addresses {
-                    let mut peer_address = peer_address
-                        .to_vec();
-
-                        bencoded_dict.append(&mut peer_address);
-                }
-            }
         }
     }
 
@@ -177,42 +137,6 @@ pub fn to_bencoded_list(bencoded_list: &BencodedValue) -> Result<Vec<u8>> {
                 bencoded_list.push(b':');
                 bencoded_list.append(&mut bytes.clone());
             }
-            BencodedValue::ByteSha1Hashes(byte_string) => {
-                let mut word_len = (byte_string.len() * 20) // 20 bytes per sha1hash
-                    .to_string()
-                    .as_bytes()
-                    .to_vec(); 
-
-                // byte count:concatenated sha1 hashes
-                bencoded_list.append(&mut word_len);
-                bencoded_list.push(b':');
-
-                for sha1hash in byte_string {
-                    let mut sha1hash = sha1hash
-                        .0
-                        .clone()
-                        .to_vec();
-
-                    bencoded_list.append(&mut sha1hash);
-                }
-            }
-            BencodedValue::ByteAddresses(byte_addresses) => {
-                let mut word_len = (byte_addresses.len() * 6) // 6 bytes per peer address
-                    .to_string()
-                    .as_bytes()
-                    .to_vec(); 
-
-                // byte count:concatenated byte addresses
-                bencoded_list.append(&mut word_len);
-                bencoded_list.push(b':');
-
-                for peer_address in byte_addresses {
-                    let mut peer_address = peer_address
-                        .to_vec();
-
-                    bencoded_list.append(&mut peer_address);
-                }
-            }
         }
     }
 
@@ -234,13 +158,13 @@ pub fn parse_bencoded_integer(bytes: &[u8]) -> Result<i64> {
     index += 1;
     
     let mut number = String::new();
-    if bytes[index] == b'-' {
+    if bytes.get(index) == Some(&b'-') {
         number.push(b'-' as char);
         index += 1;
     }
 
-    while bytes[index].is_ascii_digit() {
-        number.push(bytes[index] as char);
+    while let Some(digit) = bytes.get(index).filter(|byte| byte.is_ascii_digit()) {
+        number.push(*digit as char);
         index += 1;
     }
 
@@ -252,7 +176,7 @@ pub fn parse_bencoded_integer(bytes: &[u8]) -> Result<i64> {
         return Err(anyhow!("Invalid bencoded integer: negative zero"));
     }
 
-    if bytes[index] != b'e' {
+    if bytes.get(index) != Some(&b'e') {
         return Err(anyhow!("Invalid bencoded integer: missing 'e' suffix"));
     }
 
@@ -305,7 +229,7 @@ pub fn parse_integer(bytes: &[u8]) -> Result<i64> {
 pub fn create_dict(bytes: &[u8], cur_index: &mut usize) -> Result<BencodedValue> {
     let bytes_count = bytes.len();   
 
-    if bytes[*cur_index] != b'd' {
+    if bytes.get(*cur_index) != Some(&b'd') {
         return Err(anyhow!("Invalid torrent file: missing 'd' prefix"));
     }
     *cur_index += 1;
@@ -333,60 +257,16 @@ pub fn create_dict(bytes: &[u8], cur_index: &mut usize) -> Result<BencodedValue>
             },
             // this should be either a key or a value
             _ => {
-                let word_len = parse_integer(&bytes[*cur_index..])? as usize;
-                let word_len_chars_len = (word_len.checked_ilog10().unwrap_or(0) + 1) as usize;
+                let byte_string = create_byte_string(bytes, cur_index)?;
 
-                *cur_index += word_len_chars_len + 1; // + 1 for the ':' byte
-        
                 if key.is_empty() {
-                    key = bytes[*cur_index..*cur_index + word_len].to_vec();
+                    key = byte_string;
                 }
                 else {
-                    if key == b"pieces" {
-                        if word_len % 20 != 0 {
-                            return Err(anyhow!("[Error] Invalid number of bytes in pieces"));
-                        }
-
-                        let sha1_hashes = bytes[*cur_index..*cur_index + word_len]
-                            .to_vec()
-                            .chunks_exact(20)
-                            .map(|chunk| {
-                                let mut sha1_chunk: [u8; 20] = [0; 20];
-                                sha1_chunk.copy_from_slice(chunk);
-                                
-                                Sha1Hash(sha1_chunk)
-                            })
-                            .collect::<Vec<Sha1Hash>>();
-
-                        dict.insert(key.clone(), BencodedValue::ByteSha1Hashes(sha1_hashes));
-                    }
-                    else if key == b"peers" {
-                        if word_len % 6 != 0 {
-                            return Err(anyhow!("[Error] Invalid number of bytes in peers"));
-                        }
-
-                        let peer_addresses = bytes[*cur_index..*cur_index + word_len]
-                            .to_vec()
-                            .chunks_exact(6)
-                            .map(|chunk| {
-                                let mut ip_port_chunk: [u8; 6] = [0; 6];
-                                ip_port_chunk.copy_from_slice(chunk);
-
-                                PeerAddress::new(ip_port_chunk)
-                            })
-                            .collect::<Vec<PeerAddress>>();
-
-                        dict.insert(key.clone(), BencodedValue::ByteAddresses(peer_addresses));
-                    }
-                    else {
-                        let byte_string = bytes[*cur_index..*cur_index + word_len].to_vec();
-                        dict.insert(key.clone(), BencodedValue::ByteString(byte_string));
-                    }
+                    dict.insert(key.clone(), BencodedValue::ByteString(byte_string));
 
                     key.clear();
                 }
-
-                *cur_index += word_len;
             }  
         }              
     }
@@ -402,7 +282,7 @@ pub fn create_dict(bytes: &[u8], cur_index: &mut usize) -> Result<BencodedValue>
 pub fn create_list(bytes: &[u8], cur_index: &mut usize) -> Result<BencodedValue> {
     let bytes_count = bytes.len();
 
-    if bytes[*cur_index] != b'l' {
+    if bytes.get(*cur_index) != Some(&b'l') {
         return Err(anyhow!("Invalid torrent file: missing 'l' prefix"));
     }
     *cur_index += 1;
@@ -419,18 +299,7 @@ pub fn create_list(bytes: &[u8], cur_index: &mut usize) -> Result<BencodedValue>
             b'l' => list.push(create_list(bytes, cur_index)?),
             b'i' => list.push(create_int(bytes, cur_index)?),
             // this should be a word
-            _ => {
-                let word_len = parse_integer(&bytes[*cur_index..])? as usize;
-                let word_len_chars_len = (word_len.checked_ilog10().unwrap_or(0) + 1) as usize;
-
-                *cur_index += word_len_chars_len + 1; // + 1 for the ':' byte
-        
-                let word = bytes[*cur_index..*cur_index + word_len].to_vec();
-                
-                list.push(BencodedValue::ByteString(word));
-
-                *cur_index += word_len;
-            }  
+            _ => list.push(BencodedValue::ByteString(create_byte_string(bytes, cur_index)?)),
         }
     }
 
@@ -443,10 +312,32 @@ pub fn create_list(bytes: &[u8], cur_index: &mut usize) -> Result<BencodedValue>
 }
 
 pub fn create_int(bytes: &[u8], cur_index: &mut usize) -> Result<BencodedValue> {
-    let num = parse_bencoded_integer(&bytes[*cur_index..])?;
-    
-    let len_of_the_num: usize = (num.checked_ilog10().unwrap_or(0) + 1) as usize;
-    *cur_index += len_of_the_num + 2; // + 2 for the 'i' and 'e' bytes
+    let bytes = bytes.get(*cur_index..).unwrap_or_default();
+    let num = parse_bencoded_integer(bytes)?;
+
+    // a valid integer always ends with the first 'e'
+    let len_of_the_int = bytes.iter().position(|byte| *byte == b'e').unwrap_or(bytes.len()) + 1;
+    *cur_index += len_of_the_int;
 
     Ok(BencodedValue::Integer(num))
+}
+
+/// Reads a `<length>:<bytes>` string, checking that all of its bytes are there.
+pub fn create_byte_string(bytes: &[u8], cur_index: &mut usize) -> Result<Vec<u8>> {
+    let bytes_left = bytes.get(*cur_index..).unwrap_or_default();
+
+    let separator = match bytes_left.iter().position(|byte| !byte.is_ascii_digit()) {
+        Some(separator) if separator > 0 && bytes_left[separator] == b':' => separator,
+        _ => return Err(anyhow!("Invalid byte string: missing length"))
+    };
+    let word_len = usize::try_from(parse_integer(&bytes_left[..separator])?)?;
+
+    let word = separator
+        .checked_add(1 + word_len)
+        .and_then(|end| bytes_left.get(separator + 1..end))
+        .ok_or_else(|| anyhow!("Invalid torrent file: too short"))?
+        .to_vec();
+    *cur_index += separator + 1 + word_len;
+
+    Ok(word)
 }
\ No newline at end of file
@@ -0,0 +1,141 @@
+use serde::{Serialize, Deserialize};
+use anyhow::{anyhow, Result};
+
+/// Fixed size set of bits, the most significant bit of a byte comes first like in the bitfield message.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(into = "SerializedBitset", try_from = "SerializedBitset")]
+pub struct Bitset {
+    bytes: Vec<u8>,
+    len: usize,
+}
+
+/// Bitsets are saved as their length and their bytes in hex, which keeps the state file small.
+#[derive(Serialize, Deserialize)]
+struct SerializedBitset {
+    len: usize,
+    bits: String,
+}
+
+impl From<Bitset> for SerializedBitset {
+    fn from(bitset: Bitset) -> SerializedBitset {
+        SerializedBitset {
+            len: bitset.len,
+            bits: hex::encode(&bitset.bytes),
+        }
+    }
+}
+
+impl TryFrom<SerializedBitset> for Bitset {
+    type Error = anyhow::Error;
+
+    fn try_from(serialized: SerializedBitset) -> Result<Bitset> {
+        let bytes = hex::decode(&serialized.bits)?;
+        if bytes.len() != serialized.len.div_ceil(8) {
+            return Err(anyhow!("bitset of {} bits can't have {} bytes", serialized.len, bytes.len()));
+        }
+
+        Ok(Bitset::from_bytes(&bytes, serialized.len))
+    }
+}
+
+impl Bitset {
+    pub fn new(len: usize) -> Bitset {
+        Bitset {
+            bytes: vec![0; len.div_ceil(8)],
+            len,
+        }
+    }
+
+    /// Missing bytes are zeros and the bits past `len` are dropped.
+    pub fn from_bytes(bytes: &[u8], len: usize) -> Bitset {
+        let mut bitset = Bitset::new(len);
+        for (byte, other) in bitset.bytes.iter_mut().zip(bytes) {
+            *byte = *other;
+        }
+        if !len.is_multiple_of(8) {
+            if let Some(last) = bitset.bytes.last_mut() {
+                *last &= 0xff << (8 - len % 8);
+            }
+        }
+
+        bitset
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+
+    /// Bits past the end are never set.
+    pub fn get(&self, index: usize) -> bool {
+        index < self.len && self.bytes[index / 8] & 1 << (7 - index % 8) != 0
+    }
+
+    pub fn set(&mut self, index: usize, value: bool) {
+        if index >= self.len {
+            return;
+        }
+
+        match value {
+            true => self.bytes[index / 8] |= 1 << (7 - index % 8),
+            false => self.bytes[index / 8] &= !(1 << (7 - index % 8)),
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.bytes.iter_mut().for_each(|byte| *byte = 0);
+    }
+
+    pub fn count_ones(&self) -> usize {
+        self.bytes.iter().map(|byte| byte.count_ones() as usize).sum()
+    }
+
+    /// Indexes of the set bits in ascending order.
+    pub fn ones(&self) -> impl Iterator<Item = usize> + '_ {
+        (0..self.len).filter(|index| self.get(*index))
+    }
+}
+
+#[cfg(test)]
+mod bitset_tests {
+    use super::*;
+
+    #[test]
+    fn test_set_and_get() {
+        let mut bitset = Bitset::new(10);
+        bitset.set(0, true);
+        bitset.set(9, true);
+        bitset.set(10, true);
+
+        assert_eq!(bitset.as_bytes(), &[0b1000_0000, 0b0100_0000]);
+        assert!(bitset.get(9) && !bitset.get(10));
+        assert_eq!(bitset.ones().collect::<Vec<usize>>(), vec![0, 9]);
+
+        bitset.set(0, false);
+        assert_eq!(bitset.count_ones(), 1);
+    }
+
+    #[test]
+    fn test_from_bytes_drops_extra_bits() {
+        let bitset = Bitset::from_bytes(&[0xff, 0xff, 0xff], 12);
+        assert_eq!(bitset.as_bytes(), &[0xff, 0xf0]);
+        assert_eq!(bitset.count_ones(), 12);
+    }
+
+    #[test]
+    fn test_serialization() {
+        let bitset = Bitset::from_bytes(&[0b1010_0000, 0b1000_0000], 9);
+
+        let serialized = serde_json::to_string(&bitset).unwrap();
+        assert_eq!(serialized, r#"{"len":9,"bits":"a080"}"#);
+        assert_eq!(serde_json::from_str::<Bitset>(&serialized).unwrap(), bitset);
+        assert!(serde_json::from_str::<Bitset>(r#"{"len":20,"bits":"a080"}"#).is_err());
+    }
+}